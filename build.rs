@@ -0,0 +1,9 @@
+// The proto is compiled with protox, so building doesn't need `protoc`.
+// Migrations are embedded by `sqlx::migrate!`, new ones need a rebuild.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto");
+    println!("cargo:rerun-if-changed=migrations");
+    let descriptors = protox::compile(["allocation.proto"], ["proto"])?;
+    tonic_build::configure().compile_fds(descriptors)?;
+    Ok(())
+}
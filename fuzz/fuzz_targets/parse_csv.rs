@@ -0,0 +1,14 @@
+#![no_main]
+
+use cosmic::services::import::parse_batches;
+use libfuzzer_sys::fuzz_target;
+
+// Any input may be rejected, as a whole or row by row, but must never panic
+// or let through a batch the domain wouldn't build or Postgres couldn't store.
+fuzz_target!(|data: &[u8]| {
+    if let Ok((batches, _errors)) = parse_batches(data) {
+        assert!(batches
+            .iter()
+            .all(|batch| batch.qty > 0 && batch.qty <= i32::MAX as u32));
+    }
+});
@@ -0,0 +1,6 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS allocations;
+
+ALTER TABLE batches
+  DROP COLUMN IF EXISTS eta,
+  DROP COLUMN IF EXISTS qty;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+ALTER TABLE batches
+  ADD COLUMN qty INTEGER NOT NULL DEFAULT 0,
+  ADD COLUMN eta TIMESTAMPTZ;
+
+CREATE TABLE IF NOT EXISTS allocations (
+  id SERIAL PRIMARY KEY,
+  batch_id INTEGER NOT NULL REFERENCES batches (id) ON DELETE CASCADE,
+  order_ref VARCHAR(255) NOT NULL,
+  sku VARCHAR(255) NOT NULL,
+  qty INTEGER NOT NULL
+);
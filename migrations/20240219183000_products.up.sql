@@ -0,0 +1,9 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS products (
+  sku VARCHAR(255) PRIMARY KEY,
+  version_number INTEGER NOT NULL DEFAULT 0
+);
+
+INSERT INTO products (sku)
+SELECT DISTINCT sku FROM batches WHERE sku IS NOT NULL
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE batches ADD COLUMN reference VARCHAR(255);
@@ -0,0 +1,7 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS allocations_view (
+  order_ref VARCHAR(255) NOT NULL,
+  sku VARCHAR(255) NOT NULL,
+  batch_id INTEGER NOT NULL,
+  PRIMARY KEY (order_ref, sku)
+);
@@ -0,0 +1,8 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS pending_lines (
+  order_ref VARCHAR(255) NOT NULL,
+  sku VARCHAR(255) NOT NULL,
+  qty INTEGER NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (order_ref, sku)
+);
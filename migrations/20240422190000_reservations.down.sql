@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS reservations;
+
+ALTER TABLE products DROP COLUMN IF EXISTS last_reservation_id;
@@ -0,0 +1,13 @@
+-- Add up migration script here
+ALTER TABLE products
+  ADD COLUMN last_reservation_id INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS reservations (
+  sku VARCHAR(255) NOT NULL,
+  id INTEGER NOT NULL,
+  batch_id INTEGER NOT NULL REFERENCES batches (id) ON DELETE CASCADE,
+  order_ref VARCHAR(255) NOT NULL,
+  qty INTEGER NOT NULL,
+  reserved_at TIMESTAMPTZ NOT NULL,
+  PRIMARY KEY (sku, id)
+);
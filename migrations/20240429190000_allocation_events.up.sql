@@ -0,0 +1,14 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS allocation_events (
+  id BIGSERIAL PRIMARY KEY,
+  order_ref VARCHAR(255) NOT NULL,
+  sku VARCHAR(255) NOT NULL,
+  qty INTEGER NOT NULL,
+  action VARCHAR(32) NOT NULL,
+  batch_id INTEGER,
+  actor VARCHAR(255) NOT NULL,
+  occurred_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS allocation_events_order_ref
+  ON allocation_events (order_ref);
@@ -0,0 +1,3 @@
+-- Add up migration script here
+ALTER TABLE products
+  ADD COLUMN over_allocation_buffer INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE batches DROP COLUMN IF EXISTS warehouse_id;
@@ -0,0 +1,2 @@
+-- Add up migration script here
+ALTER TABLE batches ADD COLUMN warehouse_id VARCHAR(255);
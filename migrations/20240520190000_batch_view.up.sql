@@ -0,0 +1,11 @@
+-- Add up migration script here
+CREATE TABLE IF NOT EXISTS batch_view (
+  batch_id INTEGER PRIMARY KEY,
+  sku VARCHAR(255) NOT NULL,
+  reference VARCHAR(255),
+  eta TIMESTAMPTZ,
+  qty INTEGER NOT NULL,
+  available_qty INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS batch_view_sku ON batch_view (sku);
@@ -0,0 +1,45 @@
+-- Add down migration script here
+-- Only the default tenant's rows fit the single-tenant keys, the others are
+-- dropped
+DELETE FROM products WHERE tenant_id <> 'default';
+DELETE FROM batches WHERE tenant_id <> 'default';
+DELETE FROM reservations WHERE tenant_id <> 'default';
+DELETE FROM allocations_view WHERE tenant_id <> 'default';
+DELETE FROM batch_view WHERE tenant_id <> 'default';
+DELETE FROM pending_lines WHERE tenant_id <> 'default';
+DELETE FROM idempotency_keys WHERE tenant_id <> 'default';
+DELETE FROM outbox WHERE tenant_id <> 'default';
+DELETE FROM snapshots WHERE tenant_id <> 'default';
+DELETE FROM allocation_events WHERE tenant_id <> 'default';
+
+DROP INDEX IF EXISTS allocation_events_tenant_order_ref;
+CREATE INDEX IF NOT EXISTS allocation_events_order_ref
+  ON allocation_events (order_ref);
+DROP INDEX IF EXISTS batch_view_tenant_sku;
+CREATE INDEX IF NOT EXISTS batch_view_sku ON batch_view (sku);
+DROP INDEX IF EXISTS batches_tenant_sku;
+
+ALTER TABLE snapshots
+  DROP CONSTRAINT snapshots_pkey, ADD PRIMARY KEY (sku, event_count);
+ALTER TABLE idempotency_keys
+  DROP CONSTRAINT idempotency_keys_pkey, ADD PRIMARY KEY (key);
+ALTER TABLE pending_lines
+  DROP CONSTRAINT pending_lines_pkey, ADD PRIMARY KEY (order_ref, sku);
+ALTER TABLE allocations_view
+  DROP CONSTRAINT allocations_view_pkey, ADD PRIMARY KEY (order_ref, sku);
+ALTER TABLE reservations
+  DROP CONSTRAINT reservations_pkey, ADD PRIMARY KEY (sku, id);
+ALTER TABLE products
+  DROP CONSTRAINT products_pkey, ADD PRIMARY KEY (sku);
+
+ALTER TABLE allocation_events DROP COLUMN tenant_id;
+ALTER TABLE snapshots DROP COLUMN tenant_id;
+ALTER TABLE outbox DROP COLUMN tenant_id;
+ALTER TABLE idempotency_keys DROP COLUMN tenant_id;
+ALTER TABLE pending_lines DROP COLUMN tenant_id;
+ALTER TABLE batch_view DROP COLUMN tenant_id;
+ALTER TABLE allocations_view DROP COLUMN tenant_id;
+ALTER TABLE reservations DROP COLUMN tenant_id;
+ALTER TABLE allocations DROP COLUMN tenant_id;
+ALTER TABLE batches DROP COLUMN tenant_id;
+ALTER TABLE products DROP COLUMN tenant_id;
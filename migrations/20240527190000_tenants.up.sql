@@ -0,0 +1,61 @@
+-- Add up migration script here
+-- Existing rows belong to the default tenant. The default is dropped again
+-- so every write has to say which tenant it is for.
+ALTER TABLE products
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE batches
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE allocations
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE reservations
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE allocations_view
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE batch_view
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE pending_lines
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE idempotency_keys
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE outbox
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE snapshots
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+ALTER TABLE allocation_events
+  ADD COLUMN tenant_id VARCHAR(255) NOT NULL DEFAULT 'default';
+
+ALTER TABLE products ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE batches ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE allocations ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE reservations ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE allocations_view ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE batch_view ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE pending_lines ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE idempotency_keys ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE outbox ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE snapshots ALTER COLUMN tenant_id DROP DEFAULT;
+ALTER TABLE allocation_events ALTER COLUMN tenant_id DROP DEFAULT;
+
+-- Keys that were unique per database are now unique per tenant
+ALTER TABLE products
+  DROP CONSTRAINT products_pkey, ADD PRIMARY KEY (tenant_id, sku);
+ALTER TABLE reservations
+  DROP CONSTRAINT reservations_pkey, ADD PRIMARY KEY (tenant_id, sku, id);
+ALTER TABLE allocations_view
+  DROP CONSTRAINT allocations_view_pkey,
+  ADD PRIMARY KEY (tenant_id, order_ref, sku);
+ALTER TABLE pending_lines
+  DROP CONSTRAINT pending_lines_pkey,
+  ADD PRIMARY KEY (tenant_id, order_ref, sku);
+ALTER TABLE idempotency_keys
+  DROP CONSTRAINT idempotency_keys_pkey, ADD PRIMARY KEY (tenant_id, key);
+ALTER TABLE snapshots
+  DROP CONSTRAINT snapshots_pkey,
+  ADD PRIMARY KEY (tenant_id, sku, event_count);
+
+CREATE INDEX IF NOT EXISTS batches_tenant_sku ON batches (tenant_id, sku);
+DROP INDEX IF EXISTS batch_view_sku;
+CREATE INDEX IF NOT EXISTS batch_view_tenant_sku ON batch_view (tenant_id, sku);
+DROP INDEX IF EXISTS allocation_events_order_ref;
+CREATE INDEX IF NOT EXISTS allocation_events_tenant_order_ref
+  ON allocation_events (tenant_id, order_ref);
@@ -0,0 +1,5 @@
+-- Add down migration script here
+ALTER TABLE pending_lines DROP CONSTRAINT IF EXISTS pending_lines_qty_positive;
+ALTER TABLE reservations DROP CONSTRAINT IF EXISTS reservations_qty_positive;
+ALTER TABLE allocations DROP CONSTRAINT IF EXISTS allocations_qty_positive;
+ALTER TABLE batches DROP CONSTRAINT IF EXISTS batches_qty_positive;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- NOT VALID leaves rows written before the checks alone, new and updated
+-- ones must pass
+ALTER TABLE batches
+  ADD CONSTRAINT batches_qty_positive CHECK (qty > 0) NOT VALID;
+ALTER TABLE allocations
+  ADD CONSTRAINT allocations_qty_positive CHECK (qty > 0) NOT VALID;
+ALTER TABLE reservations
+  ADD CONSTRAINT reservations_qty_positive CHECK (qty > 0) NOT VALID;
+ALTER TABLE pending_lines
+  ADD CONSTRAINT pending_lines_qty_positive CHECK (qty > 0) NOT VALID;
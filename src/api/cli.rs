@@ -0,0 +1,286 @@
+use crate::api::http::{self, AppState};
+use crate::domain::commands::{Command, CommandOutcome};
+use crate::domain::model::{Batch, BatchId};
+use crate::domain::repository::ProductRepository;
+use crate::domain::tenant::{TenantId, DEFAULT_TENANT};
+use crate::infrastructure::repository::PostgresBatchRepository;
+use crate::services::handlers::InvalidSku;
+use chrono::{DateTime, Local};
+use clap::{Parser, Subcommand};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use std::io::Write;
+
+#[derive(Debug, Parser)]
+#[command(name = "cosmic", about = "Allocates order lines to batches")]
+pub struct Cli {
+    /// Print results as JSON instead of a table
+    #[arg(long, global = true)]
+    pub json: bool,
+    /// Tenant whose data the command works on
+    #[arg(
+        long,
+        global = true,
+        default_value = DEFAULT_TENANT,
+        value_parser = |tenant: &str| TenantId::try_from(tenant)
+    )]
+    pub tenant: TenantId,
+    /// Same as the `migrate` subcommand, kept for existing deploy scripts
+    #[arg(long, hide = true)]
+    pub migrate: bool,
+    /// Runs the server when left out
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Subcommand)]
+pub enum CliCommand {
+    /// Serve the HTTP and gRPC APIs
+    Serve,
+    /// Apply pending migrations and exit
+    Migrate,
+    /// Allocate an order line
+    Allocate {
+        #[arg(long)]
+        order_ref: String,
+        #[arg(long)]
+        sku: String,
+        #[arg(long)]
+        qty: u32,
+    },
+    /// Register a batch
+    AddBatch {
+        #[arg(long = "ref")]
+        reference: String,
+        #[arg(long)]
+        sku: String,
+        #[arg(long)]
+        qty: u32,
+        /// RFC 3339 date and time the batch arrives, in stock without one
+        #[arg(long)]
+        eta: Option<DateTime<Local>>,
+    },
+    /// List the batches of a product
+    Show {
+        #[arg(long)]
+        sku: String,
+    },
+}
+
+#[derive(Debug, Serialize)]
+struct Allocation {
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    batch_id: BatchId,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchLine {
+    id: Option<BatchId>,
+    reference: Option<String>,
+    qty: u32,
+    available: u32,
+    eta: Option<DateTime<Local>>,
+}
+
+impl From<&Batch> for BatchLine {
+    fn from(batch: &Batch) -> Self {
+        BatchLine {
+            id: batch.id,
+            reference: batch.reference.clone(),
+            qty: batch.qty,
+            available: batch.available_qty(),
+            eta: batch.eta,
+        }
+    }
+}
+
+// Runs one of the one-off commands against the database through the same
+// message bus as the API, writing the result to `out`. `Serve` and `Migrate`
+// are handled by the binary.
+pub async fn run(
+    command: CliCommand,
+    tenant: &TenantId,
+    json: bool,
+    pg_pool: PgPool,
+    out: &mut dyn Write,
+) -> anyhow::Result<()> {
+    let state = AppState::new(pg_pool.clone());
+    match command {
+        CliCommand::Allocate {
+            order_ref,
+            sku,
+            qty,
+        } => {
+            let command = Command::Allocate {
+                order_ref: order_ref.clone(),
+                sku: sku.clone(),
+                qty,
+            };
+            let CommandOutcome::Allocated { batch_id } =
+                http::handle_command(&state, tenant, command).await?
+            else {
+                anyhow::bail!("allocation returned no batch");
+            };
+            let allocation = Allocation {
+                order_ref,
+                sku,
+                qty,
+                batch_id,
+            };
+            if json {
+                writeln!(out, "{}", serde_json::to_string(&allocation)?)?;
+            } else {
+                writeln!(
+                    out,
+                    "{:<12} {:<16} {:>6} BATCH",
+                    "ORDER", "SKU", "QTY"
+                )?;
+                writeln!(
+                    out,
+                    "{:<12} {:<16} {:>6} {}",
+                    allocation.order_ref,
+                    allocation.sku,
+                    allocation.qty,
+                    allocation.batch_id
+                )?;
+            }
+        }
+        CliCommand::AddBatch {
+            reference,
+            sku,
+            qty,
+            eta,
+        } => {
+            let command = Command::CreateBatch {
+                reference: reference.clone(),
+                sku: sku.clone(),
+                qty,
+                eta,
+            };
+            http::handle_command(&state, tenant, command).await?;
+            if json {
+                let batch = serde_json::json!({
+                    "reference": reference,
+                    "sku": sku,
+                    "qty": qty,
+                    "eta": eta,
+                });
+                writeln!(out, "{}", batch)?;
+            } else {
+                writeln!(out, "added batch {} of {} {}", reference, qty, sku)?;
+            }
+        }
+        CliCommand::Show { sku } => {
+            let product =
+                PostgresBatchRepository::for_tenant(pg_pool, tenant.clone())
+                    .get(&sku)
+                    .await?
+                    .ok_or_else(|| InvalidSku(sku.clone()))?;
+            let batches: Vec<BatchLine> =
+                product.batches.iter().map(BatchLine::from).collect();
+            if json {
+                writeln!(out, "{}", serde_json::to_string(&batches)?)?;
+            } else {
+                writeln!(
+                    out,
+                    "{:>6} {:<16} {:>6} {:>9} ETA",
+                    "ID", "REFERENCE", "QTY", "AVAILABLE"
+                )?;
+                for batch in &batches {
+                    let id = batch.id.map(|id| id.to_string());
+                    let eta = batch.eta.map(|eta| eta.to_rfc3339());
+                    writeln!(
+                        out,
+                        "{:>6} {:<16} {:>6} {:>9} {}",
+                        id.as_deref().unwrap_or("-"),
+                        batch.reference.as_deref().unwrap_or("-"),
+                        batch.qty,
+                        batch.available,
+                        eta.as_deref().unwrap_or("in stock")
+                    )?;
+                }
+            }
+        }
+        CliCommand::Serve | CliCommand::Migrate => {
+            anyhow::bail!("{:?} is run by the binary itself", command)
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parses_subcommands() {
+        let cli = Cli::try_parse_from([
+            "cosmic",
+            "add-batch",
+            "--ref",
+            "BATCH_1",
+            "--sku",
+            "SMALL_TABLE",
+            "--qty",
+            "10",
+            "--json",
+        ])
+        .unwrap();
+
+        assert!(cli.json);
+        assert_eq!(cli.tenant, TenantId::default());
+        assert_eq!(
+            cli.command,
+            Some(CliCommand::AddBatch {
+                reference: "BATCH_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 10,
+                eta: None,
+            })
+        );
+        let cli = Cli::try_parse_from(["cosmic", "--migrate"]).unwrap();
+        assert!(cli.migrate && cli.command.is_none());
+        assert!(
+            Cli::try_parse_from(["cosmic", "allocate", "--qty", "1"]).is_err()
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_show_lists_batches_as_a_table(pg_pool: PgPool) {
+        let tenant = TenantId::default();
+        let mut out = Vec::new();
+        let add = CliCommand::AddBatch {
+            reference: "BATCH_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 10,
+            eta: None,
+        };
+        run(add, &tenant, false, pg_pool.clone(), &mut out)
+            .await
+            .unwrap();
+        let allocate = CliCommand::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 4,
+        };
+        run(allocate, &tenant, false, pg_pool.clone(), &mut out)
+            .await
+            .unwrap();
+        out.clear();
+
+        let show = CliCommand::Show {
+            sku: "SMALL_TABLE".to_string(),
+        };
+        run(show, &tenant, false, pg_pool, &mut out).await.unwrap();
+
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<Vec<&str>> = out
+            .lines()
+            .map(|line| line.split_whitespace().collect())
+            .collect();
+        assert_eq!(lines[0], ["ID", "REFERENCE", "QTY", "AVAILABLE", "ETA"]);
+        assert_eq!(lines[1], ["1", "BATCH_1", "10", "6", "in", "stock"]);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::api::http::{self, AppState, TENANT_HEADER};
+use crate::domain::commands::{Command, CommandOutcome};
+use crate::domain::model::{AllocationError, DomainError};
+use crate::domain::tenant::{InvalidTenant, TenantId};
+use crate::infrastructure::db;
+use crate::services::handlers::InvalidSku;
+use axum::Router;
+use chrono::{DateTime, Local};
+use tonic::{Request, Response, Status};
+
+pub mod proto {
+    tonic::include_proto!("allocation");
+}
+
+use proto::allocation_server::{Allocation, AllocationServer};
+
+// Runs the same commands as the HTTP API, through the same message bus
+pub struct AllocationService {
+    state: AppState,
+}
+
+impl AllocationService {
+    pub fn new(state: AppState) -> Self {
+        Self { state }
+    }
+}
+
+// gRPC calls are plain HTTP/2 requests, so the service is mounted on the
+// HTTP router and shares its listener and graceful shutdown
+pub fn routes(state: AppState) -> Router {
+    Router::new().route_service(
+        "/allocation.Allocation/{*method}",
+        AllocationServer::new(AllocationService::new(state)),
+    )
+}
+
+// Read from the `x-tenant-id` metadata, like the HTTP header
+fn tenant<T>(request: &Request<T>) -> Result<TenantId, InvalidTenant> {
+    let value = match request.metadata().get(TENANT_HEADER) {
+        Some(value) => Some(value.to_str().map_err(|_| {
+            InvalidTenant(String::from_utf8_lossy(value.as_bytes()).into())
+        })?),
+        None => None,
+    };
+    http::tenant_from_header(value)
+}
+
+#[tonic::async_trait]
+impl Allocation for AllocationService {
+    async fn allocate(
+        &self,
+        request: Request<proto::AllocateRequest>,
+    ) -> Result<Response<proto::AllocateResponse>, Status> {
+        let tenant = tenant(&request)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let request = request.into_inner();
+        let command = Command::Allocate {
+            order_ref: request.order_ref,
+            sku: request.sku,
+            qty: request.qty,
+        };
+
+        match http::handle_command(&self.state, &tenant, command).await {
+            Ok(CommandOutcome::Allocated { batch_id }) => {
+                Ok(Response::new(proto::AllocateResponse {
+                    batch_id: batch_id.into(),
+                }))
+            }
+            Ok(outcome) => Err(Status::internal(format!(
+                "unexpected outcome {:?} of an allocation",
+                outcome
+            ))),
+            Err(err) => Err(status(err)),
+        }
+    }
+
+    async fn add_batch(
+        &self,
+        request: Request<proto::AddBatchRequest>,
+    ) -> Result<Response<proto::AddBatchResponse>, Status> {
+        let tenant = tenant(&request)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let request = request.into_inner();
+        let eta = match request.eta {
+            Some(timestamp) => Some(
+                eta_from_timestamp(timestamp)
+                    .ok_or_else(|| Status::invalid_argument("Invalid eta"))?,
+            ),
+            None => None,
+        };
+        let command = Command::CreateBatch {
+            reference: request.r#ref,
+            sku: request.sku,
+            qty: request.qty,
+            eta,
+        };
+
+        http::handle_command(&self.state, &tenant, command)
+            .await
+            .map_err(status)?;
+        Ok(Response::new(proto::AddBatchResponse {}))
+    }
+}
+
+fn eta_from_timestamp(
+    timestamp: prost_types::Timestamp,
+) -> Option<DateTime<Local>> {
+    let nanos = u32::try_from(timestamp.nanos).ok()?;
+    DateTime::from_timestamp(timestamp.seconds, nanos)
+        .map(|eta| eta.with_timezone(&Local))
+}
+
+// Mirrors the HTTP status mapping. Lines that can't be allocated with the
+// current stock are a failed precondition rather than a bad request.
+fn status(err: anyhow::Error) -> Status {
+    if db::is_statement_timeout(&err) {
+        return Status::deadline_exceeded(
+            "The database took too long to respond",
+        );
+    }
+    if err.is::<InvalidSku>() || err.is::<DomainError>() {
+        return Status::invalid_argument(err.to_string());
+    }
+    match err.downcast_ref::<AllocationError>() {
+        Some(AllocationError::Invalid(_)) => {
+            Status::invalid_argument(err.to_string())
+        }
+        Some(
+            AllocationError::UnknownBatch { .. }
+            | AllocationError::UnknownProduct { .. },
+        ) => Status::not_found(err.to_string()),
+        Some(_) => Status::failed_precondition(err.to_string()),
+        None => {
+            tracing::error!(%err, "command failed");
+            Status::internal(err.to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proto::allocation_client::AllocationClient;
+    use sqlx::postgres::PgPool;
+    use tokio::net::TcpListener;
+    use tonic::transport::Channel;
+    use tonic::Code;
+
+    async fn client(pg_pool: PgPool) -> AllocationClient<Channel> {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let state = AppState::new(pg_pool);
+        tokio::spawn(http::serve(listener, state, std::future::pending()));
+        AllocationClient::connect(format!("http://{addr}"))
+            .await
+            .unwrap()
+    }
+
+    fn add_batch(sku: &str, qty: u32) -> proto::AddBatchRequest {
+        proto::AddBatchRequest {
+            r#ref: "BATCH_1".to_string(),
+            sku: sku.to_string(),
+            qty,
+            eta: None,
+        }
+    }
+
+    fn allocate(sku: &str, qty: u32) -> proto::AllocateRequest {
+        proto::AllocateRequest {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.to_string(),
+            qty,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_batch_id(pg_pool: PgPool) {
+        let mut client = client(pg_pool.clone()).await;
+        client
+            .add_batch(add_batch("SMALL_TABLE", 10))
+            .await
+            .unwrap();
+
+        let response = client
+            .allocate(allocate("SMALL_TABLE", 3))
+            .await
+            .unwrap()
+            .into_inner();
+
+        let batch_id: i32 = sqlx::query_scalar("SELECT id FROM batches")
+            .fetch_one(&pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(response.batch_id, batch_id as u32);
+    }
+
+    #[sqlx::test]
+    async fn test_out_of_stock_is_a_failed_precondition(pg_pool: PgPool) {
+        let mut client = client(pg_pool).await;
+        client
+            .add_batch(add_batch("SMALL_TABLE", 10))
+            .await
+            .unwrap();
+
+        let status = client
+            .allocate(allocate("SMALL_TABLE", 30))
+            .await
+            .unwrap_err();
+
+        assert_eq!(status.code(), Code::FailedPrecondition);
+    }
+
+    #[sqlx::test]
+    async fn test_unknown_sku_is_an_invalid_argument(pg_pool: PgPool) {
+        let mut client = client(pg_pool).await;
+
+        let status = client.allocate(allocate("MISSING", 3)).await.unwrap_err();
+
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(status.message(), "Invalid sku MISSING");
+    }
+}
@@ -0,0 +1,1192 @@
+use crate::api::grpc;
+use crate::api::rate_limit::RateLimiter;
+use crate::domain::commands::{Command, CommandOutcome};
+use crate::domain::model::{AllocationError, BatchId, DomainError};
+use crate::domain::tenant::{InvalidTenant, TenantId};
+use crate::infrastructure::db;
+use crate::infrastructure::idempotency::{self, StoredResponse};
+use crate::services::bootstrap::bootstrap;
+use crate::services::handlers::{self, InvalidSku};
+use crate::services::messagebus::MessageBus;
+use crate::services::unit_of_work::{InMemoryStore, UnitOfWorkFactory};
+use crate::services::views;
+use axum::extract::{FromRequestParts, Path, Query, State};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use chrono::{DateTime, Local};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sqlx::postgres::PgPool;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+
+// How long `/readyz` waits for `SELECT 1` before reporting the database as
+// unavailable. Kept well below the usual Kubernetes probe timeout (1s).
+pub const READINESS_TIMEOUT: Duration = Duration::from_millis(500);
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+// Which tenant the request works on, the default one when it is left out
+pub const TENANT_HEADER: &str = "x-tenant-id";
+pub const DEFAULT_IDEMPOTENCY_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+#[derive(Clone)]
+pub struct AppState {
+    // `None` in memory mode, which turns off idempotency keys, the
+    // allocations view and the outbox
+    pub pg_pool: Option<PgPool>,
+    pub uow: Arc<dyn UnitOfWorkFactory>,
+    // How long a response is replayed for a repeated `Idempotency-Key`
+    pub idempotency_ttl: Duration,
+    // Shared by every request to `POST /allocate`, `None` leaves it unlimited
+    pub allocate_limiter: Option<Arc<RateLimiter>>,
+    pub bus: Arc<MessageBus>,
+}
+
+impl AppState {
+    pub fn new(pg_pool: PgPool) -> Self {
+        Self {
+            bus: Arc::new(bootstrap(pg_pool.clone())),
+            uow: Arc::new(pg_pool.clone()),
+            pg_pool: Some(pg_pool),
+            idempotency_ttl: DEFAULT_IDEMPOTENCY_TTL,
+            allocate_limiter: None,
+        }
+    }
+
+    // The event handlers all write to Postgres, so none are registered
+    pub fn in_memory(store: InMemoryStore) -> Self {
+        Self {
+            pg_pool: None,
+            uow: Arc::new(store),
+            idempotency_ttl: DEFAULT_IDEMPOTENCY_TTL,
+            allocate_limiter: None,
+            bus: Arc::new(MessageBus::new()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AllocateRequest {
+    pub order_ref: String,
+    pub sku: String,
+    pub qty: u32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AllocateResponse {
+    pub batch_id: BatchId,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreviewResponse {
+    // `None` when no batch could take the line
+    pub batch_id: Option<BatchId>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddBatchRequest {
+    #[serde(rename = "ref")]
+    pub reference: String,
+    pub sku: String,
+    pub qty: u32,
+    // RFC3339, `None` means the batch is in stock
+    pub eta: Option<DateTime<Local>>,
+}
+
+pub fn router(state: AppState) -> Router {
+    prometheus();
+    Router::new()
+        .route("/allocate", post(allocate))
+        .route("/allocate/preview", get(preview_allocation))
+        .route("/batches", post(add_batch))
+        .route("/allocations/{order_ref}", get(allocations))
+        .route("/products/{sku}", get(product))
+        .route("/products/{sku}/batches", get(batches))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
+        .with_state(state.clone())
+        .merge(grpc::routes(state))
+}
+
+// Serves the API until `shutdown` resolves. In-flight requests are drained
+// first so no unit of work is cut off mid-commit, then the pool is closed.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    state: AppState,
+    shutdown: impl Future<Output = ()> + Send + 'static,
+) -> anyhow::Result<()> {
+    let pg_pool = state.pg_pool.clone();
+    axum::serve(listener, router(state))
+        .with_graceful_shutdown(shutdown)
+        .await?;
+    if let Some(pg_pool) = pg_pool {
+        tracing::info!("server stopped, closing the database pool");
+        pg_pool.close().await;
+    }
+    Ok(())
+}
+
+// Resolves on Ctrl+C, or SIGTERM on Unix
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            tracing::error!(%err, "failed to listen for Ctrl+C");
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            }
+            Err(err) => {
+                tracing::error!(%err, "failed to listen for SIGTERM");
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+    tracing::info!("shutdown signal received, draining requests");
+}
+
+pub fn tenant_from_header(
+    value: Option<&str>,
+) -> Result<TenantId, InvalidTenant> {
+    match value {
+        Some(tenant) => TenantId::try_from(tenant),
+        None => Ok(TenantId::default()),
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for TenantId {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Self, Self::Rejection> {
+        let value = match parts.headers.get(TENANT_HEADER) {
+            Some(value) => Some(value.to_str().map_err(|_| {
+                ApiError::BadRequest("Invalid tenant header".to_string())
+            })?),
+            None => None,
+        };
+        tenant_from_header(value)
+            .map_err(|err| ApiError::BadRequest(err.to_string()))
+    }
+}
+
+async fn add_batch(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    Json(request): Json<AddBatchRequest>,
+) -> Response {
+    let command = Command::CreateBatch {
+        reference: request.reference,
+        sku: request.sku,
+        qty: request.qty,
+        eta: request.eta,
+    };
+
+    match handle_command(&state, &tenant, command).await {
+        Ok(_) => StatusCode::CREATED.into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+// Requests carrying an `Idempotency-Key` header get the response recorded
+// for that key replayed instead of being allocated again. Server errors
+// aren't recorded so the request can be retried. Requests past the rate
+// limit are turned away before touching the database, replays included.
+async fn allocate(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    headers: HeaderMap,
+    Json(request): Json<AllocateRequest>,
+) -> Response {
+    if let Some(limiter) = &state.allocate_limiter {
+        if !limiter.try_acquire() {
+            return ApiError::RateLimited.into_response();
+        }
+    }
+    let key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let idempotency = state.pg_pool.clone().zip(key);
+    if let Some((pg_pool, key)) = &idempotency {
+        let ttl = state.idempotency_ttl;
+        match idempotency::get(pg_pool, &tenant, key, ttl).await {
+            Ok(Some(stored)) => return stored_response(stored),
+            Ok(None) => {}
+            Err(err) => return error_response(err),
+        }
+    }
+
+    let sku = request.sku.clone();
+    let command = Command::Allocate {
+        order_ref: request.order_ref,
+        sku: request.sku,
+        qty: request.qty,
+    };
+
+    let (status, body) = match handle_command(&state, &tenant, command).await {
+        Ok(CommandOutcome::Allocated { batch_id }) => {
+            (StatusCode::CREATED, json!(AllocateResponse { batch_id }))
+        }
+        Ok(outcome) => ApiError::from(anyhow::anyhow!(
+            "unexpected outcome {:?} of an allocation",
+            outcome
+        ))
+        .to_parts(),
+        Err(err) => ApiError::from_allocation(err, &sku).to_parts(),
+    };
+    if let Some((pg_pool, key)) =
+        idempotency.filter(|_| !status.is_server_error())
+    {
+        let stored = StoredResponse {
+            status: status.as_u16(),
+            body,
+        };
+        if let Err(err) =
+            idempotency::put(&pg_pool, &tenant, &key, &stored).await
+        {
+            return error_response(err);
+        }
+        return stored_response(stored);
+    }
+    (status, Json(body)).into_response()
+}
+
+async fn preview_allocation(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    Query(request): Query<AllocateRequest>,
+) -> Response {
+    let uow = match state.uow.begin_for(&tenant).await {
+        Ok(uow) => uow,
+        Err(err) => return error_response(err),
+    };
+    let result = handlers::preview_allocation(
+        request.order_ref,
+        request.sku,
+        request.qty,
+        uow.products(),
+    )
+    .await;
+
+    match result {
+        Ok(batch_id) => Json(PreviewResponse { batch_id }).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+fn stored_response(stored: StoredResponse) -> Response {
+    let status = StatusCode::from_u16(stored.status)
+        .unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+    (status, Json(stored.body)).into_response()
+}
+
+async fn allocations(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    Path(order_ref): Path<String>,
+) -> Response {
+    let Some(pg_pool) = &state.pg_pool else {
+        let message = "The allocations view needs Postgres";
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!({ "message": message })),
+        )
+            .into_response();
+    };
+    match views::allocations(&order_ref, &tenant, pg_pool).await {
+        Ok(allocations) if allocations.is_empty() => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": format!("Unknown order {}", order_ref) })),
+        )
+            .into_response(),
+        Ok(allocations) => Json(allocations).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+async fn product(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    Path(sku): Path<String>,
+) -> Response {
+    let product = match state.uow.begin_for(&tenant).await {
+        Ok(uow) => uow.products().get(&sku).await,
+        Err(err) => return error_response(err),
+    };
+    match product {
+        Ok(Some(product)) => Json(product.summary()).into_response(),
+        Ok(None) => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": format!("Unknown product {}", sku) })),
+        )
+            .into_response(),
+        Err(err) => error_response(err.into()),
+    }
+}
+
+// Read from `batch_view` only, so it can lag the write model slightly
+async fn batches(
+    State(state): State<AppState>,
+    tenant: TenantId,
+    Path(sku): Path<String>,
+) -> Response {
+    let Some(pg_pool) = &state.pg_pool else {
+        let message = "The batches view needs Postgres";
+        return (
+            StatusCode::NOT_IMPLEMENTED,
+            Json(json!({ "message": message })),
+        )
+            .into_response();
+    };
+    match views::batches_for_sku(&sku, &tenant, pg_pool).await {
+        Ok(batches) if batches.is_empty() => (
+            StatusCode::NOT_FOUND,
+            Json(json!({ "message": format!("Unknown product {}", sku) })),
+        )
+            .into_response(),
+        Ok(batches) => Json(batches).into_response(),
+        Err(err) => error_response(err),
+    }
+}
+
+// The recorder is process wide, so it is installed by whichever router is
+// built first and shared from then on
+fn prometheus() -> &'static PrometheusHandle {
+    static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+    HANDLE.get_or_init(|| {
+        PrometheusBuilder::new()
+            .install_recorder()
+            .expect("metrics recorder already installed")
+    })
+}
+
+async fn metrics() -> String {
+    prometheus().render()
+}
+
+async fn healthz() -> StatusCode {
+    StatusCode::OK
+}
+
+async fn readyz(State(state): State<AppState>) -> StatusCode {
+    let Some(pg_pool) = &state.pg_pool else {
+        return StatusCode::OK;
+    };
+    let ping = sqlx::query("SELECT 1").execute(pg_pool);
+    match tokio::time::timeout(READINESS_TIMEOUT, ping).await {
+        Ok(Ok(_)) => StatusCode::OK,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    }
+}
+
+pub(crate) async fn handle_command(
+    state: &AppState,
+    tenant: &TenantId,
+    command: Command,
+) -> anyhow::Result<CommandOutcome> {
+    let command = &command;
+    handlers::retry_on_conflict(handlers::MAX_ATTEMPTS, || async move {
+        let isolation = handlers::isolation_level(command);
+        let mut uow = state.uow.begin_with(tenant, isolation).await?;
+        state
+            .bus
+            .handle_command(command.clone(), uow.as_mut())
+            .await
+    })
+    .await
+}
+
+fn error_response(err: anyhow::Error) -> Response {
+    ApiError::from(err).into_response()
+}
+
+// Service errors as the API reports them. The body's `error` is a stable
+// code clients can match on, `message` is meant for people.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ApiError {
+    OutOfStock { sku: String },
+    InvalidSku { sku: String },
+    BadRequest(String),
+    Timeout,
+    RateLimited,
+    Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+pub struct ErrorBody {
+    pub error: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sku: Option<String>,
+    pub message: String,
+}
+
+impl ApiError {
+    // Running out of stock is only known to be about `sku` here, the
+    // domain error doesn't carry it
+    pub fn from_allocation(err: anyhow::Error, sku: &str) -> Self {
+        match err.downcast_ref::<AllocationError>() {
+            Some(AllocationError::NoBatchAvailable) => ApiError::OutOfStock {
+                sku: sku.to_string(),
+            },
+            _ => ApiError::from(err),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::OutOfStock { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::InvalidSku { .. } | ApiError::BadRequest(_) => {
+                StatusCode::BAD_REQUEST
+            }
+            ApiError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        let (error, sku, message) = match self {
+            ApiError::OutOfStock { sku } => (
+                "out_of_stock",
+                Some(sku.clone()),
+                AllocationError::NoBatchAvailable.to_string(),
+            ),
+            ApiError::InvalidSku { sku } => (
+                "invalid_sku",
+                Some(sku.clone()),
+                InvalidSku(sku.clone()).to_string(),
+            ),
+            ApiError::BadRequest(message) => {
+                ("bad_request", None, message.clone())
+            }
+            ApiError::Timeout => (
+                "timeout",
+                None,
+                "The database took too long to respond".to_string(),
+            ),
+            ApiError::RateLimited => (
+                "rate_limited",
+                None,
+                "Too many allocation requests, try again shortly".to_string(),
+            ),
+            ApiError::Internal(message) => ("internal", None, message.clone()),
+        };
+        ErrorBody {
+            error,
+            sku,
+            message,
+        }
+    }
+
+    fn to_parts(&self) -> (StatusCode, Value) {
+        (self.status(), json!(self.body()))
+    }
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        if db::is_statement_timeout(&err) {
+            tracing::warn!(%err, "query timed out");
+            return ApiError::Timeout;
+        }
+        if let Some(InvalidSku(sku)) = err.downcast_ref::<InvalidSku>() {
+            return ApiError::InvalidSku { sku: sku.clone() };
+        }
+        if err.is::<AllocationError>() || err.is::<DomainError>() {
+            return ApiError::BadRequest(err.to_string());
+        }
+        ApiError::Internal(err.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.status(), Json(self.body())).into_response()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::{Batch, Product};
+    use crate::domain::repository::ProductRepository;
+    use crate::infrastructure::repository::fixtures::seed_batch;
+    use crate::infrastructure::repository::PostgresBatchRepository;
+    use axum::body::Body;
+    use axum::http::Request;
+    use http_body_util::BodyExt;
+    use sqlx::postgres::PgPoolOptions;
+    use tower::ServiceExt;
+
+    // Never connects until a query is run against it
+    fn unreachable_pool() -> PgPool {
+        PgPoolOptions::new()
+            .acquire_timeout(Duration::from_millis(100))
+            .connect_lazy("postgres://postgres@127.0.0.1:1/cosmic")
+            .unwrap()
+    }
+
+    // Sends one request through the app. JSON bodies are parsed, anything
+    // else comes back as a string.
+    async fn send(
+        state: AppState,
+        request: Request<Body>,
+    ) -> (StatusCode, Value) {
+        let response = router(state).oneshot(request).await.unwrap();
+
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let body = serde_json::from_slice(&body).unwrap_or_else(|_| {
+            Value::String(String::from_utf8_lossy(&body).into_owned())
+        });
+        (status, body)
+    }
+
+    fn get(uri: &str) -> Request<Body> {
+        Request::get(uri).body(Body::empty()).unwrap()
+    }
+
+    // A JSON body with any extra headers, e.g. the tenant
+    fn post(uri: &str, headers: &[(&str, &str)], body: Value) -> Request<Body> {
+        let mut request =
+            Request::post(uri).header("content-type", "application/json");
+        for (name, value) in headers {
+            request = request.header(*name, *value);
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_201_and_batch_id(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 3,
+                }),
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, json!({ "batch_id": 1 }));
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_429_past_the_rate_limit(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 100).await;
+        let mut state = AppState::new(pg_pool);
+        state.allocate_limiter = Some(Arc::new(RateLimiter::per_second(3)));
+
+        let mut statuses = Vec::new();
+        for n in 0..10 {
+            let (status, body) = send(
+                state.clone(),
+                post(
+                    "/allocate",
+                    &[(IDEMPOTENCY_KEY_HEADER, &format!("KEY_{}", n))],
+                    json!({
+                        "order_ref": format!("ORDER_{}", n),
+                        "sku": "SMALL_TABLE",
+                        "qty": 1,
+                    }),
+                ),
+            )
+            .await;
+            if status == StatusCode::TOO_MANY_REQUESTS {
+                assert_eq!(body["error"], "rate_limited");
+            }
+            statuses.push(status);
+        }
+
+        let limited = statuses
+            .iter()
+            .filter(|status| **status == StatusCode::TOO_MANY_REQUESTS)
+            .count();
+        assert!(limited > 0, "{:?}", statuses);
+        assert!(statuses[..3]
+            .iter()
+            .all(|status| *status == StatusCode::CREATED));
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_422_when_out_of_stock(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 30,
+                }),
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(
+            body,
+            json!({
+                "error": "out_of_stock",
+                "sku": "SMALL_TABLE",
+                "message": AllocationError::NoBatchAvailable.to_string(),
+            })
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_400_for_invalid_sku(pg_pool: PgPool) {
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({ "order_ref": "ORDER_1", "sku": "UNKNOWN", "qty": 3 }),
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            body,
+            json!({
+                "error": "invalid_sku",
+                "sku": "UNKNOWN",
+                "message": "Invalid sku UNKNOWN",
+            })
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_returns_400_for_zero_quantity(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 0,
+                }),
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"], "bad_request");
+        assert_eq!(body.get("sku"), None);
+    }
+
+    #[sqlx::test]
+    async fn test_add_batch_then_allocate_against_it(pg_pool: PgPool) {
+        let (status, _) = send(
+            AppState::new(pg_pool.clone()),
+            post(
+                "/batches",
+                &[],
+                json!({
+                    "ref": "BATCH_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 100,
+                    "eta": "2030-01-01T10:00:00+00:00"
+                }),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 3,
+                }),
+            ),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, json!({ "batch_id": 1 }));
+    }
+
+    #[sqlx::test]
+    async fn test_add_batch_without_eta_is_in_stock(pg_pool: PgPool) {
+        let (status, _) = send(
+            AppState::new(pg_pool.clone()),
+            post(
+                "/batches",
+                &[],
+                json!({ "ref": "BATCH_1", "sku": "SMALL_TABLE", "qty": 100 }),
+            ),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let product = repo.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].eta, None);
+        assert_eq!(product.batches[0].reference, Some("BATCH_1".to_string()));
+    }
+
+    #[sqlx::test]
+    async fn test_allocations_returns_batch_per_line(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        seed_batch(&pg_pool, "BLUE_LAMP", 10).await;
+        for sku in ["SMALL_TABLE", "BLUE_LAMP"] {
+            let (status, _) = send(
+                AppState::new(pg_pool.clone()),
+                post(
+                    "/allocate",
+                    &[],
+                    json!({ "order_ref": "ORDER_1", "sku": sku, "qty": 3 }),
+                ),
+            )
+            .await;
+            assert_eq!(status, StatusCode::CREATED);
+        }
+
+        let (status, body) =
+            send(AppState::new(pg_pool), get("/allocations/ORDER_1")).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([
+                { "sku": "BLUE_LAMP", "batch_id": 2 },
+                { "sku": "SMALL_TABLE", "batch_id": 1 },
+            ])
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_allocations_returns_404_for_unknown_order(pg_pool: PgPool) {
+        let (status, _) =
+            send(AppState::new(pg_pool), get("/allocations/ORDER_1")).await;
+
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn test_product_returns_its_batch_summary(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        send(
+            AppState::new(pg_pool.clone()),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 3,
+                }),
+            ),
+        )
+        .await;
+
+        let (status, body) =
+            send(AppState::new(pg_pool.clone()), get("/products/SMALL_TABLE"))
+                .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([{
+                "batch_id": 1,
+                "eta": null,
+                "total_qty": 10,
+                "allocated_qty": 3,
+                "available_qty": 7,
+                "order_refs": ["ORDER_1"],
+            }])
+        );
+        let (_, lowercase) =
+            send(AppState::new(pg_pool.clone()), get("/products/small_table"))
+                .await;
+        assert_eq!(lowercase, body);
+        let (status, _) =
+            send(AppState::new(pg_pool), get("/products/BLUE_LAMP")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[sqlx::test]
+    async fn test_product_batches_are_read_from_the_view(pg_pool: PgPool) {
+        let batch =
+            json!({ "ref": "BATCH_1", "sku": "SMALL_TABLE", "qty": 10 });
+        let (status, _) =
+            send(AppState::new(pg_pool.clone()), post("/batches", &[], batch))
+                .await;
+        assert_eq!(status, StatusCode::CREATED);
+        send(
+            AppState::new(pg_pool.clone()),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 3,
+                }),
+            ),
+        )
+        .await;
+
+        let (status, body) = send(
+            AppState::new(pg_pool.clone()),
+            get("/products/SMALL_TABLE/batches"),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(
+            body,
+            json!([{
+                "batch_id": 1,
+                "reference": "BATCH_1",
+                "eta": null,
+                "qty": 10,
+                "available_qty": 7,
+            }])
+        );
+        let (_, lowercase) = send(
+            AppState::new(pg_pool.clone()),
+            get("/products/small_table/batches"),
+        )
+        .await;
+        assert_eq!(lowercase, body);
+        let (status, _) =
+            send(AppState::new(pg_pool), get("/products/BLUE_LAMP/batches"))
+                .await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_healthz_does_not_need_a_database() {
+        let (status, _) =
+            send(AppState::new(unreachable_pool()), get("/healthz")).await;
+
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_readyz_returns_503_when_database_is_down() {
+        let (status, _) =
+            send(AppState::new(unreachable_pool()), get("/readyz")).await;
+
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    }
+
+    #[sqlx::test]
+    async fn test_readyz_returns_200_when_database_is_up(pg_pool: PgPool) {
+        let (status, _) = send(AppState::new(pg_pool), get("/readyz")).await;
+
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    async fn allocation_rows(pg_pool: &PgPool) -> i64 {
+        sqlx::query_scalar(r#"SELECT COUNT(*) FROM allocations"#)
+            .fetch_one(pg_pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_tenant_header_scopes_allocation(pg_pool: PgPool) {
+        let tenant = TenantId::try_from("acme").unwrap();
+        let repo = PostgresBatchRepository::for_tenant(pg_pool.clone(), tenant);
+        let batch = Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        let product =
+            Product::new("SMALL_TABLE".to_string(), vec![batch]).unwrap();
+        repo.add(&product).await.unwrap();
+        let body =
+            json!({ "order_ref": "ORDER_1", "sku": "SMALL_TABLE", "qty": 3 });
+
+        let (status, _) = send(
+            AppState::new(pg_pool.clone()),
+            post("/allocate", &[(TENANT_HEADER, "globex")], body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            AppState::new(pg_pool.clone()),
+            post("/allocate", &[], body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        let (status, _) = send(
+            AppState::new(pg_pool.clone()),
+            post("/allocate", &[(TENANT_HEADER, " ")], body.clone()),
+        )
+        .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let (status, body) = send(
+            AppState::new(pg_pool),
+            post("/allocate", &[(TENANT_HEADER, "acme")], body),
+        )
+        .await;
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, json!({ "batch_id": 1 }));
+    }
+
+    #[sqlx::test]
+    async fn test_repeated_idempotency_key_replays_response(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let state = AppState::new(pg_pool.clone());
+        let body =
+            json!({ "order_ref": "ORDER_1", "sku": "SMALL_TABLE", "qty": 3 });
+
+        let first = send(
+            state.clone(),
+            post(
+                "/allocate",
+                &[(IDEMPOTENCY_KEY_HEADER, "KEY_1")],
+                body.clone(),
+            ),
+        )
+        .await;
+        let second = send(
+            state,
+            post("/allocate", &[(IDEMPOTENCY_KEY_HEADER, "KEY_1")], body),
+        )
+        .await;
+
+        assert_eq!(first, (StatusCode::CREATED, json!({ "batch_id": 1 })));
+        assert_eq!(second, first);
+        assert_eq!(allocation_rows(&pg_pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn test_expired_idempotency_key_is_processed_again(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let state = AppState {
+            idempotency_ttl: Duration::ZERO,
+            ..AppState::new(pg_pool.clone())
+        };
+        let body =
+            json!({ "order_ref": "ORDER_1", "sku": "SMALL_TABLE", "qty": 3 });
+
+        let first = send(
+            state.clone(),
+            post(
+                "/allocate",
+                &[(IDEMPOTENCY_KEY_HEADER, "KEY_1")],
+                body.clone(),
+            ),
+        )
+        .await;
+        let second = send(
+            state,
+            post("/allocate", &[(IDEMPOTENCY_KEY_HEADER, "KEY_1")], body),
+        )
+        .await;
+
+        assert_eq!(first.0, StatusCode::CREATED);
+        // Allocated again, which finds the line already in its batch
+        assert_eq!(second, first);
+        assert_eq!(allocation_rows(&pg_pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn test_preview_matches_subsequent_allocation(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let uri = "/allocate/preview?order_ref=ORDER_1&sku=SMALL_TABLE&qty=3";
+
+        let (status, preview) =
+            send(AppState::new(pg_pool.clone()), get(uri)).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(allocation_rows(&pg_pool).await, 0);
+
+        let (_, allocated) = send(
+            AppState::new(pg_pool),
+            post(
+                "/allocate",
+                &[],
+                json!({
+                    "order_ref": "ORDER_1",
+                    "sku": "SMALL_TABLE",
+                    "qty": 3,
+                }),
+            ),
+        )
+        .await;
+        assert_eq!(preview, allocated);
+    }
+
+    #[sqlx::test]
+    async fn test_preview_returns_null_when_nothing_fits(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let uri = "/allocate/preview?order_ref=ORDER_1&sku=SMALL_TABLE&qty=30";
+
+        let (status, body) = send(AppState::new(pg_pool), get(uri)).await;
+
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, json!({ "batch_id": null }));
+    }
+
+    #[sqlx::test]
+    async fn test_metrics_count_allocations_and_out_of_stock(pg_pool: PgPool) {
+        // A SKU of its own, the recorder is shared with the other tests
+        seed_batch(&pg_pool, "METRICS_TABLE", 10).await;
+        for (order_ref, qty) in [("ORDER_1", 3), ("ORDER_2", 30)] {
+            send(
+                AppState::new(pg_pool.clone()),
+                post(
+                    "/allocate",
+                    &[],
+                    json!({
+                        "order_ref": order_ref,
+                        "sku": "METRICS_TABLE",
+                        "qty": qty,
+                    }),
+                ),
+            )
+            .await;
+        }
+
+        let (_, metrics) = send(AppState::new(pg_pool), get("/metrics")).await;
+        let metrics = metrics.as_str().unwrap();
+
+        assert!(metrics.contains(r#"allocations_total{sku="METRICS_TABLE"} 1"#));
+        assert!(
+            metrics.contains(r#"out_of_stock_total{sku="METRICS_TABLE"} 1"#)
+        );
+        assert!(metrics.contains("allocate_duration_seconds"));
+    }
+
+    #[sqlx::test]
+    async fn test_statement_timeout_is_a_gateway_timeout(pg_pool: PgPool) {
+        let mut conn = pg_pool.acquire().await.unwrap();
+        sqlx::query("SET statement_timeout = 10")
+            .execute(&mut *conn)
+            .await
+            .unwrap();
+        let err = sqlx::query("SELECT pg_sleep(1)")
+            .execute(&mut *conn)
+            .await
+            .unwrap_err();
+
+        let err = ApiError::from(anyhow::Error::from(err));
+
+        assert_eq!(err, ApiError::Timeout);
+        assert_eq!(err.status(), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(err.body().message, "The database took too long to respond");
+    }
+
+    #[sqlx::test]
+    async fn test_shutdown_drains_in_flight_request(pg_pool: PgPool) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        use tokio::net::{TcpListener, TcpStream};
+
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (stop, stopped) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(
+            listener,
+            AppState::new(pg_pool.clone()),
+            async move {
+                stopped.await.ok();
+            },
+        ));
+
+        // Holding the batch rows keeps the allocation waiting mid-request
+        let mut lock = pg_pool.begin().await.unwrap();
+        sqlx::query("SELECT id FROM batches FOR UPDATE")
+            .execute(&mut *lock)
+            .await
+            .unwrap();
+        let in_flight = tokio::spawn(async move {
+            let body = json!({
+                "order_ref": "ORDER_1", "sku": "SMALL_TABLE", "qty": 3
+            })
+            .to_string();
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            let request = format!(
+                "POST /allocate HTTP/1.1\r\nhost: localhost\r\n\
+                 content-type: application/json\r\n\
+                 content-length: {}\r\nconnection: close\r\n\r\n{}",
+                body.len(),
+                body
+            );
+            stream.write_all(request.as_bytes()).await.unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            response
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        stop.send(()).unwrap();
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(TcpStream::connect(addr).await.is_err());
+        assert!(!server.is_finished());
+
+        lock.rollback().await.unwrap();
+        let response = in_flight.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 201"), "{response}");
+        server.await.unwrap().unwrap();
+        assert!(pg_pool.is_closed());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_end_to_end_in_memory() {
+        let mut batch =
+            Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let store = InMemoryStore::with_products(vec![Product::new(
+            "SMALL_TABLE".to_string(),
+            vec![batch],
+        )
+        .unwrap()]);
+        let body =
+            json!({ "order_ref": "ORDER_1", "sku": "SMALL_TABLE", "qty": 3 });
+
+        let (status, body) = send(
+            AppState::in_memory(store.clone()),
+            post("/allocate", &[], body),
+        )
+        .await;
+
+        assert_eq!(status, StatusCode::CREATED);
+        assert_eq!(body, json!({ "batch_id": 1 }));
+        let product = store.products().get("SMALL_TABLE").await.unwrap();
+        assert_eq!(product.unwrap().batches[0].available_qty(), 7);
+    }
+}
@@ -0,0 +1,4 @@
+pub mod cli;
+pub mod grpc;
+pub mod http;
+pub mod rate_limit;
@@ -0,0 +1,71 @@
+use std::sync::Mutex;
+use std::time::Instant;
+
+// Token bucket holding up to a second's worth of requests. Each request takes
+// a token and tokens come back at `per_second`, so bursts up to the limit get
+// through and anything past it is turned away rather than queued.
+#[derive(Debug)]
+pub struct RateLimiter {
+    per_second: f64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: Instant,
+}
+
+impl RateLimiter {
+    pub fn per_second(per_second: u32) -> Self {
+        let per_second = f64::from(per_second.max(1));
+        Self {
+            per_second,
+            bucket: Mutex::new(Bucket {
+                tokens: per_second,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        self.try_acquire_at(Instant::now())
+    }
+
+    pub fn try_acquire_at(&self, now: Instant) -> bool {
+        let mut bucket = self.bucket.lock().unwrap();
+        let elapsed = now.saturating_duration_since(bucket.refilled_at);
+        bucket.tokens = (bucket.tokens
+            + elapsed.as_secs_f64() * self.per_second)
+            .min(self.per_second);
+        bucket.refilled_at = bucket.refilled_at.max(now);
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bursts_up_to_the_limit_then_refills() {
+        let limiter = RateLimiter::per_second(2);
+        let start = Instant::now();
+
+        assert!(limiter.try_acquire_at(start));
+        assert!(limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start));
+        assert!(!limiter.try_acquire_at(start + Duration::from_millis(400)));
+        assert!(limiter.try_acquire_at(start + Duration::from_millis(500)));
+        // An idle limiter never saves up more than a second's worth
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.try_acquire_at(later));
+        assert!(limiter.try_acquire_at(later));
+        assert!(!limiter.try_acquire_at(later));
+    }
+}
@@ -0,0 +1,32 @@
+use crate::domain::model::BatchId;
+use chrono::{DateTime, Local};
+
+// Requests to change the system, each handled by exactly one handler
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Allocate {
+        order_ref: String,
+        sku: String,
+        qty: u32,
+    },
+    CreateBatch {
+        reference: String,
+        sku: String,
+        qty: u32,
+        eta: Option<DateTime<Local>>,
+    },
+    ChangeBatchQuantity {
+        batch_id: BatchId,
+        qty: u32,
+    },
+    CancelAllocation {
+        order_ref: String,
+    },
+}
+
+// What a successfully handled command produced
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CommandOutcome {
+    Allocated { batch_id: BatchId },
+    Done,
+}
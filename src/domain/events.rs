@@ -0,0 +1,102 @@
+use crate::domain::model::BatchId;
+use crate::domain::tenant::TenantId;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+
+// Stored in the outbox as JSON tagged with the variant name
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum DomainEvent {
+    // The line that couldn't be allocated
+    OutOfStock {
+        order_ref: String,
+        sku: String,
+        qty: u32,
+    },
+    Allocated {
+        order_ref: String,
+        sku: String,
+        qty: u32,
+        batch_id: BatchId,
+    },
+    Deallocated {
+        order_ref: String,
+        sku: String,
+        qty: u32,
+        // The order was cancelled, so the line mustn't be allocated again
+        #[serde(default)]
+        cancelled: bool,
+    },
+    // Its id is only handed out once the batch is saved
+    BatchCreated {
+        sku: String,
+        reference: Option<String>,
+        qty: u32,
+    },
+    // Lines waiting for stock may fit once a batch has grown
+    BatchQuantityIncreased {
+        sku: String,
+        batch_id: BatchId,
+    },
+    // The line went past the batch's quantity into the product's
+    // over-allocation buffer, `buffer_used` is how much of it is now taken
+    OverAllocated {
+        order_ref: String,
+        sku: String,
+        batch_id: BatchId,
+        buffer_used: u32,
+    },
+    // A reservation was never confirmed and gave its stock back
+    ReservationReleased {
+        reservation_id: u32,
+        order_ref: String,
+        sku: String,
+        qty: u32,
+    },
+    // Raised once per cancel, whether or not the order held any lines
+    OrderCancelled {
+        order_ref: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventKind {
+    OutOfStock,
+    Allocated,
+    Deallocated,
+    BatchCreated,
+    BatchQuantityIncreased,
+    OverAllocated,
+    ReservationReleased,
+    OrderCancelled,
+}
+
+impl DomainEvent {
+    pub fn kind(&self) -> EventKind {
+        match self {
+            DomainEvent::OutOfStock { .. } => EventKind::OutOfStock,
+            DomainEvent::Allocated { .. } => EventKind::Allocated,
+            DomainEvent::Deallocated { .. } => EventKind::Deallocated,
+            DomainEvent::BatchCreated { .. } => EventKind::BatchCreated,
+            DomainEvent::BatchQuantityIncreased { .. } => {
+                EventKind::BatchQuantityIncreased
+            }
+            DomainEvent::OverAllocated { .. } => EventKind::OverAllocated,
+            DomainEvent::ReservationReleased { .. } => {
+                EventKind::ReservationReleased
+            }
+            DomainEvent::OrderCancelled { .. } => EventKind::OrderCancelled,
+        }
+    }
+}
+
+// Sends events somewhere outside the aggregate, the outbox relay delivers
+// through one of these along with the tenant that raised them
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish(
+        &self,
+        tenant: &TenantId,
+        event: &DomainEvent,
+    ) -> anyhow::Result<()>;
+}
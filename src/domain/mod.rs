@@ -1,2 +1,6 @@
+pub mod clock;
+pub mod commands;
+pub mod events;
 pub mod model;
 pub mod repository;
+pub mod tenant;
@@ -1,186 +1,3694 @@
 #![allow(dead_code)]
-use chrono::{DateTime, Local};
+use crate::domain::clock::{Clock, SystemClock};
+use crate::domain::events::DomainEvent;
+use chrono::{DateTime, Duration, Local};
+use indexmap::IndexSet;
+use serde::{Deserialize, Serialize};
+use std::cmp::{Ordering, Reverse};
+use std::collections::HashSet;
+use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Batch<'a> {
-    pub id: Option<u32>,
-    pub sku: String,
+pub enum AllocationError {
+    SkuMismatch {
+        expected: String,
+        got: String,
+    },
+    AlreadyAllocated,
+    InsufficientQuantity {
+        available: u32,
+        requested: u32,
+    },
+    NotAllocated,
+    NoBatchAvailable,
+    MissingBatchId,
+    UnknownBatch {
+        batch_id: BatchId,
+    },
+    OverConsumed {
+        remaining: u32,
+        requested: u32,
+    },
+    UnknownProduct {
+        sku: String,
+    },
+    UnknownReservation {
+        reservation_id: ReservationId,
+    },
+    SnapshotAhead {
+        event_count: usize,
+        stream_len: usize,
+    },
+    Invalid(DomainError),
+}
+
+impl fmt::Display for AllocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AllocationError::SkuMismatch { expected, got } => {
+                write!(
+                    f,
+                    "SKU do not match: expected {}, got {}",
+                    expected, got
+                )
+            }
+            AllocationError::AlreadyAllocated => {
+                write!(f, "Order line already allocated in this batch")
+            }
+            AllocationError::InsufficientQuantity {
+                available,
+                requested,
+            } => write!(
+                f,
+                "Not enough quantity in batch to allocate order line: \
+                 available {}, requested {}",
+                available, requested
+            ),
+            AllocationError::NotAllocated => {
+                write!(f, "Cannot deallocate unallocated order")
+            }
+            AllocationError::NoBatchAvailable => {
+                write!(f, "Cannot allocate order line to any batch")
+            }
+            AllocationError::MissingBatchId => {
+                write!(f, "Cannot allocate order line to a batch without id")
+            }
+            AllocationError::UnknownBatch { batch_id } => {
+                write!(f, "Unknown batch id {}", batch_id)
+            }
+            AllocationError::OverConsumed {
+                remaining,
+                requested,
+            } => write!(
+                f,
+                "Cannot consume more of the order line than remains: \
+                 remaining {}, requested {}",
+                remaining, requested
+            ),
+            AllocationError::UnknownProduct { sku } => {
+                write!(f, "No product with SKU {}", sku)
+            }
+            AllocationError::UnknownReservation { reservation_id } => {
+                write!(f, "Unknown reservation id {}", reservation_id)
+            }
+            AllocationError::SnapshotAhead {
+                event_count,
+                stream_len,
+            } => write!(
+                f,
+                "Snapshot covers {} events but the stream only has {}",
+                event_count, stream_len
+            ),
+            AllocationError::Invalid(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for AllocationError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DomainError {
+    ZeroQuantity,
+    EmptySku,
+}
+
+impl fmt::Display for DomainError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DomainError::ZeroQuantity => {
+                write!(f, "Quantity must be greater than zero")
+            }
+            DomainError::EmptySku => write!(f, "SKU must not be empty"),
+        }
+    }
+}
+
+impl std::error::Error for DomainError {}
+
+impl From<DomainError> for AllocationError {
+    fn from(err: DomainError) -> Self {
+        AllocationError::Invalid(err)
+    }
+}
+
+fn validate_qty(qty: u32) -> Result<(), DomainError> {
+    if qty == 0 {
+        return Err(DomainError::ZeroQuantity);
+    }
+    Ok(())
+}
+
+// Trimmed and uppercased so the same product is recognized whatever the
+// source, e.g. "small-table" and "SMALL-TABLE "
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sku(String);
+
+impl Sku {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl TryFrom<String> for Sku {
+    type Error = DomainError;
+
+    fn try_from(sku: String) -> Result<Self, Self::Error> {
+        let sku = sku.trim();
+        if sku.is_empty() {
+            return Err(DomainError::EmptySku);
+        }
+        Ok(Sku(sku.to_uppercase()))
+    }
+}
+
+impl TryFrom<&str> for Sku {
+    type Error = DomainError;
+
+    fn try_from(sku: &str) -> Result<Self, Self::Error> {
+        Sku::try_from(sku.to_string())
+    }
+}
+
+impl From<Sku> for String {
+    fn from(sku: Sku) -> Self {
+        sku.0
+    }
+}
+
+impl AsRef<str> for Sku {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for Sku {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl PartialEq<str> for Sku {
+    fn eq(&self, other: &str) -> bool {
+        self.0 == other
+    }
+}
+
+impl PartialEq<&str> for Sku {
+    fn eq(&self, other: &&str) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<String> for Sku {
+    fn eq(&self, other: &String) -> bool {
+        &self.0 == other
+    }
+}
+
+// Ids of stored rows, distinct types so one can't be passed for the other.
+// Converting from or to a bare `u32` has to be spelled out.
+///
+/// ```compile_fail
+/// use cosmic::domain::model::{BatchId, OrderLineId};
+/// let line_id: OrderLineId = BatchId(1);
+/// ```
+///
+/// ```compile_fail
+/// use cosmic::domain::model::BatchId;
+/// let batch_id: BatchId = 1;
+/// ```
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct BatchId(pub u32);
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct OrderLineId(pub u32);
+
+impl From<u32> for BatchId {
+    fn from(id: u32) -> Self {
+        BatchId(id)
+    }
+}
+
+impl From<BatchId> for u32 {
+    fn from(id: BatchId) -> Self {
+        id.0
+    }
+}
+
+impl From<u32> for OrderLineId {
+    fn from(id: u32) -> Self {
+        OrderLineId(id)
+    }
+}
+
+impl From<OrderLineId> for u32 {
+    fn from(id: OrderLineId) -> Self {
+        id.0
+    }
+}
+
+impl fmt::Display for BatchId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl fmt::Display for OrderLineId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Numbered per product, counting up from 1 and never reused
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+)]
+#[serde(transparent)]
+pub struct ReservationId(pub u32);
+
+impl fmt::Display for ReservationId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// Quantity held for a line until it is confirmed into an allocation or
+// released
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Reservation {
+    pub id: ReservationId,
+    pub line: OrderLine,
+    pub reserved_at: DateTime<Local>,
+}
+
+// Serialized with the eta as an RFC3339 string and missing ids as `null`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Batch {
+    pub id: Option<BatchId>,
+    // External reference supplied when registering the batch
+    pub reference: Option<String>,
+    pub sku: Sku,
     pub qty: u32,
-    pub eta: DateTime<Local>,
-    pub allocated: Vec<&'a OrderLine>,
+    // `None` means the batch is already in stock
+    pub eta: Option<DateTime<Local>>,
+    // Set once the batch is soft-deleted
+    #[serde(default)]
+    pub deleted_at: Option<DateTime<Local>>,
+    // Perishable stock can't be allocated from this instant on
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Local>>,
+    // In the order the lines were allocated
+    pub allocated: IndexSet<OrderLine>,
+    // Held but not confirmed yet, counts against the available quantity
+    #[serde(default)]
+    pub reserved: Vec<Reservation>,
+    // How far past `qty` the batch may be allocated, set by its product
+    #[serde(default)]
+    pub over_allocation_buffer: u32,
+    // Where the batch is stocked, `None` for stock not tied to a warehouse
+    #[serde(default)]
+    pub warehouse_id: Option<String>,
 }
 
-impl<'a> Batch<'a> {
-    pub fn new(sku: String, qty: u32) -> Batch<'a> {
-        Batch {
-            id: None,
-            sku,
-            qty,
-            eta: Local::now(),
-            allocated: Vec::new(),
+impl Batch {
+    pub fn new(
+        sku: String,
+        qty: u32,
+        eta: Option<DateTime<Local>>,
+    ) -> Result<Batch, DomainError> {
+        let builder = Batch::builder().sku(sku).qty(qty);
+        match eta {
+            Some(eta) => builder.eta(eta),
+            None => builder.in_stock(),
+        }
+        .build()
+    }
+
+    pub fn builder() -> BatchBuilder {
+        BatchBuilder::default()
+    }
+
+    // A shipment expected `lead_time` from the clock's current time
+    pub fn arriving_in(
+        sku: String,
+        qty: u32,
+        lead_time: Duration,
+        clock: &dyn Clock,
+    ) -> Result<Batch, DomainError> {
+        Batch::new(sku, qty, Some(clock.now() + lead_time))
+    }
+
+    pub fn is_expired(&self, now: DateTime<Local>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    // Includes the over-allocation buffer. Never underflows, an
+    // over-allocated batch has nothing available.
+    pub fn available_qty(&self) -> u32 {
+        (self.qty + self.over_allocation_buffer)
+            .saturating_sub(self.total_allocated_qty() + self.reserved_qty())
+    }
+
+    // How much of the over-allocation buffer is taken up, 0 while the batch
+    // holds no more than its quantity
+    pub fn buffer_used(&self) -> u32 {
+        (self.total_allocated_qty() + self.reserved_qty())
+            .saturating_sub(self.qty)
+    }
+
+    // Panics in debug builds if the batch holds more than its quantity and
+    // buffer
+    pub fn assert_invariants(&self) {
+        debug_assert!(
+            self.total_allocated_qty() + self.reserved_qty()
+                <= self.qty + self.over_allocation_buffer,
+            "batch {:?} over-allocated: {} allocated, {} reserved, \
+             quantity {}, buffer {}",
+            self.reference,
+            self.total_allocated_qty(),
+            self.reserved_qty(),
+            self.qty,
+            self.over_allocation_buffer
+        );
+    }
+
+    pub fn reserved_qty(&self) -> u32 {
+        self.reserved
+            .iter()
+            .map(|reservation| reservation.line.qty)
+            .sum()
+    }
+
+    pub fn total_allocated_qty(&self) -> u32 {
+        self.allocated.iter().map(|line| line.qty).sum()
+    }
+
+    // Share of the batch already allocated, from 0.0 (empty) to 1.0 (full).
+    // Goes past 1.0 once allocations dip into the buffer.
+    pub fn utilization(&self) -> f64 {
+        if self.qty == 0 {
+            return 0.0;
         }
+        f64::from(self.total_allocated_qty()) / f64::from(self.qty)
     }
 
+    #[deprecated(
+        since = "0.1.0",
+        note = "misspelled, use `available_qty` instead"
+    )]
     pub fn avaialble_qty(&self) -> u32 {
-        let allocated_qty: u32 =
-            self.allocated.iter().map(|line| line.qty).sum();
-        self.qty - allocated_qty
+        self.available_qty()
+    }
+
+    pub fn allocated_order_refs(&self) -> HashSet<String> {
+        self.allocated
+            .iter()
+            .map(|line| line.order_ref.clone())
+            .collect()
+    }
+
+    pub fn can_allocate(&self, order_line: &OrderLine) -> bool {
+        self.check_allocation(order_line).is_ok()
     }
 
+    // Returns the quantity the batch has left for other lines
+    #[must_use = "the line may not have been allocated"]
     pub fn allocate(
         &mut self,
-        order_line: &'a OrderLine,
-    ) -> Result<(), &'static str> {
+        order_line: &OrderLine,
+    ) -> Result<u32, AllocationError> {
+        self.check_allocation(order_line)?;
+        self.allocated.insert(order_line.clone());
+        self.assert_invariants();
+        Ok(self.available_qty())
+    }
+
+    // Allocation rules shared by `can_allocate` and `allocate`
+    fn check_allocation(
+        &self,
+        order_line: &OrderLine,
+    ) -> Result<(), AllocationError> {
         if self.sku != order_line.sku {
-            return Err("SKU do not match");
+            return Err(AllocationError::SkuMismatch {
+                expected: self.sku.to_string(),
+                got: order_line.sku.to_string(),
+            });
         }
-        if self.allocated.iter().any(|&x| x == order_line) {
-            return Err("Order line already allocated in this batch");
+        if self.allocated.contains(order_line)
+            || self
+                .reserved
+                .iter()
+                .any(|reservation| reservation.line == *order_line)
+        {
+            return Err(AllocationError::AlreadyAllocated);
         }
-        if self.avaialble_qty() >= order_line.qty {
-            self.allocated.push(order_line);
-            Ok(())
-        } else {
-            Err("Not enough quantity in batch to allocate order line")
+        if self.available_qty() < order_line.qty {
+            return Err(AllocationError::InsufficientQuantity {
+                available: self.available_qty(),
+                requested: order_line.qty,
+            });
         }
+        Ok(())
     }
 
     pub fn deallocate(
         &mut self,
-        order_line: &'a OrderLine,
-    ) -> Result<(), &'static str> {
-        let position = self.allocated.iter().position(|&x| x == order_line);
-        match position {
-            Some(index) => {
-                self.allocated.remove(index);
-                Ok(())
-            }
-            None => Err("Cannot deallocate unallocated order"),
+        order_line: &OrderLine,
+    ) -> Result<(), AllocationError> {
+        self.deallocate_one(order_line)
+            .map(|_| ())
+            .ok_or(AllocationError::NotAllocated)
+    }
+
+    pub fn deallocate_one(
+        &mut self,
+        order_line: &OrderLine,
+    ) -> Option<OrderLine> {
+        self.allocated.shift_take(order_line)
+    }
+
+    // Sets the quantity, deallocating the most recently allocated lines until
+    // the rest fit. Returns the freed lines, latest first. Reservations are
+    // left alone, they are released when they expire.
+    pub fn adjust_quantity(
+        &mut self,
+        new_qty: u32,
+    ) -> Result<Vec<OrderLine>, DomainError> {
+        validate_qty(new_qty)?;
+        self.qty = new_qty;
+        let capacity = (new_qty + self.over_allocation_buffer)
+            .saturating_sub(self.reserved_qty());
+        let mut freed = Vec::new();
+        while self.total_allocated_qty() > capacity {
+            let Some(line) = self.allocated.pop() else {
+                break;
+            };
+            freed.push(line);
         }
+        Ok(freed)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+// Batches start out in stock and unallocated, `build` checks the quantity and
+// SKU the same way `Batch::new` does
+#[derive(Debug, Clone, Default)]
+pub struct BatchBuilder {
+    id: Option<BatchId>,
+    reference: Option<String>,
+    sku: String,
+    qty: u32,
+    eta: Option<DateTime<Local>>,
+    expires_at: Option<DateTime<Local>>,
+    warehouse_id: Option<String>,
+}
+
+impl BatchBuilder {
+    pub fn id(mut self, id: BatchId) -> Self {
+        self.id = Some(id);
+        self
+    }
+
+    pub fn reference(mut self, reference: impl Into<String>) -> Self {
+        self.reference = Some(reference.into());
+        self
+    }
+
+    pub fn sku(mut self, sku: impl Into<String>) -> Self {
+        self.sku = sku.into();
+        self
+    }
+
+    pub fn qty(mut self, qty: u32) -> Self {
+        self.qty = qty;
+        self
+    }
+
+    pub fn eta(mut self, eta: DateTime<Local>) -> Self {
+        self.eta = Some(eta);
+        self
+    }
+
+    pub fn in_stock(mut self) -> Self {
+        self.eta = None;
+        self
+    }
+
+    pub fn expires_at(mut self, expires_at: DateTime<Local>) -> Self {
+        self.expires_at = Some(expires_at);
+        self
+    }
+
+    pub fn warehouse_id(mut self, warehouse_id: impl Into<String>) -> Self {
+        self.warehouse_id = Some(warehouse_id.into());
+        self
+    }
+
+    pub fn build(self) -> Result<Batch, DomainError> {
+        validate_qty(self.qty)?;
+        Ok(Batch {
+            id: self.id,
+            reference: self.reference,
+            sku: Sku::try_from(self.sku)?,
+            qty: self.qty,
+            eta: self.eta,
+            deleted_at: None,
+            expires_at: self.expires_at,
+            allocated: IndexSet::new(),
+            reserved: Vec::new(),
+            over_allocation_buffer: 0,
+            warehouse_id: self.warehouse_id,
+        })
+    }
+}
+
+// Batches order by eta, in-stock ones (`None` eta) first, then by id. This
+// only looks at `(eta, id)`, so batches comparing `Equal` need not be `==`.
+impl Ord for Batch {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.eta, self.id).cmp(&(other.eta, other.id))
+    }
+}
+
+impl PartialOrd for Batch {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for Batch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.reference.as_deref().unwrap_or("unnamed batch");
+        write!(
+            f,
+            "{} {} {}/{}",
+            name,
+            self.sku,
+            self.available_qty(),
+            self.qty
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct OrderLine {
-    pub id: Option<u32>,
-    pub sku: String,
+    pub id: Option<OrderLineId>,
+    pub order_ref: String,
+    pub sku: Sku,
     pub qty: u32,
 }
 
 impl OrderLine {
-    pub fn new(sku: String, qty: u32) -> OrderLine {
-        OrderLine { id: None, sku, qty }
+    pub fn new(
+        order_ref: String,
+        sku: String,
+        qty: u32,
+    ) -> Result<OrderLine, DomainError> {
+        validate_qty(qty)?;
+        Ok(OrderLine {
+            id: None,
+            order_ref,
+            sku: Sku::try_from(sku)?,
+            qty,
+        })
     }
 }
 
-pub fn allocate<'a>(
-    order_line: &'a OrderLine,
-    batches: &mut Vec<&mut Batch<'a>>,
-) -> Result<(), &'static str> {
-    // Sort batches by eta
-    batches.sort_by(|a, b| a.eta.cmp(&b.eta));
+impl fmt::Display for OrderLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} x{}", self.order_ref, self.sku, self.qty)
+    }
+}
 
-    // Try to allocate the order line to each batch
-    if batches
-        .iter_mut()
-        .any(|batch| batch.allocate(order_line).is_ok())
-    {
-        Ok(()) // If allocation is successful, return Ok(())
-    } else {
-        // If none of the batches can accommodate the order line, return an error
-        Err("Cannot allocate order line to any batch")
+// Tracks how much of an order line is still unallocated while it is split
+// across batches
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AllocationPlan {
+    order_line: OrderLine,
+    remaining: u32,
+}
+
+impl AllocationPlan {
+    pub fn new(order_line: OrderLine) -> AllocationPlan {
+        let remaining = order_line.qty;
+        AllocationPlan {
+            order_line,
+            remaining,
+        }
+    }
+
+    pub fn remaining(&self) -> u32 {
+        self.remaining
+    }
+
+    // Takes `qty` off the line, returning the part to allocate
+    pub fn consume(&mut self, qty: u32) -> Result<OrderLine, AllocationError> {
+        validate_qty(qty)?;
+        if qty > self.remaining {
+            return Err(AllocationError::OverConsumed {
+                remaining: self.remaining,
+                requested: qty,
+            });
+        }
+        self.remaining -= qty;
+        Ok(OrderLine {
+            qty,
+            ..self.order_line.clone()
+        })
     }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationMode {
+    // Allocate whichever lines fit, skipping the rest
+    BestEffort,
+    // Allocate every line or none of them
+    AllOrNothing,
+}
 
-    #[test]
-    fn test_allocating_to_a_batch_reduces_the_available_quantity() {
-        let sku = "SMALL_TABLE".to_string();
+// Chooses which of the product's batches an order line goes to, returning
+// its index. Batches that can't take the line must never be chosen.
+pub trait AllocationStrategy {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize>;
+}
+
+fn candidates<'a>(
+    order_line: &'a OrderLine,
+    batches: &'a [Batch],
+) -> impl Iterator<Item = (usize, &'a Batch)> {
+    batches
+        .iter()
+        .enumerate()
+        .filter(|(_, batch)| batch.can_allocate(order_line))
+}
 
-        let mut batch = Batch::new(sku.clone(), 20);
-        let order = OrderLine::new(sku, 2);
+// In-stock batches (`None` eta) first, then the earliest shipment
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EarliestEta;
 
-        assert_eq!(batch.allocate(&order), Ok(()));
-        assert_eq!(batch.avaialble_qty(), 18);
+impl AllocationStrategy for EarliestEta {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize> {
+        candidates(order_line, batches)
+            .min_by_key(|(_, batch)| batch.eta)
+            .map(|(index, _)| index)
     }
+}
 
-    #[test]
-    fn test_allocating_to_a_batch_with_insufficient_quantity() {
-        let sku = "SMALL_TABLE".to_string();
+// The batch with the least quantity left that still fits the line, to
+// reduce fragmentation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SmallestFit;
+
+impl AllocationStrategy for SmallestFit {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize> {
+        candidates(order_line, batches)
+            .min_by_key(|(_, batch)| (batch.available_qty(), batch.eta))
+            .map(|(index, _)| index)
+    }
+}
 
-        let mut batch = Batch::new(sku.clone(), 1);
-        let order = OrderLine::new(sku, 2);
+// The batch with the most quantity left, to consolidate shipments
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LargestFirst;
 
-        assert_eq!(
-            batch.allocate(&order),
-            Err("Not enough quantity in batch to allocate order line")
-        );
-        assert_eq!(batch.avaialble_qty(), 1);
+impl AllocationStrategy for LargestFirst {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize> {
+        candidates(order_line, batches)
+            .min_by_key(|(_, batch)| {
+                (Reverse(batch.available_qty()), batch.eta)
+            })
+            .map(|(index, _)| index)
     }
+}
 
-    #[test]
-    fn test_allocating_to_a_batch_with_different_sku() {
-        let batch_sku = "SMALL_TABLE".to_string();
-        let order_sku = "BIG_TABLE".to_string();
+// Batches in the warehouse listed first, e.g. the one nearest the customer,
+// then the next one and so on, with the earliest eta winning within a
+// warehouse. Batches in warehouses that aren't listed come last.
+#[derive(Debug, Clone, Default)]
+pub struct WarehousePriority {
+    pub warehouses: Vec<String>,
+}
 
-        let mut batch = Batch::new(batch_sku, 10);
-        let order = OrderLine::new(order_sku, 2);
+impl WarehousePriority {
+    fn rank(&self, batch: &Batch) -> usize {
+        batch
+            .warehouse_id
+            .as_ref()
+            .and_then(|warehouse_id| {
+                self.warehouses
+                    .iter()
+                    .position(|warehouse| warehouse == warehouse_id)
+            })
+            .unwrap_or(self.warehouses.len())
+    }
+}
 
-        assert_eq!(batch.allocate(&order), Err("SKU do not match"));
-        assert_eq!(batch.avaialble_qty(), 10);
+impl AllocationStrategy for WarehousePriority {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize> {
+        candidates(order_line, batches)
+            .min_by_key(|(_, batch)| (self.rank(batch), batch.eta))
+            .map(|(index, _)| index)
     }
+}
 
-    #[test]
-    fn test_can_only_deallocate_allocated_lines() {
-        let sku = "SMALL_TABLE".to_string();
+// The batch with id `batch_id` if it can take the line, otherwise whatever
+// `fallback` picks. An id the product doesn't have falls back too.
+pub struct PreferredBatch<'a> {
+    pub batch_id: BatchId,
+    pub fallback: &'a dyn AllocationStrategy,
+}
 
-        let mut batch = Batch::new(sku.clone(), 10);
-        let order1 = OrderLine::new(sku.clone(), 3);
-        let order2 = OrderLine::new(sku.clone(), 2);
+impl AllocationStrategy for PreferredBatch<'_> {
+    fn choose(
+        &self,
+        order_line: &OrderLine,
+        batches: &[Batch],
+    ) -> Option<usize> {
+        candidates(order_line, batches)
+            .find(|(_, batch)| batch.id == Some(self.batch_id))
+            .map(|(index, _)| index)
+            .or_else(|| self.fallback.choose(order_line, batches))
+    }
+}
 
-        assert_eq!(batch.allocate(&order1), Ok(()));
-        assert_eq!(batch.deallocate(&order1), Ok(()));
-        assert_eq!(batch.avaialble_qty(), 10);
+// Take a snapshot every this many events so loading a product only replays
+// the ones since
+pub const SNAPSHOT_INTERVAL: usize = 100;
 
-        assert_eq!(
-            batch.deallocate(&order2),
-            Err("Cannot deallocate unallocated order")
-        );
-        assert_eq!(batch.avaialble_qty(), 10)
+// A product as it was after the first `event_count` events of its stream
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProductSnapshot {
+    pub sku: String,
+    pub batches: Vec<Batch>,
+    pub version_number: i32,
+    pub event_count: usize,
+    #[serde(default)]
+    pub over_allocation_buffer: u32,
+}
+
+// Stock of one batch as the admin views show it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BatchSummary {
+    pub batch_id: Option<BatchId>,
+    pub eta: Option<DateTime<Local>>,
+    pub total_qty: u32,
+    pub allocated_qty: u32,
+    pub available_qty: u32,
+    // Sorted so the summary reads the same every time
+    pub order_refs: Vec<String>,
+}
+
+// A line that went into, or came out of, the batch `batch_id`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationChange {
+    pub batch_id: Option<BatchId>,
+    pub line: OrderLine,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct QuantityChange {
+    pub batch_id: Option<BatchId>,
+    pub from: u32,
+    pub to: u32,
+}
+
+// What changed between two states of a product, see `Product::diff`. A line
+// moved to another batch shows up as both freed and allocated.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ProductDiff {
+    pub allocated: Vec<AllocationChange>,
+    pub freed: Vec<AllocationChange>,
+    pub quantity_changes: Vec<QuantityChange>,
+}
+
+impl ProductDiff {
+    pub fn is_empty(&self) -> bool {
+        self.allocated.is_empty()
+            && self.freed.is_empty()
+            && self.quantity_changes.is_empty()
     }
+}
 
-    #[test]
-    fn test_allocation_is_idempotent() {
-        let sku = "SMALL_TABLE".to_string();
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Product {
+    pub sku: Sku,
+    pub batches: Vec<Batch>,
+    pub version_number: i32,
+    // Version stored when the product was loaded, `None` if it never was.
+    // Saving checks it so a stale copy can't overwrite a newer one.
+    pub persisted_version: Option<i32>,
+    // Highest reservation id handed out so far
+    pub last_reservation_id: u32,
+    // Extra quantity each batch may be allocated past its own, 0 unless set
+    // with `set_over_allocation_buffer`
+    pub over_allocation_buffer: u32,
+    pub events: Vec<DomainEvent>,
+}
 
-        let mut batch = Batch::new(sku.clone(), 20);
-        let order = OrderLine::new(sku, 2);
+impl Product {
+    pub fn new(
+        sku: String,
+        batches: Vec<Batch>,
+    ) -> Result<Product, AllocationError> {
+        let sku = Sku::try_from(sku)?;
+        if let Some(batch) = batches.iter().find(|batch| batch.sku != sku) {
+            return Err(AllocationError::SkuMismatch {
+                expected: sku.to_string(),
+                got: batch.sku.to_string(),
+            });
+        }
+        let last_reservation_id = batches
+            .iter()
+            .flat_map(|batch| &batch.reserved)
+            .map(|reservation| reservation.id.0)
+            .max()
+            .unwrap_or(0);
+        Ok(Product {
+            sku,
+            batches,
+            version_number: 0,
+            persisted_version: None,
+            last_reservation_id,
+            over_allocation_buffer: 0,
+            events: Vec::new(),
+        })
+    }
 
-        assert_eq!(batch.allocate(&order), Ok(()));
-        assert_eq!(
-            batch.allocate(&order),
-            Err("Order line already allocated in this batch")
-        );
-        assert_eq!(batch.avaialble_qty(), 18);
+    // Lets every batch, including ones added later, take lines totalling up
+    // to its quantity plus `buffer`. Lines already allocated are kept when
+    // the buffer shrinks.
+    pub fn set_over_allocation_buffer(&mut self, buffer: u32) {
+        self.over_allocation_buffer = buffer;
+        for batch in &mut self.batches {
+            batch.over_allocation_buffer = buffer;
+        }
     }
 
-    #[test]
-    fn test_prefers_earlier_batches() {
-        let sku = "SMALL_TABLE".to_string();
+    // Rebuilds a product by replaying `Allocated` and `Deallocated` events on
+    // top of its known batches. Every replayed event bumps the version, as
+    // the allocation that recorded it did.
+    pub fn from_events(
+        sku: String,
+        batches: Vec<Batch>,
+        events: &[DomainEvent],
+    ) -> Result<Product, AllocationError> {
+        let mut product = Product::new(sku, batches)?;
+        product.replay(events)?;
+        Ok(product)
+    }
+
+    // Same as `from_events` for the whole stream, with the events the
+    // snapshot already covers skipped. A stream shorter than the snapshot
+    // isn't the one it was taken from.
+    pub fn from_snapshot(
+        snapshot: ProductSnapshot,
+        events: &[DomainEvent],
+    ) -> Result<Product, AllocationError> {
+        let tail = events.get(snapshot.event_count..).ok_or(
+            AllocationError::SnapshotAhead {
+                event_count: snapshot.event_count,
+                stream_len: events.len(),
+            },
+        )?;
+        let mut product = Product::new(snapshot.sku, snapshot.batches)?;
+        product.set_over_allocation_buffer(snapshot.over_allocation_buffer);
+        product.version_number = snapshot.version_number;
+        product.replay(tail)?;
+        Ok(product)
+    }
+
+    // `event_count` is how many events of the product's stream led to its
+    // current state
+    pub fn snapshot(&self, event_count: usize) -> ProductSnapshot {
+        ProductSnapshot {
+            sku: self.sku.to_string(),
+            batches: self.batches.clone(),
+            version_number: self.version_number,
+            event_count,
+            over_allocation_buffer: self.over_allocation_buffer,
+        }
+    }
 
-        let mut stock_batch = Batch::new(sku.clone(), 20);
-        let mut ship_batch = Batch::new(sku.clone(), 20);
-        let mut batches = Vec::new();
-        batches.push(&mut ship_batch);
-        batches.push(&mut stock_batch);
+    // One entry per batch, in the product's batch order
+    pub fn summary(&self) -> Vec<BatchSummary> {
+        self.batches
+            .iter()
+            .map(|batch| {
+                let mut order_refs: Vec<String> =
+                    batch.allocated_order_refs().into_iter().collect();
+                order_refs.sort();
+                BatchSummary {
+                    batch_id: batch.id,
+                    eta: batch.eta,
+                    total_qty: batch.qty,
+                    allocated_qty: batch.total_allocated_qty(),
+                    available_qty: batch.available_qty(),
+                    order_refs,
+                }
+            })
+            .collect()
+    }
+
+    // Changes that turn `self` into `other`, a later state of the same
+    // product. Batches are matched by id, so the lines of a batch only one
+    // side has count as allocated or freed. Entries are sorted by batch id,
+    // then order ref.
+    pub fn diff(&self, other: &Product) -> ProductDiff {
+        let mut diff = ProductDiff::default();
+        let changes = |from: &Batch, to: Option<&Batch>| {
+            from.allocated
+                .iter()
+                .filter(|line| {
+                    !to.is_some_and(|to| to.allocated.contains(*line))
+                })
+                .map(|line| AllocationChange {
+                    batch_id: from.id,
+                    line: line.clone(),
+                })
+                .collect::<Vec<_>>()
+        };
+        for batch in &self.batches {
+            let later = other.batches.iter().find(|later| later.id == batch.id);
+            diff.freed.extend(changes(batch, later));
+            if let Some(later) = later.filter(|later| later.qty != batch.qty) {
+                diff.quantity_changes.push(QuantityChange {
+                    batch_id: batch.id,
+                    from: batch.qty,
+                    to: later.qty,
+                });
+            }
+        }
+        for batch in &other.batches {
+            let earlier =
+                self.batches.iter().find(|earlier| earlier.id == batch.id);
+            diff.allocated.extend(changes(batch, earlier));
+        }
 
-        let order = OrderLine::new(sku, 10);
+        for changes in [&mut diff.allocated, &mut diff.freed] {
+            changes.sort_by(|a, b| {
+                (a.batch_id, &a.line.order_ref)
+                    .cmp(&(b.batch_id, &b.line.order_ref))
+            });
+        }
+        diff.quantity_changes.sort_by_key(|change| change.batch_id);
+        diff
+    }
 
-        assert_eq!(allocate(&order, &mut batches), Ok(()));
-        assert_eq!(stock_batch.avaialble_qty(), 10);
-        assert_eq!(ship_batch.avaialble_qty(), 20);
+    fn replay(
+        &mut self,
+        events: &[DomainEvent],
+    ) -> Result<(), AllocationError> {
+        for event in events {
+            match event {
+                DomainEvent::Allocated {
+                    order_ref,
+                    sku,
+                    qty,
+                    batch_id,
+                } => {
+                    let batch = self
+                        .batches
+                        .iter_mut()
+                        .find(|batch| batch.id == Some(*batch_id))
+                        .ok_or(AllocationError::UnknownBatch {
+                            batch_id: *batch_id,
+                        })?;
+                    batch.allocated.insert(OrderLine {
+                        id: None,
+                        order_ref: order_ref.clone(),
+                        sku: Sku::try_from(sku.as_str())?,
+                        qty: *qty,
+                    });
+                    self.version_number += 1;
+                }
+                DomainEvent::Deallocated {
+                    order_ref,
+                    sku,
+                    qty,
+                    ..
+                } => {
+                    let line = OrderLine {
+                        id: None,
+                        order_ref: order_ref.clone(),
+                        sku: Sku::try_from(sku.as_str())?,
+                        qty: *qty,
+                    };
+                    self.deallocate(&line)?;
+                }
+                DomainEvent::OutOfStock { .. }
+                | DomainEvent::BatchCreated { .. }
+                | DomainEvent::BatchQuantityIncreased { .. }
+                | DomainEvent::OverAllocated { .. }
+                | DomainEvent::ReservationReleased { .. }
+                | DomainEvent::OrderCancelled { .. } => {}
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_batch(
+        &mut self,
+        mut batch: Batch,
+    ) -> Result<(), AllocationError> {
+        if self.sku != batch.sku {
+            return Err(AllocationError::SkuMismatch {
+                expected: self.sku.to_string(),
+                got: batch.sku.to_string(),
+            });
+        }
+        batch.over_allocation_buffer = self.over_allocation_buffer;
+        self.events.push(DomainEvent::BatchCreated {
+            sku: self.sku.to_string(),
+            reference: batch.reference.clone(),
+            qty: batch.qty,
+        });
+        self.batches.push(batch);
+        self.version_number += 1;
+        Ok(())
+    }
+
+    // Returns the id of the batch the line went to, the preferred one when
+    // it can take the line
+    #[must_use = "the line may not have been allocated"]
+    pub fn allocate(
+        &mut self,
+        order_line: &OrderLine,
+        preferred_batch_id: Option<BatchId>,
+    ) -> Result<BatchId, AllocationError> {
+        self.allocate_at(order_line, preferred_batch_id, &SystemClock)
+    }
+
+    // Same as `allocate`, with batches expired by the clock's current time
+    // left out
+    #[must_use = "the line may not have been allocated"]
+    pub fn allocate_at(
+        &mut self,
+        order_line: &OrderLine,
+        preferred_batch_id: Option<BatchId>,
+        clock: &dyn Clock,
+    ) -> Result<BatchId, AllocationError> {
+        match preferred_batch_id {
+            Some(batch_id) => self.allocate_with(
+                order_line,
+                &PreferredBatch {
+                    batch_id,
+                    fallback: &EarliestEta,
+                },
+                clock,
+            ),
+            None => self.allocate_with(order_line, &EarliestEta, clock),
+        }
+    }
+
+    // Same as `allocate`, trying the warehouses in the given order
+    #[must_use = "the line may not have been allocated"]
+    pub fn allocate_by_warehouse(
+        &mut self,
+        order_line: &OrderLine,
+        warehouses: &[String],
+    ) -> Result<BatchId, AllocationError> {
+        let strategy = WarehousePriority {
+            warehouses: warehouses.to_vec(),
+        };
+        self.allocate_with(order_line, &strategy, &SystemClock)
+    }
+
+    #[must_use = "the line may not have been allocated"]
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            sku = %order_line.sku,
+            order_ref = %order_line.order_ref,
+            qty = order_line.qty,
+        )
+    )]
+    pub fn allocate_with(
+        &mut self,
+        order_line: &OrderLine,
+        strategy: &dyn AllocationStrategy,
+        clock: &dyn Clock,
+    ) -> Result<BatchId, AllocationError> {
+        if self.sku != order_line.sku {
+            return Err(AllocationError::SkuMismatch {
+                expected: self.sku.to_string(),
+                got: order_line.sku.to_string(),
+            });
+        }
+
+        // An order's line is only ever allocated once, a repeat gets the
+        // batch it already went to and changes nothing
+        if let Some(batch_id) =
+            self.allocated_batch(&order_line.order_ref, order_line.sku.as_str())
+        {
+            tracing::info!(batch_id = batch_id.0, "already allocated");
+            return Ok(batch_id);
+        }
+
+        let Some(index) = self.choose_unexpired(order_line, strategy, clock)
+        else {
+            tracing::warn!("out of stock");
+            self.events.push(DomainEvent::OutOfStock {
+                order_ref: order_line.order_ref.clone(),
+                sku: order_line.sku.to_string(),
+                qty: order_line.qty,
+            });
+            return Err(AllocationError::NoBatchAvailable);
+        };
+        let batch = &mut self.batches[index];
+        let batch_id = batch.id.ok_or(AllocationError::MissingBatchId)?;
+
+        batch.allocate(order_line)?;
+        self.version_number += 1;
+        self.events.push(DomainEvent::Allocated {
+            order_ref: order_line.order_ref.clone(),
+            sku: order_line.sku.to_string(),
+            qty: order_line.qty,
+            batch_id,
+        });
+        tracing::info!(batch_id = batch_id.0, "allocated");
+        self.flag_over_allocation(index, batch_id, order_line);
+        Ok(batch_id)
+    }
+
+    // The batch the order's line of `sku` went to, the first one when it was
+    // split. `(order_ref, sku)` identifies a line, whatever its quantity.
+    pub fn allocated_batch(
+        &self,
+        order_ref: &str,
+        sku: &str,
+    ) -> Option<BatchId> {
+        self.allocated_parts(order_ref, sku)
+            .first()
+            .map(|(batch_id, _)| *batch_id)
+    }
+
+    // `(batch_id, qty)` of every batch holding part of the order's line, in
+    // batch order
+    fn allocated_parts(
+        &self,
+        order_ref: &str,
+        sku: &str,
+    ) -> Vec<(BatchId, u32)> {
+        self.batches
+            .iter()
+            .filter_map(|batch| {
+                let qty: u32 = batch
+                    .allocated
+                    .iter()
+                    .filter(|line| {
+                        line.order_ref == order_ref && line.sku == sku
+                    })
+                    .map(|line| line.qty)
+                    .sum();
+                Some((batch.id?, qty)).filter(|_| qty > 0)
+            })
+            .collect()
+    }
+
+    // Reports a line that only fit thanks to the over-allocation buffer
+    fn flag_over_allocation(
+        &mut self,
+        index: usize,
+        batch_id: BatchId,
+        order_line: &OrderLine,
+    ) {
+        let buffer_used = self.batches[index].buffer_used();
+        if buffer_used == 0 {
+            return;
+        }
+        tracing::warn!(buffer_used, "allocated into the buffer");
+        self.events.push(DomainEvent::OverAllocated {
+            order_ref: order_line.order_ref.clone(),
+            sku: order_line.sku.to_string(),
+            batch_id,
+            buffer_used,
+        });
+    }
+
+    // Strategies only get to see the batches that haven't expired, the index
+    // returned is into all of them
+    fn choose_unexpired(
+        &self,
+        order_line: &OrderLine,
+        strategy: &dyn AllocationStrategy,
+        clock: &dyn Clock,
+    ) -> Option<usize> {
+        let now = clock.now();
+        let live: Vec<usize> = (0..self.batches.len())
+            .filter(|&index| !self.batches[index].is_expired(now))
+            .collect();
+        if live.len() == self.batches.len() {
+            return strategy.choose(order_line, &self.batches);
+        }
+        let batches: Vec<Batch> = live
+            .iter()
+            .map(|&index| self.batches[index].clone())
+            .collect();
+        strategy
+            .choose(order_line, &batches)
+            .map(|index| live[index])
+    }
+
+    // The batch `allocate` would pick for the line right now, without
+    // allocating it
+    pub fn preview_allocation(
+        &self,
+        order_line: &OrderLine,
+    ) -> Option<BatchId> {
+        if self.sku != order_line.sku {
+            return None;
+        }
+        let index =
+            self.choose_unexpired(order_line, &EarliestEta, &SystemClock)?;
+        self.batches[index].id
+    }
+
+    // Splits one order line across as many batches as it takes, in eta order.
+    // Returns `(batch_id, qty)` for every part, nothing is allocated unless
+    // the whole line fits. A line that was already allocated gets its parts
+    // back unchanged.
+    #[must_use = "the line may not have been allocated"]
+    pub fn allocate_split(
+        &mut self,
+        order_line: &OrderLine,
+    ) -> Result<Vec<(BatchId, u32)>, AllocationError> {
+        if self.sku != order_line.sku {
+            return Err(AllocationError::SkuMismatch {
+                expected: self.sku.to_string(),
+                got: order_line.sku.to_string(),
+            });
+        }
+        let parts = self
+            .allocated_parts(&order_line.order_ref, order_line.sku.as_str());
+        if !parts.is_empty() {
+            return Ok(parts);
+        }
+
+        let now = SystemClock.now();
+        let mut indices: Vec<usize> = (0..self.batches.len())
+            .filter(|&index| {
+                let batch = &self.batches[index];
+                batch.available_qty() > 0 && !batch.is_expired(now)
+            })
+            .collect();
+        indices.sort_by_key(|&index| self.batches[index].eta);
+
+        let mut plan = AllocationPlan::new(order_line.clone());
+        let mut parts = Vec::new();
+        for index in indices {
+            if plan.remaining() == 0 {
+                break;
+            }
+            let batch = &self.batches[index];
+            let batch_id = batch.id.ok_or(AllocationError::MissingBatchId)?;
+            let part =
+                plan.consume(plan.remaining().min(batch.available_qty()))?;
+            parts.push((index, batch_id, part));
+        }
+        if plan.remaining() > 0 {
+            self.events.push(DomainEvent::OutOfStock {
+                order_ref: order_line.order_ref.clone(),
+                sku: order_line.sku.to_string(),
+                qty: order_line.qty,
+            });
+            return Err(AllocationError::InsufficientQuantity {
+                available: order_line.qty - plan.remaining(),
+                requested: order_line.qty,
+            });
+        }
+
+        for (index, batch_id, part) in &parts {
+            self.batches[*index].allocate(part)?;
+            self.events.push(DomainEvent::Allocated {
+                order_ref: part.order_ref.clone(),
+                sku: part.sku.to_string(),
+                qty: part.qty,
+                batch_id: *batch_id,
+            });
+            self.flag_over_allocation(*index, *batch_id, part);
+        }
+        self.version_number += 1;
+        Ok(parts
+            .into_iter()
+            .map(|(_, batch_id, part)| (batch_id, part.qty))
+            .collect())
+    }
+
+    pub fn allocate_order(
+        &mut self,
+        lines: &[OrderLine],
+        mode: AllocationMode,
+    ) -> Result<Vec<(OrderLine, BatchId)>, AllocationError> {
+        let batches = self.batches.clone();
+        let version_number = self.version_number;
+        let events_len = self.events.len();
+
+        let mut allocated = Vec::new();
+        for line in lines {
+            match self.allocate(line, None) {
+                Ok(batch_id) => allocated.push((line.clone(), batch_id)),
+                Err(_) if mode == AllocationMode::BestEffort => {}
+                Err(err) => {
+                    // Roll back, keeping only the out of stock reports
+                    self.batches = batches;
+                    self.version_number = version_number;
+                    let events = self.events.split_off(events_len);
+                    self.events.extend(events.into_iter().filter(|event| {
+                        matches!(event, DomainEvent::OutOfStock { .. })
+                    }));
+                    return Err(err);
+                }
+            }
+        }
+        Ok(allocated)
+    }
+
+    pub fn deallocate(
+        &mut self,
+        order_line: &OrderLine,
+    ) -> Result<(), AllocationError> {
+        let batch = self
+            .batches
+            .iter_mut()
+            .find(|batch| batch.allocated.contains(order_line))
+            .ok_or(AllocationError::NotAllocated)?;
+
+        batch.deallocate(order_line)?;
+        self.version_number += 1;
+        Ok(())
+    }
+
+    // Changes the quantity of a batch, deallocating its latest lines until
+    // the remaining allocations and reservations fit. Returns the freed lines.
+    // Reservations aren't given up, so the batch can't shrink below them.
+    // Growing a batch is reported so lines waiting for stock can be retried.
+    pub fn change_batch_quantity(
+        &mut self,
+        batch_id: BatchId,
+        qty: u32,
+    ) -> Result<Vec<OrderLine>, AllocationError> {
+        validate_qty(qty)?;
+        let batch = self
+            .batches
+            .iter_mut()
+            .find(|batch| batch.id == Some(batch_id))
+            .ok_or(AllocationError::UnknownBatch { batch_id })?;
+        let reserved_qty = batch.reserved_qty();
+        if reserved_qty > qty + batch.over_allocation_buffer {
+            return Err(AllocationError::InsufficientQuantity {
+                available: qty + batch.over_allocation_buffer,
+                requested: reserved_qty,
+            });
+        }
+        if qty > batch.qty {
+            self.events.push(DomainEvent::BatchQuantityIncreased {
+                sku: self.sku.to_string(),
+                batch_id,
+            });
+        }
+
+        let freed = batch.adjust_quantity(qty)?;
+        for line in &freed {
+            self.events.push(DomainEvent::Deallocated {
+                order_ref: line.order_ref.clone(),
+                sku: line.sku.to_string(),
+                qty: line.qty,
+                cancelled: false,
+            });
+        }
+        batch.assert_invariants();
+
+        self.version_number += 1;
+        Ok(freed)
+    }
+
+    // Removes a batch from the product, deallocating every line it held.
+    // Returns the freed lines.
+    pub fn remove_batch(
+        &mut self,
+        batch_id: BatchId,
+    ) -> Result<Vec<OrderLine>, AllocationError> {
+        let index = self
+            .batches
+            .iter()
+            .position(|batch| batch.id == Some(batch_id))
+            .ok_or(AllocationError::UnknownBatch { batch_id })?;
+        let batch = self.batches.remove(index);
+
+        let mut freed: Vec<OrderLine> = batch.allocated.into_iter().collect();
+        freed.sort_by(|a, b| a.order_ref.cmp(&b.order_ref));
+        for line in &freed {
+            self.events.push(DomainEvent::Deallocated {
+                order_ref: line.order_ref.clone(),
+                sku: line.sku.to_string(),
+                qty: line.qty,
+                cancelled: false,
+            });
+        }
+
+        self.version_number += 1;
+        Ok(freed)
+    }
+
+    // Merges batches arriving at the same time into the same warehouse into
+    // the one with the lowest id, which takes their quantity, allocations and
+    // reservations. Batches expiring at different times are kept apart so
+    // perishable stock isn't mixed, and so are batches without an id yet.
+    // Returns the ids of the merged batches, which are gone from the product.
+    pub fn compact(&mut self) -> Vec<BatchId> {
+        let mut batches: Vec<Batch> = Vec::with_capacity(self.batches.len());
+        let mut merged = Vec::new();
+        let mut order: Vec<&Batch> = self
+            .batches
+            .iter()
+            .filter(|batch| batch.id.is_some())
+            .collect();
+        order.sort_by_key(|batch| batch.id);
+        for batch in order {
+            let Some(kept) = batches.iter_mut().find(|kept| {
+                (kept.eta, &kept.warehouse_id, kept.expires_at)
+                    == (batch.eta, &batch.warehouse_id, batch.expires_at)
+            }) else {
+                batches.push(batch.clone());
+                continue;
+            };
+            kept.qty += batch.qty;
+            for line in &batch.allocated {
+                // Parts of a split line become one line again
+                let part = kept
+                    .allocated
+                    .iter()
+                    .find(|kept| kept.order_ref == line.order_ref)
+                    .cloned();
+                let mut line = line.clone();
+                if let Some(part) = part {
+                    kept.allocated.shift_remove(&part);
+                    line.qty += part.qty;
+                }
+                kept.allocated.insert(line);
+            }
+            kept.reserved.extend(batch.reserved.iter().cloned());
+            merged.extend(batch.id);
+        }
+        if merged.is_empty() {
+            return merged;
+        }
+
+        // Kept batches stay where they were
+        self.batches
+            .retain(|batch| !batch.id.is_some_and(|id| merged.contains(&id)));
+        for batch in &mut self.batches {
+            if let Some(kept) = batches.iter().find(|kept| kept.id == batch.id)
+            {
+                *batch = kept.clone();
+            }
+        }
+        self.version_number += 1;
+        merged
+    }
+
+    // Frees every line of the order, each with a cancelled `Deallocated`
+    // event. An order with nothing allocated here is left alone.
+    pub fn cancel_order(&mut self, order_ref: &str) -> Vec<OrderLine> {
+        let mut freed = Vec::new();
+        for batch in &mut self.batches {
+            let lines: Vec<OrderLine> = batch
+                .allocated
+                .iter()
+                .filter(|line| line.order_ref == order_ref)
+                .cloned()
+                .collect();
+            for line in lines {
+                batch.allocated.shift_remove(&line);
+                self.events.push(DomainEvent::Deallocated {
+                    order_ref: line.order_ref.clone(),
+                    sku: line.sku.to_string(),
+                    qty: line.qty,
+                    cancelled: true,
+                });
+                freed.push(line);
+            }
+        }
+        if !freed.is_empty() {
+            self.version_number += 1;
+        }
+        freed
+    }
+
+    // Holds the line's quantity in the batch `allocate` would pick, without
+    // allocating it yet
+    pub fn reserve(
+        &mut self,
+        order_line: &OrderLine,
+    ) -> Result<ReservationId, AllocationError> {
+        self.reserve_at(order_line, &SystemClock)
+    }
+
+    // Same as `reserve`, stamping the reservation with the clock's current
+    // time and leaving out batches it has expired
+    pub fn reserve_at(
+        &mut self,
+        order_line: &OrderLine,
+        clock: &dyn Clock,
+    ) -> Result<ReservationId, AllocationError> {
+        if self.sku != order_line.sku {
+            return Err(AllocationError::SkuMismatch {
+                expected: self.sku.to_string(),
+                got: order_line.sku.to_string(),
+            });
+        }
+
+        let Some(index) =
+            self.choose_unexpired(order_line, &EarliestEta, clock)
+        else {
+            self.events.push(DomainEvent::OutOfStock {
+                order_ref: order_line.order_ref.clone(),
+                sku: order_line.sku.to_string(),
+                qty: order_line.qty,
+            });
+            return Err(AllocationError::NoBatchAvailable);
+        };
+        let batch = &mut self.batches[index];
+        batch.check_allocation(order_line)?;
+
+        let id = ReservationId(self.last_reservation_id + 1);
+        batch.reserved.push(Reservation {
+            id,
+            line: order_line.clone(),
+            reserved_at: clock.now(),
+        });
+        batch.assert_invariants();
+        self.last_reservation_id = id.0;
+        self.version_number += 1;
+        Ok(id)
+    }
+
+    // Turns the reservation into an allocation of the batch holding it,
+    // returning the batch id
+    pub fn confirm(
+        &mut self,
+        reservation_id: ReservationId,
+    ) -> Result<BatchId, AllocationError> {
+        let (batch, reservation) = self.take_reservation(reservation_id)?;
+        let batch_id = batch.id.ok_or(AllocationError::MissingBatchId)?;
+        let line = reservation.line;
+        batch.allocated.insert(line.clone());
+
+        self.version_number += 1;
+        self.events.push(DomainEvent::Allocated {
+            order_ref: line.order_ref,
+            sku: line.sku.to_string(),
+            qty: line.qty,
+            batch_id,
+        });
+        Ok(batch_id)
+    }
+
+    // Gives the reserved quantity back, returning the line it was held for.
+    // Reported with a `ReservationReleased` event like `release_unconfirmed`.
+    pub fn release(
+        &mut self,
+        reservation_id: ReservationId,
+    ) -> Result<OrderLine, AllocationError> {
+        let (_, reservation) = self.take_reservation(reservation_id)?;
+        self.version_number += 1;
+        self.events.push(DomainEvent::ReservationReleased {
+            reservation_id: reservation_id.0,
+            order_ref: reservation.line.order_ref.clone(),
+            sku: reservation.line.sku.to_string(),
+            qty: reservation.line.qty,
+        });
+        Ok(reservation.line)
+    }
+
+    // Releases every reservation made before `reserved_before`, pass the
+    // current time to release all of them. Each one is reported with a
+    // `ReservationReleased` event.
+    pub fn release_unconfirmed(
+        &mut self,
+        reserved_before: DateTime<Local>,
+    ) -> Vec<Reservation> {
+        let mut released = Vec::new();
+        for batch in &mut self.batches {
+            let (stale, kept) =
+                std::mem::take(&mut batch.reserved).into_iter().partition(
+                    |reservation| reservation.reserved_at < reserved_before,
+                );
+            batch.reserved = kept;
+            released.extend(stale);
+        }
+        released.sort_by_key(|reservation| reservation.id);
+        for reservation in &released {
+            self.events.push(DomainEvent::ReservationReleased {
+                reservation_id: reservation.id.0,
+                order_ref: reservation.line.order_ref.clone(),
+                sku: reservation.line.sku.to_string(),
+                qty: reservation.line.qty,
+            });
+        }
+        if !released.is_empty() {
+            self.version_number += 1;
+        }
+        released
+    }
+
+    fn take_reservation(
+        &mut self,
+        reservation_id: ReservationId,
+    ) -> Result<(&mut Batch, Reservation), AllocationError> {
+        for batch in &mut self.batches {
+            if let Some(index) = batch
+                .reserved
+                .iter()
+                .position(|reservation| reservation.id == reservation_id)
+            {
+                let reservation = batch.reserved.remove(index);
+                return Ok((batch, reservation));
+            }
+        }
+        Err(AllocationError::UnknownReservation { reservation_id })
+    }
+
+    // Frees every line held by a batch that has expired, each with a
+    // `Deallocated` event so it can be allocated elsewhere. The batches stay,
+    // they just can't be allocated from anymore.
+    pub fn release_expired(&mut self, clock: &dyn Clock) -> Vec<OrderLine> {
+        let now = clock.now();
+        let mut freed = Vec::new();
+        for batch in &mut self.batches {
+            if !batch.is_expired(now) || batch.allocated.is_empty() {
+                continue;
+            }
+            let mut lines: Vec<OrderLine> = batch.allocated.drain(..).collect();
+            lines.sort_by(|a, b| a.order_ref.cmp(&b.order_ref));
+            tracing::warn!(
+                batch_id = batch.id.map(u32::from),
+                lines = lines.len(),
+                "expired batch still had allocations"
+            );
+            for line in lines {
+                self.events.push(DomainEvent::Deallocated {
+                    order_ref: line.order_ref.clone(),
+                    sku: line.sku.to_string(),
+                    qty: line.qty,
+                    cancelled: false,
+                });
+                freed.push(line);
+            }
+        }
+        if !freed.is_empty() {
+            self.version_number += 1;
+        }
+        freed
+    }
+
+    pub fn collect_new_events(&mut self) -> Vec<DomainEvent> {
+        std::mem::take(&mut self.events)
+    }
+}
+
+// Thin facade over several products for queries spanning SKUs, each line is
+// still allocated by the product it belongs to
+#[derive(Debug, Clone, Default)]
+pub struct Warehouse {
+    pub products: Vec<Product>,
+}
+
+impl Warehouse {
+    pub fn new(products: Vec<Product>) -> Warehouse {
+        Warehouse { products }
+    }
+
+    pub fn total_available(&self) -> u32 {
+        self.products
+            .iter()
+            .flat_map(|product| &product.batches)
+            .map(Batch::available_qty)
+            .sum()
+    }
+
+    pub fn product(&self, sku: &str) -> Option<&Product> {
+        let sku = Sku::try_from(sku).ok()?;
+        self.products.iter().find(|product| product.sku == sku)
+    }
+
+    #[must_use = "the line may not have been allocated"]
+    pub fn allocate(
+        &mut self,
+        order_line: &OrderLine,
+    ) -> Result<BatchId, AllocationError> {
+        self.products
+            .iter_mut()
+            .find(|product| product.sku == order_line.sku)
+            .ok_or_else(|| AllocationError::UnknownProduct {
+                sku: order_line.sku.to_string(),
+            })?
+            .allocate(order_line, None)
+    }
+}
+
+// Returns the quantity left in the batch the line went to
+#[must_use = "the line may not have been allocated"]
+pub fn allocate(
+    order_line: &OrderLine,
+    batches: &mut [&mut Batch],
+) -> Result<u32, AllocationError> {
+    // In-stock batches first, then by eta
+    batches.sort();
+
+    // Try to allocate the order line to each batch
+    batches
+        .iter_mut()
+        .find_map(|batch| batch.allocate(order_line).ok())
+        // If none of the batches can accommodate the order line, return an error
+        .ok_or(AllocationError::NoBatchAvailable)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::clock::{FixedClock, SystemClock};
+    use tracing_test::traced_test;
+
+    // A SMALL_TABLE line
+    fn line(order_ref: &str, qty: u32) -> OrderLine {
+        OrderLine::new(order_ref.to_string(), "SMALL_TABLE".to_string(), qty)
+            .unwrap()
+    }
+
+    // A SMALL_TABLE product with a batch per (qty, days until it arrives),
+    // numbered from 1. Batches without a number of days are in stock.
+    fn product(batches: &[(u32, Option<i64>)]) -> Product {
+        let batches = batches
+            .iter()
+            .zip(1..)
+            .map(|(&(qty, days), id)| {
+                let eta = days.map(|days| Local::now() + Duration::days(days));
+                let mut batch =
+                    Batch::new("SMALL_TABLE".to_string(), qty, eta).unwrap();
+                batch.id = Some(BatchId(id));
+                batch
+            })
+            .collect();
+        Product::new("SMALL_TABLE".to_string(), batches).unwrap()
+    }
+
+    #[test]
+    fn test_allocating_to_a_batch_reduces_the_available_quantity() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert_eq!(batch.allocate(&order), Ok(18));
+        assert_eq!(batch.available_qty(), 18);
+    }
+
+    #[test]
+    fn test_allocating_to_a_batch_with_insufficient_quantity() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 1, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert_eq!(
+            batch.allocate(&order),
+            Err(AllocationError::InsufficientQuantity {
+                available: 1,
+                requested: 2
+            })
+        );
+        assert_eq!(batch.available_qty(), 1);
+    }
+
+    #[test]
+    fn test_allocating_to_a_batch_with_different_sku() {
+        let batch_sku = "SMALL_TABLE".to_string();
+        let order_sku = "BIG_TABLE".to_string();
+
+        let mut batch = Batch::new(batch_sku, 10, None).unwrap();
+        let order =
+            OrderLine::new("ORDER_1".to_string(), order_sku, 2).unwrap();
+
+        assert_eq!(
+            batch.allocate(&order),
+            Err(AllocationError::SkuMismatch {
+                expected: "SMALL_TABLE".to_string(),
+                got: "BIG_TABLE".to_string()
+            })
+        );
+        assert_eq!(batch.available_qty(), 10);
+    }
+
+    #[test]
+    fn test_can_only_deallocate_allocated_lines() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let order1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 3).unwrap();
+        let order2 =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 2).unwrap();
+
+        assert_eq!(batch.allocate(&order1), Ok(7));
+        assert_eq!(batch.deallocate(&order1), Ok(()));
+        assert_eq!(batch.available_qty(), 10);
+
+        assert_eq!(
+            batch.deallocate(&order2),
+            Err(AllocationError::NotAllocated)
+        );
+        assert_eq!(batch.available_qty(), 10)
+    }
+
+    #[test]
+    fn test_deallocate_one_returns_the_removed_line() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 3).unwrap();
+
+        assert_eq!(batch.allocate(&order), Ok(7));
+        assert_eq!(batch.deallocate_one(&order), Some(order.clone()));
+        assert_eq!(batch.deallocate_one(&order), None);
+        assert_eq!(batch.available_qty(), 10);
+    }
+
+    #[test]
+    fn test_adjust_quantity_up_keeps_every_line() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 8).unwrap();
+        assert_eq!(batch.allocate(&order), Ok(2));
+
+        assert_eq!(batch.adjust_quantity(15), Ok(vec![]));
+        assert_eq!(batch.qty, 15);
+        assert_eq!(batch.available_qty(), 7);
+        assert_eq!(batch.adjust_quantity(0), Err(DomainError::ZeroQuantity));
+        assert_eq!(batch.qty, 15);
+    }
+
+    #[test]
+    fn test_adjust_quantity_below_allocated_frees_latest_lines() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let lines: Vec<OrderLine> =
+            [("ORDER_1", 5), ("ORDER_2", 2), ("ORDER_3", 6)]
+                .into_iter()
+                .map(|(order_ref, qty)| {
+                    OrderLine::new(order_ref.to_string(), sku.clone(), qty)
+                        .unwrap()
+                })
+                .collect();
+        for line in &lines {
+            let _ = batch.allocate(line).unwrap();
+        }
+
+        let freed = batch.adjust_quantity(6).unwrap();
+
+        assert_eq!(freed, [lines[2].clone(), lines[1].clone()]);
+        assert_eq!(batch.allocated, IndexSet::from([lines[0].clone()]));
+        assert_eq!(batch.qty, 6);
+        assert_eq!(batch.available_qty(), 1);
+    }
+
+    #[test]
+    fn test_allocation_is_idempotent() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert_eq!(batch.allocate(&order), Ok(18));
+        assert_eq!(
+            batch.allocate(&order),
+            Err(AllocationError::AlreadyAllocated)
+        );
+        assert_eq!(batch.available_qty(), 18);
+    }
+
+    #[test]
+    fn test_repeated_allocation_keeps_a_single_line() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        for _ in 0..5 {
+            let _ = batch.allocate(&order);
+            assert!(batch.allocated.len() <= 1);
+        }
+        assert_eq!(batch.allocated.len(), 1);
+        assert_eq!(batch.available_qty(), 18);
+    }
+
+    #[test]
+    fn test_can_allocate_if_available_greater_than_required() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert!(batch.can_allocate(&order));
+    }
+
+    #[test]
+    fn test_can_allocate_if_available_equal_to_required() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let batch = Batch::new(sku.clone(), 2, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert!(batch.can_allocate(&order));
+    }
+
+    #[test]
+    fn test_cannot_allocate_if_available_smaller_than_required() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let batch = Batch::new(sku.clone(), 1, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert!(!batch.can_allocate(&order));
+    }
+
+    #[test]
+    fn test_cannot_allocate_if_skus_do_not_match() {
+        let batch = Batch::new("SMALL_TABLE".to_string(), 20, None).unwrap();
+        let order =
+            OrderLine::new("ORDER_1".to_string(), "BIG_TABLE".to_string(), 2)
+                .unwrap();
+
+        assert!(!batch.can_allocate(&order));
+    }
+
+    #[test]
+    fn test_cannot_allocate_if_already_allocated() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+
+        assert_eq!(batch.allocate(&order), Ok(18));
+        assert!(!batch.can_allocate(&order));
+    }
+
+    #[test]
+    fn test_allocated_order_refs_are_distinct() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        let line2 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 3).unwrap();
+        let line3 = OrderLine::new("ORDER_2".to_string(), sku, 4).unwrap();
+
+        assert_eq!(batch.allocate(&line1), Ok(18));
+        assert_eq!(batch.allocate(&line2), Ok(15));
+        assert_eq!(batch.allocate(&line3), Ok(11));
+
+        let expected: HashSet<String> =
+            ["ORDER_1".to_string(), "ORDER_2".to_string()].into();
+        assert_eq!(batch.allocated_order_refs(), expected);
+    }
+
+    #[test]
+    fn test_prefers_current_stock_batches_to_shipments() {
+        let sku = "SMALL_TABLE".to_string();
+        let tomorrow = Local::now() + Duration::days(1);
+
+        let mut stock_batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let mut ship_batch =
+            Batch::new(sku.clone(), 20, Some(tomorrow)).unwrap();
+        let mut batches = [&mut ship_batch, &mut stock_batch];
+
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 10).unwrap();
+
+        assert_eq!(allocate(&order, &mut batches), Ok(10));
+        assert_eq!(stock_batch.available_qty(), 10);
+        assert_eq!(ship_batch.available_qty(), 20);
+    }
+
+    #[test]
+    fn test_builder_builds_in_stock_batch() {
+        let batch = Batch::builder()
+            .id(BatchId(7))
+            .reference("BATCH_1")
+            .sku("small_table")
+            .qty(20)
+            .in_stock()
+            .build()
+            .unwrap();
+
+        assert_eq!(batch.id, Some(BatchId(7)));
+        assert_eq!(batch.reference, Some("BATCH_1".to_string()));
+        assert_eq!(batch.sku, "SMALL_TABLE");
+        assert_eq!(batch.eta, None);
+        assert_eq!(batch.available_qty(), 20);
+    }
+
+    #[test]
+    fn test_builder_builds_shipment() {
+        let eta = Local::now() + Duration::days(3);
+
+        let batch = Batch::builder()
+            .sku("SMALL_TABLE")
+            .qty(5)
+            .eta(eta)
+            .build()
+            .unwrap();
+
+        assert_eq!(batch.eta, Some(eta));
+        assert_eq!(batch.id, None);
+    }
+
+    #[test]
+    fn test_builder_validates_qty_and_sku() {
+        let zero = Batch::builder().sku("SMALL_TABLE").build();
+        let unnamed = Batch::builder().qty(5).build();
+
+        assert_eq!(zero.unwrap_err(), DomainError::ZeroQuantity);
+        assert_eq!(unnamed.unwrap_err(), DomainError::EmptySku);
+    }
+
+    #[test]
+    fn test_batches_sort_in_stock_first_then_by_eta_and_id() {
+        let sku = "SMALL_TABLE".to_string();
+        let tomorrow = Local::now() + Duration::days(1);
+        let later = tomorrow + Duration::days(10);
+        let batch = |id, eta| {
+            let mut batch = Batch::new(sku.clone(), 20, eta).unwrap();
+            batch.id = Some(id);
+            batch
+        };
+
+        let mut batches = [
+            batch(BatchId(1), Some(later)),
+            batch(BatchId(4), None),
+            batch(BatchId(2), Some(tomorrow)),
+            batch(BatchId(3), None),
+        ];
+        batches.sort();
+
+        let ids: Vec<_> = batches.iter().map(|batch| batch.id).collect();
+        assert_eq!(ids, [3, 4, 2, 1].map(|id| Some(BatchId(id))));
+    }
+
+    #[test]
+    fn test_prefers_earlier_batches() {
+        let sku = "SMALL_TABLE".to_string();
+        let today = Local::now();
+        let tomorrow = today + Duration::days(1);
+        let later = today + Duration::days(10);
+
+        let mut earliest = Batch::new(sku.clone(), 20, Some(today)).unwrap();
+        let mut medium = Batch::new(sku.clone(), 20, Some(tomorrow)).unwrap();
+        let mut latest = Batch::new(sku.clone(), 20, Some(later)).unwrap();
+        let mut batches = [&mut latest, &mut medium, &mut earliest];
+
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 10).unwrap();
+
+        assert_eq!(allocate(&order, &mut batches), Ok(10));
+        assert_eq!(earliest.available_qty(), 10);
+        assert_eq!(medium.available_qty(), 20);
+        assert_eq!(latest.available_qty(), 20);
+    }
+
+    #[test]
+    fn test_product_requires_batches_with_matching_sku() {
+        let batches = vec![
+            Batch::new("SMALL_TABLE".to_string(), 20, None).unwrap(),
+            Batch::new("BIG_TABLE".to_string(), 20, None).unwrap(),
+        ];
+
+        assert_eq!(
+            Product::new("SMALL_TABLE".to_string(), batches),
+            Err(AllocationError::SkuMismatch {
+                expected: "SMALL_TABLE".to_string(),
+                got: "BIG_TABLE".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_product_prefers_earlier_batches() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let tomorrow = Local::now() + Duration::days(1);
+
+        let mut stock_batch = Batch::new(sku.clone(), 20, None).unwrap();
+        stock_batch.id = Some(BatchId(1));
+        let mut ship_batch =
+            Batch::new(sku.clone(), 20, Some(tomorrow)).unwrap();
+        ship_batch.id = Some(BatchId(2));
+
+        let mut product =
+            Product::new(sku.clone(), vec![ship_batch, stock_batch]).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 10).unwrap();
+
+        assert_eq!(product.allocate(&order, None), Ok(BatchId(1)));
+        assert_eq!(product.batches[0].available_qty(), 20);
+        assert_eq!(product.batches[1].available_qty(), 10);
+    }
+
+    #[test]
+    fn test_product_allocates_to_the_preferred_batch() {
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        let order = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(product.allocate(&order, Some(BatchId(2))), Ok(BatchId(2)));
+        assert_eq!(product.batches[0].available_qty(), 20);
+        assert_eq!(product.batches[1].available_qty(), 10);
+    }
+
+    #[test]
+    fn test_product_falls_back_when_preferred_batch_is_too_small() {
+        let mut product = product(&[(30, None), (20, Some(1))]);
+        let order = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            25,
+        )
+        .unwrap();
+
+        assert_eq!(product.allocate(&order, Some(BatchId(2))), Ok(BatchId(1)));
+        assert_eq!(product.batches[1].available_qty(), 20);
+    }
+
+    #[test]
+    fn test_product_ignores_a_preferred_batch_it_does_not_have() {
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        let order = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(product.allocate(&order, Some(BatchId(42))), Ok(BatchId(1)));
+        assert_eq!(product.batches[0].available_qty(), 10);
+    }
+
+    #[test]
+    fn test_product_allocate_fails_when_no_batch_can_take_the_line() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 5, None).unwrap();
+        batch.id = Some(BatchId(1));
+
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 10).unwrap();
+
+        assert_eq!(
+            product.allocate(&order, None),
+            Err(AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(product.batches[0].available_qty(), 5);
+    }
+
+    #[test]
+    fn test_product_version_increments_on_successful_allocation() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let small =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 5).unwrap();
+        let big = OrderLine::new("ORDER_2".to_string(), sku, 50).unwrap();
+
+        assert_eq!(product.version_number, 0);
+        assert_eq!(product.allocate(&small, None), Ok(BatchId(1)));
+        assert_eq!(product.version_number, 1);
+
+        assert!(product.allocate(&big, None).is_err());
+        assert_eq!(product.version_number, 1);
+
+        assert_eq!(product.deallocate(&small), Ok(()));
+        assert_eq!(product.version_number, 2);
+
+        assert!(product.deallocate(&small).is_err());
+        assert_eq!(product.version_number, 2);
+    }
+
+    #[test]
+    fn test_product_records_out_of_stock_event_if_cannot_allocate() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let order =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 50).unwrap();
+
+        assert_eq!(
+            product.allocate(&order, None),
+            Err(AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 50
+            }]
+        );
+        assert!(product.collect_new_events().is_empty());
+    }
+
+    #[test]
+    fn test_product_records_no_out_of_stock_event_on_success() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 5).unwrap();
+
+        assert_eq!(product.allocate(&order, None), Ok(BatchId(1)));
+        assert!(!product
+            .collect_new_events()
+            .iter()
+            .any(|event| matches!(event, DomainEvent::OutOfStock { .. })));
+    }
+
+    #[test]
+    fn test_product_records_allocated_event_with_chosen_batch() {
+        let sku = "SMALL_TABLE".to_string();
+        let tomorrow = Local::now() + Duration::days(1);
+
+        let mut ship_batch =
+            Batch::new(sku.clone(), 20, Some(tomorrow)).unwrap();
+        ship_batch.id = Some(BatchId(1));
+        let mut stock_batch = Batch::new(sku.clone(), 20, None).unwrap();
+        stock_batch.id = Some(BatchId(2));
+
+        let mut product =
+            Product::new(sku.clone(), vec![ship_batch, stock_batch]).unwrap();
+        let order =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 7).unwrap();
+
+        assert_eq!(product.allocate(&order, None), Ok(BatchId(2)));
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 7,
+                batch_id: BatchId(2),
+            }]
+        );
+    }
+
+    #[test]
+    fn test_change_batch_quantity_frees_latest_lines_first() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+
+        let big =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 10).unwrap();
+        let small =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 3).unwrap();
+        let medium = OrderLine::new("ORDER_3".to_string(), sku, 5).unwrap();
+        for line in [&big, &small, &medium] {
+            product.allocate(line, None).unwrap();
+        }
+
+        assert_eq!(
+            product.change_batch_quantity(BatchId(1), 11),
+            Ok(vec![medium.clone(), small.clone()])
+        );
+        assert_eq!(product.batches[0].qty, 11);
+        assert_eq!(product.batches[0].available_qty(), 1);
+        assert!(product.batches[0].allocated.contains(&big));
+    }
+
+    #[test]
+    fn test_change_quantity_of_unknown_batch_errors() {
+        let mut product =
+            Product::new("SMALL_TABLE".to_string(), vec![]).unwrap();
+
+        assert_eq!(
+            product.change_batch_quantity(BatchId(1), 10),
+            Err(AllocationError::UnknownBatch {
+                batch_id: BatchId(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_change_batch_quantity_records_deallocated_event() {
+        let sku = "SMALL_TABLE".to_string();
+
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 10).unwrap();
+        let line2 =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 6).unwrap();
+        product.allocate(&line1, None).unwrap();
+        product.allocate(&line2, None).unwrap();
+        product.collect_new_events();
+
+        let freed = product.change_batch_quantity(BatchId(1), 12).unwrap();
+
+        assert_eq!(freed, vec![line2]);
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::Deallocated {
+                order_ref: "ORDER_2".to_string(),
+                sku,
+                qty: 6,
+                cancelled: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_change_batch_quantity_keeps_room_for_reservations() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+        let reserved =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 5).unwrap();
+        let allocated =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 5).unwrap();
+        product.reserve(&reserved).unwrap();
+        product.allocate(&allocated, None).unwrap();
+
+        assert_eq!(
+            product.change_batch_quantity(BatchId(1), 4),
+            Err(AllocationError::InsufficientQuantity {
+                available: 4,
+                requested: 5
+            })
+        );
+        assert_eq!(product.batches[0].qty, 10);
+        assert_eq!(
+            product.change_batch_quantity(BatchId(1), 0),
+            Err(AllocationError::Invalid(DomainError::ZeroQuantity))
+        );
+        assert_eq!(
+            product.change_batch_quantity(BatchId(1), 6),
+            Ok(vec![allocated])
+        );
+        assert_eq!(product.batches[0].qty, 6);
+        assert_eq!(product.batches[0].reserved_qty(), 5);
+        assert_eq!(product.batches[0].available_qty(), 1);
+    }
+
+    #[test]
+    fn test_allocate_order_best_effort_keeps_lines_that_fit() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+
+        let fits =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 6).unwrap();
+        let too_big =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 7).unwrap();
+        let also_fits = OrderLine::new("ORDER_3".to_string(), sku, 4).unwrap();
+
+        assert_eq!(
+            product.allocate_order(
+                &[fits.clone(), too_big, also_fits.clone()],
+                AllocationMode::BestEffort
+            ),
+            Ok(vec![(fits, BatchId(1)), (also_fits, BatchId(1))])
+        );
+        assert_eq!(product.batches[0].available_qty(), 0);
+    }
+
+    #[test]
+    fn test_allocate_order_all_or_nothing_rolls_back_on_failure() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+
+        let fits =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 6).unwrap();
+        let too_big =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 7).unwrap();
+
+        assert_eq!(
+            product
+                .allocate_order(&[fits, too_big], AllocationMode::AllOrNothing),
+            Err(AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(product.batches[0].available_qty(), 10);
+        assert_eq!(product.version_number, 0);
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::OutOfStock {
+                order_ref: "ORDER_2".to_string(),
+                sku,
+                qty: 7
+            }]
+        );
+    }
+
+    #[test]
+    fn test_allocate_order_all_or_nothing_allocates_every_line() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 6).unwrap();
+        let line2 = OrderLine::new("ORDER_2".to_string(), sku, 4).unwrap();
+
+        assert_eq!(
+            product.allocate_order(
+                &[line1.clone(), line2.clone()],
+                AllocationMode::AllOrNothing
+            ),
+            Ok(vec![(line1, BatchId(1)), (line2, BatchId(1))])
+        );
+        assert_eq!(product.version_number, 2);
+    }
+
+    #[test]
+    fn test_product_only_accepts_batches_with_its_sku() {
+        let mut product =
+            Product::new("SMALL_TABLE".to_string(), vec![]).unwrap();
+
+        assert_eq!(
+            product.add_batch(
+                Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap()
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            product.add_batch(
+                Batch::new("BIG_TABLE".to_string(), 10, None).unwrap()
+            ),
+            Err(AllocationError::SkuMismatch {
+                expected: "SMALL_TABLE".to_string(),
+                got: "BIG_TABLE".to_string()
+            })
+        );
+        assert_eq!(product.batches.len(), 1);
+        assert_eq!(product.version_number, 1);
+    }
+
+    #[test]
+    fn test_batch_round_trips_through_json() {
+        let sku = "SMALL_TABLE".to_string();
+        let eta = Local::now() + Duration::days(1);
+        let mut batch = Batch::new(sku.clone(), 20, Some(eta)).unwrap();
+        batch
+            .allocate(
+                &OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap(),
+            )
+            .unwrap();
+        batch
+            .allocate(&OrderLine::new("ORDER_2".to_string(), sku, 3).unwrap())
+            .unwrap();
+
+        let json = serde_json::to_value(&batch).unwrap();
+        assert_eq!(json["id"], serde_json::Value::Null);
+        let serialized_eta = json["eta"].as_str().unwrap();
+        assert_eq!(DateTime::parse_from_rfc3339(serialized_eta).unwrap(), eta);
+        assert_eq!(serde_json::from_value::<Batch>(json).unwrap(), batch);
+    }
+
+    #[test]
+    fn test_display_batch_and_order_line() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        batch.reference = Some("BATCH_1".to_string());
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+        batch.allocate(&line).unwrap();
+
+        assert_eq!(batch.to_string(), "BATCH_1 SMALL_TABLE 18/20");
+        assert_eq!(line.to_string(), "ORDER_1 SMALL_TABLE x2");
+    }
+
+    // In stock, arriving tomorrow and arriving in ten days
+    const STRATEGY_BATCHES: &[(u32, Option<i64>)] =
+        &[(30, None), (50, Some(1)), (10, Some(10))];
+
+    #[test]
+    fn test_earliest_eta_strategy_prefers_in_stock_batch() {
+        let mut with_strategy = product(STRATEGY_BATCHES);
+        let mut by_default = product(STRATEGY_BATCHES);
+
+        let batch_id = with_strategy.allocate_with(
+            &line("ORDER_1", 5),
+            &EarliestEta,
+            &SystemClock,
+        );
+
+        assert_eq!(batch_id, Ok(BatchId(1)));
+        assert_eq!(
+            by_default.allocate(&line("ORDER_1", 5), None),
+            Ok(BatchId(1))
+        );
+    }
+
+    #[test]
+    fn test_smallest_fit_strategy_prefers_fullest_batch() {
+        let mut product = product(STRATEGY_BATCHES);
+
+        let batch_id = product.allocate_with(
+            &line("ORDER_1", 5),
+            &SmallestFit,
+            &SystemClock,
+        );
+
+        assert_eq!(batch_id, Ok(BatchId(3)));
+    }
+
+    #[test]
+    fn test_largest_first_strategy_prefers_emptiest_batch() {
+        let mut product = product(STRATEGY_BATCHES);
+
+        let batch_id = product.allocate_with(
+            &line("ORDER_1", 5),
+            &LargestFirst,
+            &SystemClock,
+        );
+
+        assert_eq!(batch_id, Ok(BatchId(2)));
+    }
+
+    #[test]
+    fn test_strategies_skip_batches_that_cannot_fit() {
+        let product = product(STRATEGY_BATCHES);
+        let line = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            20,
+        )
+        .unwrap();
+
+        assert_eq!(SmallestFit.choose(&line, &product.batches), Some(0));
+        assert_eq!(EarliestEta.choose(&line, &product.batches), Some(0));
+        assert_eq!(LargestFirst.choose(&line, &product.batches), Some(1));
+    }
+
+    #[test]
+    fn test_zero_quantity_batch_is_rejected() {
+        let batch = Batch::new("SMALL_TABLE".to_string(), 0, None);
+
+        assert_eq!(batch, Err(DomainError::ZeroQuantity));
+    }
+
+    #[test]
+    fn test_zero_quantity_order_line_is_rejected() {
+        let line =
+            OrderLine::new("ORDER_1".to_string(), "SMALL_TABLE".to_string(), 0);
+
+        assert_eq!(line, Err(DomainError::ZeroQuantity));
+    }
+
+    #[test]
+    fn test_blank_sku_is_rejected() {
+        assert_eq!(
+            Batch::new("  ".to_string(), 10, None),
+            Err(DomainError::EmptySku)
+        );
+        assert_eq!(
+            OrderLine::new("ORDER_1".to_string(), String::new(), 1),
+            Err(DomainError::EmptySku)
+        );
+    }
+
+    #[test]
+    fn test_allocate_split_spreads_line_across_batches_in_eta_order() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(15, Some(1)), (20, None)]);
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 30).unwrap();
+
+        assert_eq!(
+            product.allocate_split(&line),
+            Ok(vec![(BatchId(2), 20), (BatchId(1), 10)])
+        );
+        assert_eq!(product.batches[0].available_qty(), 5);
+        assert_eq!(product.batches[1].available_qty(), 0);
+        assert_eq!(product.collect_new_events().len(), 2);
+    }
+
+    #[test]
+    fn test_allocate_split_allocates_nothing_when_total_is_insufficient() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(15, Some(1)), (20, None)]);
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 40).unwrap();
+
+        assert_eq!(
+            product.allocate_split(&line),
+            Err(AllocationError::InsufficientQuantity {
+                available: 35,
+                requested: 40
+            })
+        );
+        assert_eq!(product.batches[0].available_qty(), 15);
+        assert_eq!(product.batches[1].available_qty(), 20);
+        assert_eq!(product.version_number, 0);
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 40
+            }]
+        );
+    }
+
+    #[test]
+    fn test_allocation_plan_consumed_in_two_steps_reaches_zero() {
+        let line = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+        let mut plan = AllocationPlan::new(line);
+
+        assert_eq!(plan.consume(4).unwrap().qty, 4);
+        assert_eq!(plan.remaining(), 6);
+        assert_eq!(plan.consume(6).unwrap().qty, 6);
+        assert_eq!(plan.remaining(), 0);
+    }
+
+    #[test]
+    fn test_allocation_plan_rejects_over_consumption() {
+        let line = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+        let mut plan = AllocationPlan::new(line);
+        plan.consume(7).unwrap();
+
+        assert_eq!(
+            plan.consume(4),
+            Err(AllocationError::OverConsumed {
+                remaining: 3,
+                requested: 4
+            })
+        );
+        assert_eq!(plan.remaining(), 3);
+    }
+
+    #[test]
+    fn test_utilization_of_empty_batch_is_zero() {
+        let mut batch =
+            Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        assert_eq!(batch.total_allocated_qty(), 0);
+        assert_eq!(batch.utilization(), 0.0);
+
+        batch.qty = 0;
+        assert_eq!(batch.utilization(), 0.0);
+    }
+
+    #[test]
+    fn test_utilization_of_half_full_batch() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 5).unwrap();
+        batch.allocate(&line).unwrap();
+
+        assert_eq!(batch.total_allocated_qty(), 5);
+        assert_eq!(batch.utilization(), 0.5);
+    }
+
+    #[test]
+    fn test_utilization_of_full_batch_is_one() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        for (order_ref, qty) in [("ORDER_1", 4), ("ORDER_2", 6)] {
+            let line = OrderLine::new(order_ref.to_string(), sku.clone(), qty);
+            batch.allocate(&line.unwrap()).unwrap();
+        }
+
+        assert_eq!(batch.total_allocated_qty(), 10);
+        assert_eq!(batch.utilization(), 1.0);
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_allocate_logs_a_warning_when_out_of_stock() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 20).unwrap();
+
+        assert!(product.allocate(&line, None).is_err());
+
+        assert!(logs_contain("WARN"));
+        assert!(logs_contain("out of stock"));
+        assert!(logs_contain("order_ref=ORDER_1"));
+        assert!(logs_contain("qty=20"));
+    }
+
+    #[test]
+    fn test_remove_batch_deallocates_its_lines() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product(&[(10, None)]);
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        let line2 =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 3).unwrap();
+        product.allocate(&line1, None).unwrap();
+        product.allocate(&line2, None).unwrap();
+        product.collect_new_events();
+
+        let freed = product.remove_batch(BatchId(1)).unwrap();
+
+        assert_eq!(freed, vec![line1, line2]);
+        assert!(product.batches.is_empty());
+        assert_eq!(
+            product.collect_new_events(),
+            vec![
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_1".to_string(),
+                    sku: sku.clone(),
+                    qty: 2,
+                    cancelled: false,
+                },
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_2".to_string(),
+                    sku,
+                    qty: 3,
+                    cancelled: false,
+                },
+            ]
+        );
+        assert_eq!(
+            product.remove_batch(BatchId(1)),
+            Err(AllocationError::UnknownBatch {
+                batch_id: BatchId(1)
+            })
+        );
+    }
+
+    #[test]
+    fn test_over_allocation_is_prevented() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let first = OrderLine::new("ORDER_1".to_string(), sku.clone(), 8);
+        let second = OrderLine::new("ORDER_2".to_string(), sku, 5);
+        batch.allocate(&first.unwrap()).unwrap();
+
+        assert_eq!(
+            batch.allocate(&second.unwrap()),
+            Err(AllocationError::InsufficientQuantity {
+                available: 2,
+                requested: 5
+            })
+        );
+        assert_eq!(batch.total_allocated_qty(), 8);
+        batch.assert_invariants();
+    }
+
+    #[test]
+    fn test_available_qty_of_over_allocated_batch_does_not_underflow() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 8).unwrap();
+        batch.allocate(&line).unwrap();
+
+        batch.qty = 5;
+
+        assert_eq!(batch.available_qty(), 0);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "over-allocated")]
+    fn test_assert_invariants_panics_when_over_allocated() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 8).unwrap();
+        batch.allocate(&line).unwrap();
+        batch.qty = 5;
+
+        batch.assert_invariants();
+    }
+
+    #[test]
+    fn test_product_replayed_from_events_equals_live_product() {
+        let sku = "SMALL_TABLE".to_string();
+        let batches = product(&[(10, None), (20, Some(1))]).batches;
+        let mut live = Product::new(sku.clone(), batches.clone()).unwrap();
+        for (order_ref, qty) in [("ORDER_1", 4), ("ORDER_2", 6), ("ORDER_3", 5)]
+        {
+            let line = OrderLine::new(order_ref.to_string(), sku.clone(), qty);
+            live.allocate(&line.unwrap(), None).unwrap();
+        }
+        let events = live.collect_new_events();
+
+        let replayed = Product::from_events(sku, batches, &events).unwrap();
+
+        assert_eq!(replayed, live);
+    }
+
+    #[test]
+    fn test_product_rebuilt_from_snapshot_equals_full_replay() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batches = product(&[(10, None), (20, Some(1))]).batches;
+        for batch in &mut batches {
+            batch.qty = 100;
+        }
+        let mut live = Product::new(sku.clone(), batches.clone()).unwrap();
+        let mut events = Vec::new();
+        let mut snapshot = None;
+        for n in 0..150 {
+            let line =
+                OrderLine::new(format!("ORDER_{}", n), sku.clone(), 1).unwrap();
+            live.allocate(&line, None).unwrap();
+            events.extend(live.collect_new_events());
+            if events.len() == SNAPSHOT_INTERVAL {
+                snapshot = Some(live.snapshot(events.len()));
+            }
+        }
+
+        let replayed = Product::from_events(sku, batches, &events).unwrap();
+        let restored =
+            Product::from_snapshot(snapshot.unwrap(), &events).unwrap();
+
+        assert_eq!(restored, replayed);
+        assert_eq!(restored, live);
+        assert_eq!(
+            Product::from_snapshot(live.snapshot(200), &events),
+            Err(AllocationError::SnapshotAhead {
+                event_count: 200,
+                stream_len: 150,
+            })
+        );
+    }
+
+    #[test]
+    fn test_replaying_deallocated_event_frees_the_line() {
+        let sku = "SMALL_TABLE".to_string();
+        let events = [
+            DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: sku.clone(),
+                qty: 4,
+                batch_id: BatchId(1),
+            },
+            DomainEvent::Deallocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: sku.clone(),
+                qty: 4,
+                cancelled: false,
+            },
+        ];
+
+        let product = Product::from_events(
+            sku.clone(),
+            product(&[(10, None), (20, Some(1))]).batches,
+            &events,
+        )
+        .unwrap();
+
+        assert_eq!(product.batches[0].available_qty(), 10);
+        assert_eq!(product.version_number, 2);
+    }
+
+    #[test]
+    fn test_replaying_event_for_unknown_batch_errors() {
+        let sku = "SMALL_TABLE".to_string();
+        let events = [DomainEvent::Allocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 4,
+            batch_id: BatchId(42),
+        }];
+
+        assert_eq!(
+            Product::from_events(sku, vec![], &events),
+            Err(AllocationError::UnknownBatch {
+                batch_id: BatchId(42)
+            })
+        );
+    }
+
+    #[test]
+    fn test_sku_is_trimmed_and_uppercased() {
+        let sku = Sku::try_from("  small-table ".to_string()).unwrap();
+
+        assert_eq!(sku, "SMALL-TABLE");
+        assert_eq!(sku, Sku::try_from("SMALL-TABLE ").unwrap());
+    }
+
+    #[test]
+    fn test_blank_sku_newtype_is_rejected() {
+        assert_eq!(Sku::try_from("".to_string()), Err(DomainError::EmptySku));
+        assert_eq!(Sku::try_from(" \t "), Err(DomainError::EmptySku));
+    }
+
+    #[test]
+    fn test_differently_written_skus_allocate_to_each_other() {
+        let mut batch =
+            Batch::new("small-table".to_string(), 10, None).unwrap();
+        let line = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL-TABLE ".to_string(),
+            2,
+        )
+        .unwrap();
+
+        assert_eq!(batch.allocate(&line), Ok(8));
+    }
+
+    #[test]
+    fn test_sku_deserialization_normalizes_and_validates() {
+        let sku: Sku = serde_json::from_str(r#"" blue_lamp""#).unwrap();
+        assert_eq!(sku, "BLUE_LAMP");
+        assert_eq!(serde_json::to_string(&sku).unwrap(), r#""BLUE_LAMP""#);
+        assert!(serde_json::from_str::<Sku>(r#""  ""#).is_err());
+    }
+
+    #[test]
+    fn test_preview_matches_the_batch_allocate_picks() {
+        let mut product = product(STRATEGY_BATCHES);
+        let line = line("ORDER_1", 5);
+        let before = product.clone();
+
+        let preview = product.preview_allocation(&line);
+
+        assert_eq!(product, before);
+        assert_eq!(preview, Some(BatchId(1)));
+        assert_eq!(product.allocate(&line, None), Ok(BatchId(1)));
+    }
+
+    #[test]
+    fn test_preview_is_none_when_nothing_fits() {
+        let product = product(STRATEGY_BATCHES);
+        let line = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            60,
+        )
+        .unwrap();
+        let other_sku =
+            OrderLine::new("ORDER_1".to_string(), "BLUE_LAMP".to_string(), 1)
+                .unwrap();
+
+        assert_eq!(product.preview_allocation(&line), None);
+        assert_eq!(product.preview_allocation(&other_sku), None);
+        assert!(product.events.is_empty());
+    }
+
+    #[test]
+    fn test_batches_created_at_the_same_time_have_equal_etas() {
+        let clock = FixedClock(Local::now());
+        let sku = "SMALL_TABLE".to_string();
+
+        let first =
+            Batch::arriving_in(sku.clone(), 10, Duration::days(1), &clock);
+        let second = Batch::arriving_in(sku, 20, Duration::days(1), &clock);
+
+        assert_eq!(first.unwrap().eta, second.unwrap().eta);
+    }
+
+    #[test]
+    fn test_batch_arriving_later_sorts_after_one_arriving_sooner() {
+        let clock = SystemClock;
+        let sku = "SMALL_TABLE".to_string();
+
+        let soon =
+            Batch::arriving_in(sku.clone(), 10, Duration::days(1), &clock);
+        let later = Batch::arriving_in(sku, 10, Duration::days(2), &clock);
+
+        assert!(soon.unwrap().eta < later.unwrap().eta);
+    }
+
+    #[test]
+    fn test_expired_batch_is_never_allocated_even_with_room() {
+        let now = Local::now();
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        product.batches[0].expires_at = Some(now);
+        let order = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+        let clock = FixedClock(now);
+
+        assert_eq!(product.allocate_at(&order, None, &clock), Ok(BatchId(2)));
+        let other = OrderLine {
+            order_ref: "ORDER_2".to_string(),
+            ..order.clone()
+        };
+        assert_eq!(
+            product.allocate_at(&other, Some(BatchId(1)), &clock),
+            Ok(BatchId(2))
+        );
+        assert_eq!(product.batches[0].available_qty(), 20);
+
+        let too_big = OrderLine {
+            order_ref: "ORDER_3".to_string(),
+            qty: 15,
+            ..order
+        };
+        assert_eq!(
+            product.allocate_at(&too_big, None, &clock),
+            Err(AllocationError::NoBatchAvailable)
+        );
+    }
+
+    #[test]
+    fn test_batch_is_allocated_until_it_expires() {
+        let now = Local::now();
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        product.batches[0].expires_at = Some(now + Duration::days(1));
+        let order = OrderLine::new(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            10,
+        )
+        .unwrap();
+
+        assert_eq!(
+            product.allocate_at(&order, None, &FixedClock(now)),
+            Ok(BatchId(1))
+        );
+    }
+
+    #[test]
+    fn test_release_expired_frees_lines_of_expired_batches() {
+        let now = Local::now();
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        product.batches[0].expires_at = Some(now);
+        let sku = "SMALL_TABLE".to_string();
+        let stale = OrderLine::new("ORDER_1".to_string(), sku.clone(), 4);
+        let fresh = OrderLine::new("ORDER_2".to_string(), sku.clone(), 3);
+        let early = FixedClock(now - Duration::days(1));
+        product.allocate_at(&stale.unwrap(), None, &early).unwrap();
+        product
+            .allocate_at(&fresh.unwrap(), Some(BatchId(2)), &early)
+            .unwrap();
+        product.collect_new_events();
+        let version_number = product.version_number;
+
+        let freed = product.release_expired(&FixedClock(now));
+
+        assert_eq!(freed.len(), 1);
+        assert_eq!(freed[0].order_ref, "ORDER_1");
+        assert!(product.batches[0].allocated.is_empty());
+        assert_eq!(product.batches[1].available_qty(), 17);
+        assert_eq!(product.version_number, version_number + 1);
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::Deallocated {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 4,
+                cancelled: false,
+            }]
+        );
+        assert!(product.release_expired(&FixedClock(now)).is_empty());
+    }
+
+    #[test]
+    fn test_reserve_then_confirm() {
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        let line = line("ORDER_1", 5);
+
+        let reservation_id = product.reserve(&line).unwrap();
+
+        assert_eq!(reservation_id, ReservationId(1));
+        assert_eq!(product.batches[0].available_qty(), 15);
+        assert_eq!(product.batches[0].reserved_qty(), 5);
+        assert_eq!(product.batches[0].total_allocated_qty(), 0);
+        assert!(product.events.is_empty());
+
+        assert_eq!(product.confirm(reservation_id), Ok(BatchId(1)));
+
+        assert_eq!(product.batches[0].available_qty(), 15);
+        assert_eq!(product.batches[0].reserved_qty(), 0);
+        assert!(product.batches[0].allocated.contains(&line));
+        assert_eq!(
+            product.collect_new_events(),
+            vec![DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 5,
+                batch_id: BatchId(1),
+            }]
+        );
+        assert_eq!(
+            product.confirm(reservation_id),
+            Err(AllocationError::UnknownReservation { reservation_id })
+        );
+    }
+
+    #[test]
+    fn test_reserve_then_release() {
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        let line = line("ORDER_1", 5);
+
+        let reservation_id = product.reserve(&line).unwrap();
+        assert_eq!(product.batches[0].available_qty(), 15);
+
+        assert_eq!(product.release(reservation_id), Ok(line.clone()));
+
+        assert_eq!(product.batches[0].available_qty(), 20);
+        assert!(product.batches[0].allocated.is_empty());
+        assert_eq!(
+            product.events,
+            [DomainEvent::ReservationReleased {
+                reservation_id: reservation_id.0,
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 5,
+            }]
+        );
+        assert_eq!(
+            product.release(reservation_id),
+            Err(AllocationError::UnknownReservation { reservation_id })
+        );
+        assert_eq!(product.reserve(&line), Ok(ReservationId(2)));
+    }
+
+    #[test]
+    fn test_reserved_stock_is_not_allocated_to_others() {
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        product.reserve(&line("ORDER_1", 15)).unwrap();
+
+        let batch_id = product.allocate(&line("ORDER_2", 10), None);
+
+        assert_eq!(batch_id, Ok(BatchId(2)));
+    }
+
+    #[test]
+    fn test_release_unconfirmed_releases_older_reservations() {
+        let now = Local::now();
+        let mut product = product(&[(20, None), (20, Some(1))]);
+        let early = FixedClock(now - Duration::hours(2));
+        let old = product.reserve_at(&line("ORDER_1", 5), &early);
+        let recent = product.reserve_at(&line("ORDER_2", 3), &FixedClock(now));
+        let confirmed = product.reserve_at(&line("ORDER_3", 2), &early);
+        product.confirm(confirmed.unwrap()).unwrap();
+
+        let released = product.release_unconfirmed(now - Duration::hours(1));
+
+        let ids: Vec<ReservationId> =
+            released.iter().map(|reservation| reservation.id).collect();
+        assert_eq!(ids, vec![old.unwrap()]);
+        assert_eq!(product.batches[0].reserved_qty(), 3);
+        assert_eq!(product.batches[0].total_allocated_qty(), 2);
+        assert_eq!(product.release(recent.unwrap()).unwrap().qty, 3);
+    }
+
+    fn warehouse() -> Warehouse {
+        let table = product(&[(10, None)]);
+        let mut chair = Batch::new("BLUE_CHAIR".to_string(), 7, None).unwrap();
+        chair.id = Some(BatchId(3));
+        let chairs = Product::new("BLUE_CHAIR".to_string(), vec![chair]);
+        Warehouse::new(vec![table, chairs.unwrap()])
+    }
+
+    #[test]
+    fn test_warehouse_totals_availability_across_products() {
+        let warehouse = warehouse();
+
+        assert_eq!(warehouse.total_available(), 17);
+        assert_eq!(
+            warehouse
+                .product("blue_chair")
+                .map(|product| product.sku.as_str()),
+            Some("BLUE_CHAIR")
+        );
+        assert!(warehouse.product("RED_LAMP").is_none());
+    }
+
+    #[test]
+    fn test_warehouse_routes_allocation_to_the_right_product() {
+        let mut warehouse = warehouse();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), "BLUE_CHAIR".to_string(), 4)
+                .unwrap();
+
+        assert_eq!(warehouse.allocate(&line), Ok(BatchId(3)));
+        assert_eq!(warehouse.total_available(), 13);
+        let table = warehouse.product("SMALL_TABLE").unwrap();
+        assert_eq!(table.batches[0].available_qty(), 10);
+
+        let lamp =
+            OrderLine::new("ORDER_2".to_string(), "RED_LAMP".to_string(), 1)
+                .unwrap();
+        assert_eq!(
+            warehouse.allocate(&lamp),
+            Err(AllocationError::UnknownProduct {
+                sku: "RED_LAMP".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_zero_buffer_allocates_no_more_than_the_batch_quantity() {
+        let mut product = product(&[(20, None)]);
+
+        product.allocate(&line("ORDER_1", 10), None).unwrap();
+        product.allocate(&line("ORDER_2", 10), None).unwrap();
+        assert_eq!(
+            product.allocate(&line("ORDER_3", 10), None),
+            Err(AllocationError::NoBatchAvailable)
+        );
+        assert!(!product
+            .collect_new_events()
+            .iter()
+            .any(|event| matches!(event, DomainEvent::OverAllocated { .. })));
+    }
+
+    #[test]
+    fn test_buffer_allows_one_extra_line_and_flags_it() {
+        let mut product = product(&[(20, None)]);
+        product.set_over_allocation_buffer(10);
+        assert_eq!(product.batches[0].available_qty(), 30);
+
+        for order_ref in ["ORDER_1", "ORDER_2"] {
+            product.allocate(&line(order_ref, 10), None).unwrap();
+        }
+        product.collect_new_events();
+        assert_eq!(
+            product.allocate(&line("ORDER_3", 10), None),
+            Ok(BatchId(1))
+        );
+
+        assert_eq!(product.batches[0].available_qty(), 0);
+        assert_eq!(product.batches[0].buffer_used(), 10);
+        assert_eq!(
+            product.collect_new_events()[1],
+            DomainEvent::OverAllocated {
+                order_ref: "ORDER_3".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                batch_id: BatchId(1),
+                buffer_used: 10,
+            }
+        );
+        assert_eq!(
+            product.allocate(&line("ORDER_4", 10), None),
+            Err(AllocationError::NoBatchAvailable)
+        );
+    }
+
+    #[test]
+    fn test_batches_added_later_share_the_buffer() {
+        let mut product = product(&[(20, None)]);
+        product.set_over_allocation_buffer(5);
+
+        let batch = Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        product.add_batch(batch).unwrap();
+
+        assert_eq!(product.batches[1].available_qty(), 15);
+    }
+
+    fn warehouses(ids: &[&str]) -> Vec<String> {
+        ids.iter().map(|id| id.to_string()).collect()
+    }
+
+    #[test]
+    fn test_nearer_warehouse_is_preferred_over_an_earlier_eta() {
+        let mut product = product(&[(10, None), (10, Some(7))]);
+        let located = warehouses(&["WAREHOUSE_FAR", "WAREHOUSE_NEAR"]);
+        for (batch, warehouse_id) in product.batches.iter_mut().zip(located) {
+            batch.warehouse_id = Some(warehouse_id);
+        }
+        let priority = warehouses(&["WAREHOUSE_NEAR", "WAREHOUSE_FAR"]);
+
+        let batch_id = product
+            .allocate_by_warehouse(&line("ORDER_1", 10), &priority)
+            .unwrap();
+
+        assert_eq!(batch_id, BatchId(2));
+    }
+
+    #[test]
+    fn test_eta_decides_within_a_warehouse_and_full_ones_are_skipped() {
+        let mut product =
+            product(&[(10, Some(7)), (10, Some(2)), (10, None), (10, None)]);
+        let located = warehouses(&[
+            "WAREHOUSE_NEAR",
+            "WAREHOUSE_NEAR",
+            "WAREHOUSE_FAR",
+            "WAREHOUSE_OTHER",
+        ]);
+        for (batch, warehouse_id) in product.batches.iter_mut().zip(located) {
+            batch.warehouse_id = Some(warehouse_id);
+        }
+        let priority = warehouses(&["WAREHOUSE_NEAR", "WAREHOUSE_FAR"]);
+
+        let batch_ids: Vec<BatchId> = (1..=4)
+            .map(|n| {
+                product
+                    .allocate_by_warehouse(
+                        &line(&format!("ORDER_{}", n), 10),
+                        &priority,
+                    )
+                    .unwrap()
+            })
+            .collect();
+
+        assert_eq!(batch_ids, [BatchId(2), BatchId(1), BatchId(3), BatchId(4)]);
+    }
+
+    #[test]
+    fn test_diff_lists_only_what_changed() {
+        let mut before = product(&[(20, None), (20, Some(2))]);
+        for order_ref in ["ORDER_1", "ORDER_2"] {
+            before.allocate(&line(order_ref, 10), None).unwrap();
+        }
+        let mut after = before.clone();
+        after.deallocate(&line("ORDER_1", 10)).unwrap();
+        after.allocate(&line("ORDER_3", 10), None).unwrap();
+        after.change_batch_quantity(BatchId(2), 30).unwrap();
+
+        let diff = before.diff(&after);
+
+        assert_eq!(
+            diff.allocated,
+            [AllocationChange {
+                batch_id: Some(BatchId(1)),
+                line: line("ORDER_3", 10),
+            }]
+        );
+        assert_eq!(
+            diff.freed,
+            [AllocationChange {
+                batch_id: Some(BatchId(1)),
+                line: line("ORDER_1", 10),
+            }]
+        );
+        assert_eq!(
+            diff.quantity_changes,
+            [QuantityChange {
+                batch_id: Some(BatchId(2)),
+                from: 20,
+                to: 30,
+            }]
+        );
+        assert!(after.diff(&after).is_empty());
+    }
+
+    #[test]
+    fn test_repeat_allocation_returns_the_original_batch() {
+        let mut product = product(&[(20, None), (20, Some(2))]);
+        assert_eq!(
+            product.allocate(&line("ORDER_1", 10), None),
+            Ok(BatchId(1))
+        );
+        product.collect_new_events();
+        let version_number = product.version_number;
+
+        // Even when pointed at another batch, or sent with another quantity
+        let resent = OrderLine {
+            qty: 5,
+            ..line("ORDER_1", 10)
+        };
+        assert_eq!(
+            product.allocate(&line("ORDER_1", 10), Some(BatchId(2))),
+            Ok(BatchId(1))
+        );
+        assert_eq!(product.allocate(&resent, None), Ok(BatchId(1)));
+
+        assert_eq!(product.batches[0].available_qty(), 10);
+        assert_eq!(product.batches[1].available_qty(), 20);
+        assert_eq!(product.version_number, version_number);
+        assert!(product.collect_new_events().is_empty());
+        assert_eq!(
+            product.allocated_batch("ORDER_1", "SMALL_TABLE"),
+            Some(BatchId(1))
+        );
+        assert_eq!(product.allocated_batch("ORDER_2", "SMALL_TABLE"), None);
+    }
+
+    #[test]
+    fn test_repeat_split_allocation_returns_the_original_parts() {
+        let mut product = product(&[(6, None), (20, Some(2))]);
+        let parts = product.allocate_split(&line("ORDER_1", 10)).unwrap();
+        assert_eq!(parts, [(BatchId(1), 6), (BatchId(2), 4)]);
+
+        assert_eq!(product.allocate_split(&line("ORDER_1", 10)), Ok(parts));
+        assert_eq!(
+            product.allocate(&line("ORDER_1", 10), None),
+            Ok(BatchId(1))
+        );
+        assert_eq!(product.batches[1].available_qty(), 16);
+    }
+
+    #[test]
+    fn test_compact_merges_batches_with_the_same_eta() {
+        let eta = Local::now() + Duration::days(3);
+        let batch = |id: u32, qty: u32, eta: DateTime<Local>| {
+            Batch::builder()
+                .id(BatchId(id))
+                .sku("SMALL_TABLE")
+                .qty(qty)
+                .eta(eta)
+                .build()
+                .unwrap()
+        };
+        let mut product = Product::new(
+            "SMALL_TABLE".to_string(),
+            vec![
+                batch(3, 4, eta),
+                batch(1, 6, eta),
+                batch(4, 10, eta + Duration::days(1)),
+                batch(2, 5, eta),
+            ],
+        )
+        .unwrap();
+        let line = |order_ref: &str, qty: u32| {
+            OrderLine::new(
+                order_ref.to_string(),
+                "SMALL_TABLE".to_string(),
+                qty,
+            )
+            .unwrap()
+        };
+        product.batches[1].allocate(&line("ORDER_1", 6)).unwrap();
+        product.batches[3].allocate(&line("ORDER_1", 2)).unwrap();
+        product.batches[0].allocate(&line("ORDER_2", 3)).unwrap();
+
+        let merged = product.compact();
+
+        assert_eq!(merged, [BatchId(2), BatchId(3)]);
+        let ids: Vec<_> =
+            product.batches.iter().map(|batch| batch.id).collect();
+        assert_eq!(ids, [Some(BatchId(1)), Some(BatchId(4))]);
+        let kept = &product.batches[0];
+        assert_eq!(kept.qty, 15);
+        assert_eq!(
+            kept.allocated,
+            IndexSet::from([line("ORDER_1", 8), line("ORDER_2", 3)])
+        );
+        assert_eq!(kept.available_qty(), 4);
+        assert_eq!(product.batches[1].qty, 10);
+        assert!(product.compact().is_empty());
+    }
+
+    #[test]
+    fn test_summary_reports_stock_per_batch() {
+        let mut product = product(&[(20, None), (10, Some(2))]);
+        product.allocate(&line("ORDER_2", 10), None).unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), "SMALL_TABLE".to_string(), 5)
+                .unwrap();
+        product.allocate(&line, None).unwrap();
+
+        let summary = product.summary();
+
+        assert_eq!(summary.len(), 2);
+        assert_eq!(summary[0].batch_id, Some(BatchId(1)));
+        assert_eq!(summary[0].eta, None);
+        assert_eq!(
+            (
+                summary[0].total_qty,
+                summary[0].allocated_qty,
+                summary[0].available_qty
+            ),
+            (20, 15, 5)
+        );
+        assert_eq!(summary[0].order_refs, ["ORDER_1", "ORDER_2"]);
+        assert_eq!(summary[1].batch_id, Some(BatchId(2)));
+        assert_eq!(summary[1].eta, product.batches[1].eta);
+        assert_eq!(
+            (
+                summary[1].total_qty,
+                summary[1].allocated_qty,
+                summary[1].available_qty
+            ),
+            (10, 0, 10)
+        );
+        assert!(summary[1].order_refs.is_empty());
+    }
+
+    // Random batches of one product, then random lines allocated against
+    // them one after the other
+    mod properties {
+        use super::*;
+        use chrono::TimeZone;
+        use proptest::prelude::*;
+
+        // Lines mostly for the product's SKU, a few for another one. Both
+        // strategies shrink towards the first SKU and the smallest quantity.
+        fn sku() -> impl Strategy<Value = &'static str> {
+            prop::sample::select(vec![
+                "SMALL_TABLE",
+                "SMALL_TABLE",
+                "BLUE_LAMP",
+            ])
+        }
+
+        fn qty(max: u32) -> impl Strategy<Value = u32> {
+            1..=max
+        }
+
+        // `(qty, eta in days)`, in stock when there is no eta
+        fn batches() -> impl Strategy<Value = Vec<(u32, Option<i64>)>> {
+            prop::collection::vec((qty(50), prop::option::of(0..30i64)), 1..8)
+        }
+
+        fn lines() -> impl Strategy<Value = Vec<(&'static str, u32)>> {
+            prop::collection::vec((sku(), qty(20)), 1..30)
+        }
+
+        fn product(batches: Vec<(u32, Option<i64>)>) -> Product {
+            let today = Local.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap();
+            let batches = batches
+                .into_iter()
+                .zip(1..)
+                .map(|((qty, days), id)| {
+                    let eta = days.map(|days| today + Duration::days(days));
+                    let mut batch =
+                        Batch::new("SMALL_TABLE".to_string(), qty, eta)
+                            .unwrap();
+                    batch.id = Some(BatchId(id));
+                    batch
+                })
+                .collect();
+            Product::new("SMALL_TABLE".to_string(), batches).unwrap()
+        }
+
+        fn line(index: usize, sku: &str, qty: u32) -> OrderLine {
+            let order_ref = format!("ORDER_{index}");
+            OrderLine::new(order_ref, sku.to_string(), qty).unwrap()
+        }
+
+        proptest! {
+            #[test]
+            fn test_allocation_never_exceeds_batch_quantity(
+                batches in batches(),
+                lines in lines(),
+            ) {
+                let mut product = product(batches);
+                for (index, (sku, qty)) in lines.into_iter().enumerate() {
+                    let line = line(index, sku, qty);
+                    let _ = product.allocate(&line, None);
+
+                    for batch in &product.batches {
+                        let allocated = batch.total_allocated_qty();
+                        prop_assert!(allocated <= batch.qty);
+                        prop_assert_eq!(
+                            batch.available_qty(),
+                            batch.qty - allocated
+                        );
+                    }
+                }
+            }
+
+            #[test]
+            fn test_allocation_prefers_the_earliest_batch_with_room(
+                batches in batches(),
+                lines in lines(),
+            ) {
+                let mut product = product(batches);
+                for (index, (sku, qty)) in lines.into_iter().enumerate() {
+                    let line = line(index, sku, qty);
+                    let before = product.batches.clone();
+                    let fits: Vec<&Batch> = before
+                        .iter()
+                        .filter(|batch| batch.can_allocate(&line))
+                        .collect();
+
+                    match product.allocate(&line, None) {
+                        Ok(batch_id) => {
+                            let chosen = fits
+                                .iter()
+                                .find(|batch| batch.id == Some(batch_id));
+                            prop_assert!(chosen.is_some());
+                            let eta = chosen.unwrap().eta;
+                            prop_assert!(
+                                fits.iter().all(|batch| batch.eta >= eta)
+                            );
+                        }
+                        Err(_) => prop_assert!(fits.is_empty()),
+                    }
+                }
+            }
+        }
     }
 }
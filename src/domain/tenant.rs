@@ -0,0 +1,83 @@
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// Used when a caller doesn't say which tenant it is, and for everything
+// stored before there were several
+pub const DEFAULT_TENANT: &str = "default";
+
+// Customer whose data a request works on. Every stored row belongs to one
+// and is only ever read or written on that tenant's behalf.
+#[derive(
+    Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize,
+)]
+#[serde(try_from = "String", into = "String")]
+pub struct TenantId(String);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidTenant(pub String);
+
+impl fmt::Display for InvalidTenant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid tenant {:?}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidTenant {}
+
+impl TenantId {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Default for TenantId {
+    fn default() -> Self {
+        TenantId(DEFAULT_TENANT.to_string())
+    }
+}
+
+// Trimmed, and short enough for the `tenant_id` columns
+impl TryFrom<String> for TenantId {
+    type Error = InvalidTenant;
+
+    fn try_from(tenant: String) -> Result<Self, Self::Error> {
+        let trimmed = tenant.trim();
+        if trimmed.is_empty() || trimmed.chars().count() > 255 {
+            return Err(InvalidTenant(tenant));
+        }
+        Ok(TenantId(trimmed.to_string()))
+    }
+}
+
+impl TryFrom<&str> for TenantId {
+    type Error = InvalidTenant;
+
+    fn try_from(tenant: &str) -> Result<Self, Self::Error> {
+        TenantId::try_from(tenant.to_string())
+    }
+}
+
+impl From<TenantId> for String {
+    fn from(tenant: TenantId) -> Self {
+        tenant.0
+    }
+}
+
+impl fmt::Display for TenantId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_tenant_ids_are_trimmed_and_must_not_be_empty() {
+        assert_eq!(TenantId::try_from(" acme ").unwrap().as_str(), "acme");
+        assert_eq!(TenantId::default().as_str(), DEFAULT_TENANT);
+        assert!(TenantId::try_from("  ").is_err());
+        assert!(TenantId::try_from("x".repeat(256)).is_err());
+    }
+}
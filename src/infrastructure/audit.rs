@@ -0,0 +1,207 @@
+use crate::domain::events::DomainEvent;
+use crate::domain::model::BatchId;
+use crate::domain::repository::RepositoryError;
+use crate::domain::tenant::TenantId;
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::Row;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Allocated,
+    Deallocated,
+}
+
+impl AuditAction {
+    fn as_str(self) -> &'static str {
+        match self {
+            AuditAction::Allocated => "allocated",
+            AuditAction::Deallocated => "deallocated",
+        }
+    }
+
+    fn parse(action: &str) -> anyhow::Result<Self> {
+        match action {
+            "allocated" => Ok(AuditAction::Allocated),
+            "deallocated" => Ok(AuditAction::Deallocated),
+            _ => Err(anyhow::anyhow!("unknown audit action: {action}")),
+        }
+    }
+}
+
+// One move of an order line, `batch_id` is the batch it went to and is only
+// set for allocations
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AuditEntry {
+    pub action: AuditAction,
+    pub order_ref: String,
+    pub sku: String,
+    pub qty: u32,
+    pub batch_id: Option<BatchId>,
+    pub actor: String,
+    pub occurred_at: DateTime<Local>,
+}
+
+// Appends the allocation events to `allocation_events` in the caller's
+// transaction, so the trail only records changes that were kept. Rows are
+// never updated or deleted.
+pub async fn add(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    events: &[DomainEvent],
+    actor: &str,
+) -> Result<(), RepositoryError> {
+    let mut order_refs = Vec::new();
+    let mut skus = Vec::new();
+    let mut qtys = Vec::new();
+    let mut actions = Vec::new();
+    let mut batch_ids = Vec::new();
+    for event in events {
+        let (action, order_ref, sku, qty, batch_id) = match event {
+            DomainEvent::Allocated {
+                order_ref,
+                sku,
+                qty,
+                batch_id,
+            } => (AuditAction::Allocated, order_ref, sku, qty, Some(*batch_id)),
+            DomainEvent::Deallocated {
+                order_ref,
+                sku,
+                qty,
+                ..
+            } => (AuditAction::Deallocated, order_ref, sku, qty, None),
+            DomainEvent::OutOfStock { .. }
+            | DomainEvent::BatchCreated { .. }
+            | DomainEvent::BatchQuantityIncreased { .. }
+            | DomainEvent::OverAllocated { .. }
+            | DomainEvent::ReservationReleased { .. }
+            | DomainEvent::OrderCancelled { .. } => continue,
+        };
+        actions.push(action.as_str());
+        order_refs.push(order_ref.as_str());
+        skus.push(sku.as_str());
+        qtys.push(i32::try_from(*qty)?);
+        batch_ids.push(batch_id);
+    }
+    if actions.is_empty() {
+        return Ok(());
+    }
+
+    sqlx::query(
+        r#"
+            INSERT INTO allocation_events
+                (order_ref, sku, qty, action, batch_id, actor, tenant_id)
+            SELECT *, $6, $7 FROM UNNEST(
+                $1::VARCHAR[], $2::VARCHAR[], $3::INTEGER[], $4::VARCHAR[],
+                $5::INTEGER[]
+            )
+        "#,
+    )
+    .bind(&order_refs)
+    .bind(&skus)
+    .bind(&qtys)
+    .bind(&actions)
+    .bind(&batch_ids)
+    .bind(actor)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Everything that happened to the order's lines, oldest first
+pub async fn allocation_history(
+    order_ref: &str,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<Vec<AuditEntry>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT action, order_ref, sku, qty, batch_id, actor, occurred_at
+            FROM allocation_events
+            WHERE order_ref = $1 AND tenant_id = $2
+            ORDER BY occurred_at, id
+        "#,
+    )
+    .bind(order_ref)
+    .bind(tenant.as_str())
+    .fetch_all(pg_pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(AuditEntry {
+                action: AuditAction::parse(row.get("action"))?,
+                order_ref: row.get("order_ref"),
+                sku: row.get("sku"),
+                qty: row.get::<i32, _>("qty") as u32,
+                batch_id: row.get("batch_id"),
+                actor: row.get("actor"),
+                occurred_at: row.get("occurred_at"),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn allocated(order_ref: &str, batch_id: u32) -> DomainEvent {
+        DomainEvent::Allocated {
+            order_ref: order_ref.to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 2,
+            batch_id: BatchId(batch_id),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_history_lists_the_order_moves_oldest_first(pg_pool: PgPool) {
+        let tenant = TenantId::default();
+        let mut tx = pg_pool.begin().await.unwrap();
+        let events = [
+            allocated("ORDER_1", 1),
+            allocated("ORDER_2", 1),
+            DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku: "BLUE_LAMP".to_string(),
+                qty: 1,
+            },
+        ];
+        add(&mut tx, &tenant, &events, "api").await.unwrap();
+        tx.commit().await.unwrap();
+        let mut tx = pg_pool.begin().await.unwrap();
+        let events = [
+            DomainEvent::Deallocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 2,
+                cancelled: false,
+            },
+            allocated("ORDER_1", 2),
+        ];
+        add(&mut tx, &tenant, &events, "reallocate").await.unwrap();
+        tx.commit().await.unwrap();
+
+        let history = allocation_history("ORDER_1", &tenant, &pg_pool)
+            .await
+            .unwrap();
+
+        let moves: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.action, entry.batch_id, entry.actor.as_str()))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                (AuditAction::Allocated, Some(BatchId(1)), "api"),
+                (AuditAction::Deallocated, None, "reallocate"),
+                (AuditAction::Allocated, Some(BatchId(2)), "reallocate"),
+            ]
+        );
+        assert!(history[0].occurred_at <= history[1].occurred_at);
+    }
+}
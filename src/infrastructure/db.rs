@@ -0,0 +1,201 @@
+use sqlx::postgres::{PgConnectOptions, PgPool, PgPoolOptions};
+use std::time::Duration;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbConfig {
+    pub database_url: String,
+    pub max_connections: u32,
+    // How long a query waits for a free connection from the pool
+    pub acquire_timeout: Duration,
+    // How long to wait for the first connection when the pool is built
+    pub connect_timeout: Duration,
+    // How long a single statement may run before Postgres cancels it
+    pub statement_timeout: Duration,
+}
+
+impl DbConfig {
+    pub fn new(database_url: String) -> Self {
+        Self {
+            database_url,
+            max_connections: 10,
+            acquire_timeout: Duration::from_secs(5),
+            connect_timeout: Duration::from_secs(5),
+            statement_timeout: Duration::from_secs(5),
+        }
+    }
+
+    // Reads `DATABASE_URL` along with the optional `DB_MAX_CONNECTIONS`,
+    // `DB_ACQUIRE_TIMEOUT`, `DB_CONNECT_TIMEOUT` and `DB_STATEMENT_TIMEOUT`
+    // (in seconds)
+    pub fn from_env() -> anyhow::Result<Self> {
+        let mut config = Self::new(std::env::var("DATABASE_URL")?);
+        if let Some(max_connections) = env_var("DB_MAX_CONNECTIONS")? {
+            config.max_connections = max_connections;
+        }
+        if let Some(secs) = env_var("DB_ACQUIRE_TIMEOUT")? {
+            config.acquire_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("DB_CONNECT_TIMEOUT")? {
+            config.connect_timeout = Duration::from_secs(secs);
+        }
+        if let Some(secs) = env_var("DB_STATEMENT_TIMEOUT")? {
+            config.statement_timeout = Duration::from_secs(secs);
+        }
+        Ok(config)
+    }
+}
+
+// Isolation level a unit of work's transaction runs at. Under
+// `Serializable` a transaction that raced another is aborted with a
+// serialization failure, which `handlers::retry_on_conflict` retries.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IsolationLevel {
+    #[default]
+    ReadCommitted,
+    Serializable,
+}
+
+impl IsolationLevel {
+    pub fn as_sql(self) -> &'static str {
+        match self {
+            IsolationLevel::ReadCommitted => "READ COMMITTED",
+            IsolationLevel::Serializable => "SERIALIZABLE",
+        }
+    }
+}
+
+fn env_var<T>(name: &str) -> anyhow::Result<Option<T>>
+where
+    T: std::str::FromStr,
+    T::Err: std::error::Error + Send + Sync + 'static,
+{
+    match std::env::var(name) {
+        Ok(value) => Ok(Some(value.parse()?)),
+        Err(std::env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+pub async fn build_pool(config: &DbConfig) -> anyhow::Result<PgPool> {
+    let statement_timeout =
+        format!("{}ms", config.statement_timeout.as_millis());
+    let options = config.database_url.parse::<PgConnectOptions>()?.options([
+        ("statement_timeout", statement_timeout.as_str()),
+        // Timestamps render and truncate the same whatever the server's
+        // own timezone is
+        ("TimeZone", "UTC"),
+    ]);
+    let connect = PgPoolOptions::new()
+        .max_connections(config.max_connections)
+        .acquire_timeout(config.acquire_timeout)
+        .connect_with(options);
+
+    match tokio::time::timeout(config.connect_timeout, connect).await {
+        Ok(pool) => Ok(pool?),
+        Err(_) => Err(anyhow::anyhow!(
+            "timed out connecting to the database after {:?}",
+            config.connect_timeout
+        )),
+    }
+}
+
+// Applies the migrations in `migrations/` that haven't run yet. They are
+// embedded at build time, so the binary doesn't need the directory.
+pub async fn migrate(pg_pool: &PgPool) -> anyhow::Result<()> {
+    sqlx::migrate!().run(pg_pool).await?;
+    Ok(())
+}
+
+// Whether Postgres cancelled the query for running past `statement_timeout`.
+// The database error may be wrapped, in a `RepositoryError` for one.
+pub fn is_statement_timeout(err: &anyhow::Error) -> bool {
+    err.chain()
+        .filter_map(|err| err.downcast_ref::<sqlx::Error>())
+        .filter_map(|err| err.as_database_error())
+        .filter_map(|err| err.code())
+        .any(|code| code == "57014")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn test_config() -> DbConfig {
+        dotenvy::dotenv().ok();
+        DbConfig::new(std::env::var("DATABASE_URL").unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_acquire_waits_for_a_free_connection() {
+        let config = DbConfig {
+            max_connections: 1,
+            ..test_config()
+        };
+        let pg_pool = build_pool(&config).await.unwrap();
+
+        let first = pg_pool.acquire().await.unwrap();
+        let second = tokio::spawn({
+            let pg_pool = pg_pool.clone();
+            async move { pg_pool.acquire().await.map(drop) }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        drop(first);
+        second.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_acquire_times_out_when_pool_is_exhausted() {
+        let config = DbConfig {
+            max_connections: 1,
+            acquire_timeout: Duration::from_millis(100),
+            ..test_config()
+        };
+        let pg_pool = build_pool(&config).await.unwrap();
+
+        let _first = pg_pool.acquire().await.unwrap();
+
+        assert!(pg_pool.acquire().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_statement_timeout_cancels_slow_queries() {
+        let config = DbConfig {
+            statement_timeout: Duration::from_millis(50),
+            ..test_config()
+        };
+        let pg_pool = build_pool(&config).await.unwrap();
+
+        let err = sqlx::query("SELECT pg_sleep(1)")
+            .execute(&pg_pool)
+            .await
+            .unwrap_err();
+
+        assert!(is_statement_timeout(&err.into()));
+        sqlx::query("SELECT pg_sleep(0.01)")
+            .execute(&pg_pool)
+            .await
+            .unwrap();
+    }
+
+    #[sqlx::test(migrations = false)]
+    async fn test_migrate_creates_the_schema(pg_pool: PgPool) {
+        migrate(&pg_pool).await.unwrap();
+        // Running them again is a no-op
+        migrate(&pg_pool).await.unwrap();
+
+        let tables: Vec<String> = sqlx::query_scalar(
+            r#"
+                SELECT table_name::TEXT FROM information_schema.tables
+                WHERE table_schema = 'public'
+            "#,
+        )
+        .fetch_all(&pg_pool)
+        .await
+        .unwrap();
+        for table in ["batches", "allocations", "products", "outbox"] {
+            assert!(tables.iter().any(|name| name == table), "{}", table);
+        }
+    }
+}
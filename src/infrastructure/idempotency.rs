@@ -0,0 +1,90 @@
+use crate::domain::tenant::TenantId;
+use serde_json::Value;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+use std::time::Duration;
+
+// Response recorded for an idempotency key, as an HTTP status and JSON body
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoredResponse {
+    pub status: u16,
+    pub body: Value,
+}
+
+// Returns the response stored for the tenant's `key`, unless it is older than
+// `ttl`. Tenants picking the same key don't see each other's responses.
+pub async fn get(
+    pg_pool: &PgPool,
+    tenant: &TenantId,
+    key: &str,
+    ttl: Duration,
+) -> anyhow::Result<Option<StoredResponse>> {
+    let row = sqlx::query(
+        r#"
+            SELECT status, response FROM idempotency_keys
+            WHERE key = $1 AND created_at > now() - make_interval(secs => $2)
+            AND tenant_id = $3
+        "#,
+    )
+    .bind(key)
+    .bind(ttl.as_secs_f64())
+    .bind(tenant.as_str())
+    .fetch_optional(pg_pool)
+    .await?;
+
+    Ok(row.map(|row| StoredResponse {
+        status: row.get::<i16, _>("status") as u16,
+        body: row.get("response"),
+    }))
+}
+
+// Records the response for `key`, replacing an expired one
+pub async fn put(
+    pg_pool: &PgPool,
+    tenant: &TenantId,
+    key: &str,
+    response: &StoredResponse,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO idempotency_keys (key, status, response, tenant_id)
+            VALUES ( $1, $2, $3, $4 )
+            ON CONFLICT (tenant_id, key) DO UPDATE
+            SET status = EXCLUDED.status,
+                response = EXCLUDED.response,
+                created_at = now()
+        "#,
+    )
+    .bind(key)
+    .bind(response.status as i16)
+    .bind(&response.body)
+    .bind(tenant.as_str())
+    .execute(pg_pool)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[sqlx::test]
+    async fn test_stored_response_expires_after_ttl(pg_pool: PgPool) {
+        let response = StoredResponse {
+            status: 201,
+            body: json!({ "batch_id": 1 }),
+        };
+        let tenant = TenantId::default();
+        let other = TenantId::try_from("acme").unwrap();
+        put(&pg_pool, &tenant, "KEY_1", &response).await.unwrap();
+
+        let ttl = Duration::from_secs(60);
+        let stored = get(&pg_pool, &tenant, "KEY_1", ttl).await.unwrap();
+        assert_eq!(stored, Some(response));
+        assert_eq!(get(&pg_pool, &tenant, "KEY_2", ttl).await.unwrap(), None);
+        assert_eq!(get(&pg_pool, &other, "KEY_1", ttl).await.unwrap(), None);
+        let expired = get(&pg_pool, &tenant, "KEY_1", Duration::ZERO).await;
+        assert_eq!(expired.unwrap(), None);
+    }
+}
@@ -0,0 +1,73 @@
+use crate::domain::model::{BatchId, OrderLineId};
+use sqlx::encode::IsNull;
+use sqlx::error::BoxDynError;
+use sqlx::postgres::{PgArgumentBuffer, PgTypeInfo, PgValueRef};
+use sqlx::{Decode, Encode, Postgres, Type};
+
+// Ids are `SERIAL` columns, stored as `INTEGER` and bound the same way
+macro_rules! integer_id {
+    ($id:ident) => {
+        impl Type<Postgres> for $id {
+            fn type_info() -> PgTypeInfo {
+                <i32 as Type<Postgres>>::type_info()
+            }
+        }
+
+        impl Encode<'_, Postgres> for $id {
+            fn encode_by_ref(&self, buf: &mut PgArgumentBuffer) -> IsNull {
+                <i32 as Encode<Postgres>>::encode_by_ref(&(self.0 as i32), buf)
+            }
+        }
+
+        impl Decode<'_, Postgres> for $id {
+            fn decode(value: PgValueRef<'_>) -> Result<Self, BoxDynError> {
+                Ok($id(<i32 as Decode<Postgres>>::decode(value)? as u32))
+            }
+        }
+
+        impl sqlx::postgres::PgHasArrayType for $id {
+            fn array_type_info() -> PgTypeInfo {
+                <i32 as sqlx::postgres::PgHasArrayType>::array_type_info()
+            }
+        }
+    };
+}
+
+integer_id!(BatchId);
+integer_id!(OrderLineId);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::postgres::PgPool;
+
+    #[sqlx::test]
+    async fn test_ids_round_trip_through_the_database(pg_pool: PgPool) {
+        let (batch_id, line_id): (BatchId, OrderLineId) =
+            sqlx::query_as("SELECT $1::INTEGER, $2::INTEGER")
+                .bind(BatchId(7))
+                .bind(OrderLineId(42))
+                .fetch_one(&pg_pool)
+                .await
+                .unwrap();
+        assert_eq!(batch_id, BatchId(7));
+        assert_eq!(line_id, OrderLineId(42));
+
+        let ids: Vec<BatchId> = sqlx::query_scalar("SELECT UNNEST($1)")
+            .bind(vec![BatchId(1), BatchId(2)])
+            .fetch_all(&pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(ids, vec![BatchId(1), BatchId(2)]);
+    }
+
+    #[test]
+    fn test_ids_only_convert_explicitly() {
+        let batch_id = BatchId::from(3);
+        let raw: u32 = batch_id.into();
+        let line_id = OrderLineId::from(raw);
+
+        assert_eq!(u32::from(line_id), 3);
+        assert_eq!(batch_id.to_string(), line_id.to_string());
+    }
+}
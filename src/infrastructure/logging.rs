@@ -0,0 +1,139 @@
+use std::str::FromStr;
+use tracing::Subscriber;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::EnvFilter;
+
+// `LOG_FORMAT=json` writes one JSON object per line, with the fields of the
+// enclosing spans under `spans`, for log aggregation. `pretty` (the default)
+// is for reading in a terminal.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            other => Err(anyhow::anyhow!(
+                "Unknown LOG_FORMAT {}, expected json or pretty",
+                other
+            )),
+        }
+    }
+}
+
+impl LogFormat {
+    pub fn from_env() -> anyhow::Result<Self> {
+        match std::env::var("LOG_FORMAT") {
+            Ok(value) => value.parse(),
+            Err(std::env::VarError::NotPresent) => Ok(LogFormat::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+pub fn subscriber<W>(
+    format: LogFormat,
+    filter: EnvFilter,
+    writer: W,
+) -> Box<dyn Subscriber + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer);
+    match format {
+        LogFormat::Pretty => Box::new(builder.pretty().finish()),
+        LogFormat::Json => Box::new(
+            builder
+                .json()
+                .with_current_span(false)
+                .with_span_list(true)
+                .finish(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::{Batch, BatchId, Product};
+    use crate::services::handlers;
+    use crate::services::unit_of_work::fake::FakeUnitOfWork;
+    use serde_json::{json, Value};
+    use std::io;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_log_format_parses_known_values() {
+        assert_eq!("json".parse::<LogFormat>().unwrap(), LogFormat::Json);
+        assert_eq!("pretty".parse::<LogFormat>().unwrap(), LogFormat::Pretty);
+        assert!("xml".parse::<LogFormat>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_json_logs_carry_allocation_fields_as_keys() {
+        let buffer = Buffer::default();
+        let writer = buffer.clone();
+        let subscriber =
+            subscriber(LogFormat::Json, EnvFilter::new("info"), move || {
+                writer.clone()
+            });
+        let _guard = tracing::subscriber::set_default(subscriber);
+        let mut batch =
+            Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut uow = FakeUnitOfWork::with_products(vec![Product::new(
+            "SMALL_TABLE".to_string(),
+            vec![batch],
+        )
+        .unwrap()]);
+
+        handlers::allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+
+        let output =
+            String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let allocated: Value = output
+            .lines()
+            .map(|line| serde_json::from_str::<Value>(line).unwrap())
+            .find(|log| log["fields"]["message"] == "allocated")
+            .unwrap();
+        assert_eq!(allocated["fields"]["batch_id"], 1);
+        let span = allocated["spans"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|span| span["name"] == "allocate")
+            .unwrap();
+        assert_eq!(span["sku"], json!("SMALL_TABLE"));
+        assert_eq!(span["order_ref"], json!("ORDER_1"));
+        assert_eq!(span["qty"], json!(3));
+    }
+}
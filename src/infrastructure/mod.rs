@@ -0,0 +1,10 @@
+pub mod audit;
+pub mod db;
+pub mod idempotency;
+pub mod ids;
+pub mod logging;
+pub mod outbox;
+#[cfg(feature = "redis")]
+pub mod redis_publisher;
+pub mod repository;
+pub mod snapshots;
@@ -0,0 +1,67 @@
+use crate::domain::events::DomainEvent;
+use crate::domain::repository::RepositoryError;
+use crate::domain::tenant::TenantId;
+use sqlx::postgres::PgConnection;
+use sqlx::types::Json;
+use sqlx::Row;
+
+// Stores events in the caller's transaction so they are only kept if the
+// changes that raised them are
+pub async fn add(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    events: &[DomainEvent],
+) -> Result<(), RepositoryError> {
+    if events.is_empty() {
+        return Ok(());
+    }
+    let events: Vec<Json<&DomainEvent>> = events.iter().map(Json).collect();
+    sqlx::query(
+        r#"
+            INSERT INTO outbox (tenant_id, event)
+            SELECT $2, * FROM UNNEST($1::JSONB[])
+        "#,
+    )
+    .bind(&events)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// The oldest events not sent yet, whatever their tenant, locked so
+// concurrent relays skip them
+pub async fn unsent(
+    conn: &mut PgConnection,
+    limit: i64,
+) -> anyhow::Result<Vec<(i64, TenantId, DomainEvent)>> {
+    let rows = sqlx::query(
+        r#"
+            SELECT id, tenant_id, event FROM outbox WHERE sent_at IS NULL
+            ORDER BY id LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        "#,
+    )
+    .bind(limit)
+    .fetch_all(&mut *conn)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            let Json(event) = row.get("event");
+            let tenant = TenantId::try_from(row.get::<String, _>("tenant_id"))?;
+            Ok((row.get("id"), tenant, event))
+        })
+        .collect()
+}
+
+pub async fn mark_sent(
+    conn: &mut PgConnection,
+    ids: &[i64],
+) -> anyhow::Result<()> {
+    sqlx::query(r#"UPDATE outbox SET sent_at = now() WHERE id = ANY($1)"#)
+        .bind(ids)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,109 @@
+use crate::domain::events::{DomainEvent, Publisher};
+use crate::domain::tenant::TenantId;
+use async_trait::async_trait;
+use redis::aio::MultiplexedConnection;
+use redis::AsyncCommands;
+
+pub const DEFAULT_CHANNEL: &str = "cosmic:events";
+
+// Publishes every event as JSON, tagged with its `type` and `tenant_id`, on
+// one pub/sub channel
+#[derive(Clone)]
+pub struct RedisEventPublisher {
+    conn: MultiplexedConnection,
+    channel: String,
+}
+
+impl RedisEventPublisher {
+    pub async fn connect(
+        redis_url: &str,
+        channel: &str,
+    ) -> anyhow::Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+        Ok(Self {
+            conn: client.get_multiplexed_async_connection().await?,
+            channel: channel.to_string(),
+        })
+    }
+}
+
+#[async_trait]
+impl Publisher for RedisEventPublisher {
+    async fn publish(
+        &self,
+        tenant: &TenantId,
+        event: &DomainEvent,
+    ) -> anyhow::Result<()> {
+        let mut payload = serde_json::to_value(event)?;
+        payload["tenant_id"] = tenant.as_str().into();
+        let payload = serde_json::to_string(&payload)?;
+        let mut conn = self.conn.clone();
+        conn.publish::<_, _, ()>(&self.channel, payload).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::{Batch, Product};
+    use crate::domain::repository::ProductRepository;
+    use crate::infrastructure::repository::PostgresBatchRepository;
+    use crate::services::handlers;
+    use crate::services::relay;
+    use crate::services::unit_of_work::PostgresUnitOfWork;
+    use futures_util::StreamExt;
+    use sqlx::postgres::PgPool;
+    use std::time::Duration;
+
+    fn redis_url() -> String {
+        std::env::var("REDIS_URL")
+            .unwrap_or_else(|_| "redis://127.0.0.1/".to_string())
+    }
+
+    #[sqlx::test]
+    async fn test_allocation_is_published_to_redis(pg_pool: PgPool) {
+        let channel = "cosmic:events:test";
+        let client = redis::Client::open(redis_url()).unwrap();
+        let mut pubsub = client.get_async_pubsub().await.unwrap();
+        pubsub.subscribe(channel).await.unwrap();
+        let publisher = RedisEventPublisher::connect(&redis_url(), channel)
+            .await
+            .unwrap();
+
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batch = Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        let product =
+            Product::new("SMALL_TABLE".to_string(), vec![batch]).unwrap();
+        repo.add(&product).await.unwrap();
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        let batch_id = handlers::allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        relay::publish_pending(&pg_pool, &publisher).await.unwrap();
+
+        let mut messages = pubsub.on_message();
+        let message =
+            tokio::time::timeout(Duration::from_secs(2), messages.next())
+                .await
+                .unwrap()
+                .unwrap();
+        let payload: String = message.get_payload().unwrap();
+        let json: serde_json::Value = serde_json::from_str(&payload).unwrap();
+        assert_eq!(json["tenant_id"], "default");
+        assert_eq!(
+            serde_json::from_str::<DomainEvent>(&payload).unwrap(),
+            DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 3,
+                batch_id,
+            }
+        );
+    }
+}
@@ -0,0 +1,1984 @@
+use crate::domain::clock::Clock;
+use crate::domain::events::DomainEvent;
+use crate::domain::model::{
+    Batch, BatchId, DomainError, OrderLine, Product, Reservation,
+    ReservationId, Sku,
+};
+use crate::domain::repository::{
+    ConcurrencyError, ProductRepository, RepositoryError,
+};
+use crate::domain::tenant::TenantId;
+use crate::infrastructure::db::IsolationLevel;
+use crate::infrastructure::{audit, outbox};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Local, Utc};
+use futures_util::{Stream, StreamExt};
+use indexmap::IndexSet;
+use serde::Serialize;
+use sqlx::postgres::{PgConnection, PgPool};
+use sqlx::{Postgres, Row, Transaction};
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+// One allocated order line as exported, flattened with its batch's eta
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationRecord {
+    pub order_ref: String,
+    pub sku: String,
+    pub qty: u32,
+    pub batch_id: BatchId,
+    pub eta: Option<DateTime<Local>>,
+}
+
+// Only sees the rows of its tenant
+pub struct PostgresBatchRepository {
+    pg_pool: Arc<PgPool>,
+    tenant: TenantId,
+}
+
+impl PostgresBatchRepository {
+    pub fn new(pg_pool: PgPool) -> Self {
+        Self::for_tenant(pg_pool, TenantId::default())
+    }
+
+    pub fn for_tenant(pg_pool: PgPool, tenant: TenantId) -> Self {
+        Self {
+            pg_pool: Arc::new(pg_pool),
+            tenant,
+        }
+    }
+
+    pub async fn create_batch(
+        &self,
+        batch: &Batch,
+    ) -> Result<BatchId, RepositoryError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let id = insert_batch(&mut tx, &self.tenant, batch).await?;
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    // Inserts all the batches in a fixed number of statements, whatever the
+    // number of batches, instead of a round-trip per batch as `create_batch`
+    // does. Returns the new ids in the order of `batches`.
+    pub async fn add_batches(
+        &self,
+        batches: &[Batch],
+    ) -> Result<Vec<BatchId>, RepositoryError> {
+        let mut references = Vec::with_capacity(batches.len());
+        let mut skus = Vec::with_capacity(batches.len());
+        let mut qtys = Vec::with_capacity(batches.len());
+        let mut etas = Vec::with_capacity(batches.len());
+        let mut expiries = Vec::with_capacity(batches.len());
+        let mut warehouse_ids = Vec::with_capacity(batches.len());
+        for batch in batches {
+            references.push(batch.reference.clone());
+            skus.push(batch.sku.to_string());
+            qtys.push(i32::try_from(batch.qty)?);
+            etas.push(to_utc(batch.eta));
+            expiries.push(to_utc(batch.expires_at));
+            warehouse_ids.push(batch.warehouse_id.clone());
+        }
+
+        let mut tx = self.pg_pool.begin().await?;
+        sqlx::query(
+            r#"
+                INSERT INTO products (tenant_id, sku)
+                SELECT DISTINCT $2, sku FROM UNNEST($1::VARCHAR[]) AS t (sku)
+                ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(&skus)
+        .bind(self.tenant.as_str())
+        .execute(&mut *tx)
+        .await?;
+
+        // Serial ids are handed out in insertion order, so sorting them
+        // lines them up with the input again
+        let mut ids: Vec<BatchId> = sqlx::query_scalar(
+            r#"
+                INSERT INTO batches (
+                    tenant_id, reference, sku, qty, eta, expires_at,
+                    warehouse_id
+                )
+                SELECT $7, reference, sku, qty, eta, expires_at, warehouse_id
+                FROM UNNEST(
+                    $1::VARCHAR[], $2::VARCHAR[], $3::INTEGER[],
+                    $4::TIMESTAMPTZ[], $5::TIMESTAMPTZ[], $6::VARCHAR[]
+                ) WITH ORDINALITY
+                    AS t (
+                        reference, sku, qty, eta, expires_at, warehouse_id,
+                        position
+                    )
+                ORDER BY position
+                RETURNING id
+            "#,
+        )
+        .bind(&references)
+        .bind(&skus)
+        .bind(&qtys)
+        .bind(&etas)
+        .bind(&expiries)
+        .bind(&warehouse_ids)
+        .bind(self.tenant.as_str())
+        .fetch_all(&mut *tx)
+        .await?;
+        ids.sort_unstable();
+
+        let mut batch_ids = Vec::new();
+        let mut order_refs = Vec::new();
+        let mut line_skus = Vec::new();
+        let mut line_qtys = Vec::new();
+        for (batch, &id) in batches.iter().zip(&ids) {
+            for line in &batch.allocated {
+                batch_ids.push(id);
+                order_refs.push(line.order_ref.clone());
+                line_skus.push(line.sku.to_string());
+                line_qtys.push(i32::try_from(line.qty)?);
+            }
+        }
+        sqlx::query(
+            r#"
+                INSERT INTO allocations
+                    (tenant_id, batch_id, order_ref, sku, qty)
+                SELECT $5, * FROM UNNEST(
+                    $1::INTEGER[], $2::VARCHAR[], $3::VARCHAR[], $4::INTEGER[]
+                )
+            "#,
+        )
+        .bind(&batch_ids)
+        .bind(&order_refs)
+        .bind(&line_skus)
+        .bind(&line_qtys)
+        .bind(self.tenant.as_str())
+        .execute(&mut *tx)
+        .await?;
+        for (batch, &id) in batches.iter().zip(&ids) {
+            insert_reservations(&mut tx, &self.tenant, id, batch).await?;
+        }
+        tx.commit().await?;
+
+        Ok(ids)
+    }
+
+    // Soft-deleted batches are only returned with `include_deleted`
+    pub async fn read_batch(
+        &self,
+        id: BatchId,
+        include_deleted: bool,
+    ) -> Result<Batch, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let row = sqlx::query_as::<_, BatchRow>(
+            r#"
+                SELECT id, reference, sku, qty, eta, deleted_at, expires_at,
+                    warehouse_id
+                FROM batches
+                WHERE id = $1 AND ($2 OR deleted_at IS NULL)
+                AND tenant_id = $3
+            "#,
+        )
+        .bind(id)
+        .bind(include_deleted)
+        .bind(self.tenant.as_str())
+        .fetch_one(&mut *conn)
+        .await?;
+
+        let mut batches = vec![Batch::try_from(row)?];
+        load_allocations(&mut conn, &self.tenant, &mut batches).await?;
+        Ok(batches.remove(0))
+    }
+
+    // Batches ordered by eta (in stock first) then id so pages stay stable.
+    // `eta_from`/`eta_to` bound the eta inclusively, in-stock batches are
+    // only listed when there is no `eta_from`. Soft-deleted batches are only
+    // listed with `include_deleted`.
+    pub async fn list_batches(
+        &self,
+        sku: Option<&str>,
+        eta_from: Option<DateTime<Local>>,
+        eta_to: Option<DateTime<Local>>,
+        limit: i64,
+        offset: i64,
+        include_deleted: bool,
+    ) -> Result<Vec<Batch>, RepositoryError> {
+        let sku = sku.map(Sku::try_from).transpose()?;
+        let mut conn = self.pg_pool.acquire().await?;
+        let rows = sqlx::query_as::<_, BatchRow>(
+            r#"
+                SELECT id, reference, sku, qty, eta, deleted_at, expires_at,
+                    warehouse_id
+                FROM batches
+                WHERE ($1::VARCHAR IS NULL OR sku = $1)
+                AND ($4 OR deleted_at IS NULL)
+                AND tenant_id = $7
+                AND (
+                    (eta IS NULL AND $5::TIMESTAMPTZ IS NULL)
+                    OR (
+                        ($5 IS NULL OR eta >= $5)
+                        AND ($6::TIMESTAMPTZ IS NULL OR eta <= $6)
+                    )
+                )
+                ORDER BY eta ASC NULLS FIRST, id
+                LIMIT $2 OFFSET $3
+            "#,
+        )
+        .bind(sku.as_ref().map(Sku::as_str))
+        .bind(limit)
+        .bind(offset)
+        .bind(include_deleted)
+        .bind(to_utc(eta_from))
+        .bind(to_utc(eta_to))
+        .bind(self.tenant.as_str())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut batches = rows
+            .into_iter()
+            .map(Batch::try_from)
+            .collect::<Result<Vec<Batch>, _>>()?;
+        load_allocations(&mut conn, &self.tenant, &mut batches).await?;
+        Ok(batches)
+    }
+
+    pub async fn update_batch(
+        &self,
+        id: BatchId,
+        batch: &Batch,
+    ) -> Result<bool, RepositoryError> {
+        let mut tx = self.pg_pool.begin().await?;
+        let updated = update_batch(&mut tx, &self.tenant, id, batch).await?;
+        tx.commit().await?;
+
+        Ok(updated)
+    }
+
+    // `(sku, batch_id)` of the batch holding the order, `None` if it isn't
+    // allocated. An order spanning several SKUs reports the first of them.
+    pub async fn find_allocation(
+        &self,
+        order_ref: &str,
+    ) -> Result<Option<(String, BatchId)>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let row = sqlx::query(
+            r#"
+                SELECT batches.sku, allocations.batch_id FROM allocations
+                JOIN batches ON batches.id = allocations.batch_id
+                WHERE allocations.order_ref = $1
+                AND batches.deleted_at IS NULL AND batches.tenant_id = $2
+                ORDER BY batches.sku
+                LIMIT 1
+            "#,
+        )
+        .bind(order_ref)
+        .bind(self.tenant.as_str())
+        .fetch_optional(&mut *conn)
+        .await?;
+
+        Ok(row.map(|row| (row.get("sku"), row.get("batch_id"))))
+    }
+
+    // Live batches expiring from the clock's current time up to `window`
+    // later, soonest first. Batches already expired aren't listed.
+    pub async fn expiring_within(
+        &self,
+        window: Duration,
+        clock: &dyn Clock,
+    ) -> Result<Vec<Batch>, RepositoryError> {
+        let now = clock.now();
+        let mut conn = self.pg_pool.acquire().await?;
+        let rows = sqlx::query_as::<_, BatchRow>(
+            r#"
+                SELECT id, reference, sku, qty, eta, deleted_at, expires_at,
+                    warehouse_id
+                FROM batches
+                WHERE deleted_at IS NULL AND tenant_id = $3
+                AND expires_at > $1 AND expires_at <= $2
+                ORDER BY expires_at, id
+            "#,
+        )
+        .bind(now)
+        .bind(now + window)
+        .bind(self.tenant.as_str())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        let mut batches = rows
+            .into_iter()
+            .map(Batch::try_from)
+            .collect::<Result<Vec<Batch>, _>>()?;
+        load_allocations(&mut conn, &self.tenant, &mut batches).await?;
+        Ok(batches)
+    }
+
+    // Every allocation on a live batch, in the order they were made. Rows
+    // are decoded as they arrive from the database rather than collected, so
+    // exporting doesn't hold the whole table in memory.
+    pub fn stream_allocations(
+        &self,
+    ) -> impl Stream<Item = Result<AllocationRecord, RepositoryError>> + '_
+    {
+        sqlx::query(
+            r#"
+                SELECT allocations.order_ref, allocations.sku,
+                    allocations.qty, allocations.batch_id, batches.eta
+                FROM allocations
+                JOIN batches ON batches.id = allocations.batch_id
+                WHERE batches.deleted_at IS NULL AND batches.tenant_id = $1
+                ORDER BY allocations.id
+            "#,
+        )
+        .bind(self.tenant.as_str())
+        .fetch(&*self.pg_pool)
+        .map(|row| {
+            let row = row?;
+            Ok(AllocationRecord {
+                order_ref: row.get("order_ref"),
+                sku: row.get("sku"),
+                qty: row.get::<i32, _>("qty") as u32,
+                batch_id: row.get("batch_id"),
+                eta: to_local(row.get("eta")),
+            })
+        })
+    }
+
+    // Quantity still free per SKU across its live batches, counted like
+    // `Batch::available_qty`. Allocations and reservations are summed per
+    // batch first so a batch counts once however many it has, and a batch
+    // holding more than it can adds nothing rather than taking from others.
+    pub async fn available_by_sku(
+        &self,
+    ) -> Result<HashMap<String, i64>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        let rows = sqlx::query(
+            r#"
+                SELECT batches.sku,
+                    SUM(GREATEST(
+                        batches.qty + products.over_allocation_buffer
+                            - COALESCE(allocated.qty, 0)
+                            - COALESCE(reserved.qty, 0),
+                        0
+                    ))::BIGINT AS available
+                FROM batches
+                JOIN products ON products.tenant_id = batches.tenant_id
+                AND products.sku = batches.sku
+                LEFT JOIN (
+                    SELECT batch_id, SUM(qty) AS qty FROM allocations
+                    GROUP BY batch_id
+                ) AS allocated ON allocated.batch_id = batches.id
+                LEFT JOIN (
+                    SELECT batch_id, SUM(qty) AS qty FROM reservations
+                    GROUP BY batch_id
+                ) AS reserved ON reserved.batch_id = batches.id
+                WHERE batches.deleted_at IS NULL AND batches.sku IS NOT NULL
+                AND batches.tenant_id = $1
+                GROUP BY batches.sku
+            "#,
+        )
+        .bind(self.tenant.as_str())
+        .fetch_all(&mut *conn)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| (row.get("sku"), row.get("available")))
+            .collect())
+    }
+}
+
+#[async_trait]
+impl ProductRepository for PostgresBatchRepository {
+    async fn get(&self, sku: &str) -> Result<Option<Product>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        Ok(get_product(&mut conn, &self.tenant, sku, false).await?)
+    }
+
+    async fn get_by_batch_id(
+        &self,
+        batch_id: BatchId,
+    ) -> Result<Option<Product>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        match batch_sku(&mut conn, &self.tenant, batch_id).await? {
+            Some(sku) => {
+                Ok(get_product(&mut conn, &self.tenant, &sku, false).await?)
+            }
+            None => Ok(None),
+        }
+    }
+
+    async fn get_by_order_ref(
+        &self,
+        order_ref: &str,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        Ok(
+            get_products_by_order_ref(
+                &mut conn,
+                &self.tenant,
+                order_ref,
+                false,
+            )
+            .await?,
+        )
+    }
+
+    async fn get_with_reservations_before(
+        &self,
+        reserved_before: DateTime<Local>,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut conn = self.pg_pool.acquire().await?;
+        Ok(get_products_with_reservations_before(
+            &mut conn,
+            &self.tenant,
+            reserved_before,
+            false,
+        )
+        .await?)
+    }
+
+    async fn add(&self, product: &Product) -> Result<(), RepositoryError> {
+        let mut tx = self.pg_pool.begin().await?;
+        add_product(&mut tx, &self.tenant, product).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+// Repository bound to a single transaction and tenant, rolled back on drop
+// unless committed
+pub struct PostgresTransactionRepository {
+    tx: Mutex<Option<Transaction<'static, Postgres>>>,
+    tenant: TenantId,
+}
+
+impl PostgresTransactionRepository {
+    pub async fn begin(pg_pool: &PgPool) -> Result<Self, RepositoryError> {
+        Self::begin_with(
+            pg_pool,
+            TenantId::default(),
+            IsolationLevel::default(),
+        )
+        .await
+    }
+
+    // The level is set before anything else runs in the transaction, as
+    // Postgres requires
+    pub async fn begin_with(
+        pg_pool: &PgPool,
+        tenant: TenantId,
+        isolation: IsolationLevel,
+    ) -> Result<Self, RepositoryError> {
+        let mut tx = pg_pool.begin().await?;
+        let statement =
+            format!("SET TRANSACTION ISOLATION LEVEL {}", isolation.as_sql());
+        sqlx::query(&statement).execute(&mut *tx).await?;
+        Ok(Self {
+            tx: Mutex::new(Some(tx)),
+            tenant,
+        })
+    }
+
+    pub fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    pub async fn commit(&self) -> Result<(), RepositoryError> {
+        match self.tx.lock().await.take() {
+            Some(tx) => Ok(tx.commit().await?),
+            None => Err(RepositoryError::Finished),
+        }
+    }
+
+    pub async fn rollback(&self) -> Result<(), RepositoryError> {
+        match self.tx.lock().await.take() {
+            Some(tx) => Ok(tx.rollback().await?),
+            None => Err(RepositoryError::Finished),
+        }
+    }
+
+    pub async fn add_to_outbox(
+        &self,
+        events: &[DomainEvent],
+    ) -> Result<(), RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        outbox::add(tx, &self.tenant, events).await
+    }
+
+    pub async fn add_to_audit_trail(
+        &self,
+        events: &[DomainEvent],
+        actor: &str,
+    ) -> Result<(), RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        audit::add(tx, &self.tenant, events, actor).await
+    }
+}
+
+#[async_trait]
+impl ProductRepository for PostgresTransactionRepository {
+    async fn get(&self, sku: &str) -> Result<Option<Product>, RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        // Lock the SKU so concurrent allocations for it serialize until this
+        // transaction finishes, while other SKUs carry on
+        Ok(get_product(tx, &self.tenant, sku, true).await?)
+    }
+
+    async fn get_by_batch_id(
+        &self,
+        batch_id: BatchId,
+    ) -> Result<Option<Product>, RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        match batch_sku(tx, &self.tenant, batch_id).await? {
+            Some(sku) => Ok(get_product(tx, &self.tenant, &sku, true).await?),
+            None => Ok(None),
+        }
+    }
+
+    // SKUs are locked in order, so two orders sharing them can't deadlock
+    async fn get_by_order_ref(
+        &self,
+        order_ref: &str,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        Ok(
+            get_products_by_order_ref(tx, &self.tenant, order_ref, true)
+                .await?,
+        )
+    }
+
+    async fn get_with_reservations_before(
+        &self,
+        reserved_before: DateTime<Local>,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        Ok(get_products_with_reservations_before(
+            tx,
+            &self.tenant,
+            reserved_before,
+            true,
+        )
+        .await?)
+    }
+
+    async fn add(&self, product: &Product) -> Result<(), RepositoryError> {
+        let mut tx = self.tx.lock().await;
+        let tx = tx.as_mut().ok_or(RepositoryError::Finished)?;
+        add_product(tx, &self.tenant, product).await
+    }
+}
+
+// With `lock`, takes a transaction-scoped advisory lock keyed on the tenant
+// and SKU first. Unlike a row lock it also covers products that don't exist
+// yet. Keys whose hashes collide merely share a lock.
+async fn get_product(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    sku: &str,
+    lock: bool,
+) -> Result<Option<Product>, RepositoryError> {
+    let Ok(sku) = Sku::try_from(sku) else {
+        return Ok(None);
+    };
+    let sku = sku.as_str();
+    if lock {
+        sqlx::query(
+            r#"SELECT pg_advisory_xact_lock(hashtext($1), hashtext($2))"#,
+        )
+        .bind(tenant.as_str())
+        .bind(sku)
+        .execute(&mut *conn)
+        .await?;
+    }
+    let row = sqlx::query(
+        r#"
+            SELECT version_number, last_reservation_id,
+                over_allocation_buffer
+            FROM products
+            WHERE tenant_id = $1 AND sku = $2
+        "#,
+    )
+    .bind(tenant.as_str())
+    .bind(sku)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(row) = row else {
+        return Ok(None);
+    };
+    let version_number: i32 = row.get("version_number");
+
+    let rows = sqlx::query_as::<_, BatchRow>(
+        r#"
+            SELECT id, reference, sku, qty, eta, deleted_at, expires_at,
+                warehouse_id
+            FROM batches
+            WHERE tenant_id = $1 AND sku = $2 AND deleted_at IS NULL
+            ORDER BY id
+        "#,
+    )
+    .bind(tenant.as_str())
+    .bind(sku)
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut batches = rows
+        .into_iter()
+        .map(Batch::try_from)
+        .collect::<Result<Vec<Batch>, _>>()?;
+    load_allocations(conn, tenant, &mut batches).await?;
+
+    let mut product = Product::new(sku.to_string(), batches)?;
+    product.version_number = version_number;
+    product.persisted_version = Some(version_number);
+    product.last_reservation_id = product
+        .last_reservation_id
+        .max(row.get::<i32, _>("last_reservation_id") as u32);
+    product.set_over_allocation_buffer(
+        row.get::<i32, _>("over_allocation_buffer") as u32,
+    );
+    Ok(Some(product))
+}
+
+async fn get_products_by_order_ref(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    order_ref: &str,
+    lock: bool,
+) -> Result<Vec<Product>, RepositoryError> {
+    let skus: Vec<String> = sqlx::query_scalar(
+        r#"
+            SELECT DISTINCT batches.sku FROM allocations
+            JOIN batches ON batches.id = allocations.batch_id
+            WHERE allocations.order_ref = $1 AND batches.tenant_id = $2
+            AND batches.deleted_at IS NULL AND batches.sku IS NOT NULL
+            ORDER BY batches.sku
+        "#,
+    )
+    .bind(order_ref)
+    .bind(tenant.as_str())
+    .fetch_all(&mut *conn)
+    .await?;
+    get_products(conn, tenant, skus, lock).await
+}
+
+async fn get_products_with_reservations_before(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    reserved_before: DateTime<Local>,
+    lock: bool,
+) -> Result<Vec<Product>, RepositoryError> {
+    let skus: Vec<String> = sqlx::query_scalar(
+        r#"
+            SELECT DISTINCT sku FROM reservations
+            WHERE reserved_at < $1 AND tenant_id = $2
+            ORDER BY sku
+        "#,
+    )
+    .bind(reserved_before)
+    .bind(tenant.as_str())
+    .fetch_all(&mut *conn)
+    .await?;
+    get_products(conn, tenant, skus, lock).await
+}
+
+// `skus` must be sorted when locking, so two callers can't deadlock
+async fn get_products(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    skus: Vec<String>,
+    lock: bool,
+) -> Result<Vec<Product>, RepositoryError> {
+    let mut products = Vec::new();
+    for sku in skus {
+        products.extend(get_product(conn, tenant, &sku, lock).await?);
+    }
+    Ok(products)
+}
+
+async fn batch_sku(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    batch_id: BatchId,
+) -> Result<Option<String>, RepositoryError> {
+    let sku: Option<Option<String>> = sqlx::query_scalar(
+        r#"
+            SELECT sku FROM batches
+            WHERE id = $1 AND tenant_id = $2 AND deleted_at IS NULL
+        "#,
+    )
+    .bind(batch_id)
+    .bind(tenant.as_str())
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(sku.flatten())
+}
+
+// A loaded product is only saved if the stored version is still the one
+// it was loaded with, and a new one only if none is stored yet. The version
+// goes up on every save.
+async fn add_product(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    product: &Product,
+) -> Result<(), RepositoryError> {
+    match product.persisted_version {
+        Some(expected_version) => {
+            let rows_affected = sqlx::query(
+                r#"
+                    UPDATE products
+                    SET version_number = GREATEST($3, $2 + 1),
+                        last_reservation_id = $4,
+                        over_allocation_buffer = $5
+                    WHERE sku = $1 AND version_number = $2 AND tenant_id = $6
+                "#,
+            )
+            .bind(product.sku.as_str())
+            .bind(expected_version)
+            .bind(product.version_number)
+            .bind(product.last_reservation_id as i32)
+            .bind(product.over_allocation_buffer as i32)
+            .bind(tenant.as_str())
+            .execute(&mut *conn)
+            .await?
+            .rows_affected();
+            if rows_affected == 0 {
+                return Err(ConcurrencyError {
+                    sku: product.sku.to_string(),
+                    expected_version,
+                }
+                .into());
+            }
+        }
+        None => {
+            sqlx::query(
+                r#"
+                    INSERT INTO products (
+                        sku, version_number, last_reservation_id,
+                        over_allocation_buffer, tenant_id
+                    )
+                    VALUES ( $1, $2, $3, $4, $5 )
+                "#,
+            )
+            .bind(product.sku.as_str())
+            .bind(product.version_number)
+            .bind(product.last_reservation_id as i32)
+            .bind(product.over_allocation_buffer as i32)
+            .bind(tenant.as_str())
+            .execute(&mut *conn)
+            .await
+            .map_err(|err| {
+                // Someone else stored the product since it was found missing
+                let stored_first = err
+                    .as_database_error()
+                    .is_some_and(|err| err.is_unique_violation());
+                if stored_first {
+                    return ConcurrencyError {
+                        sku: product.sku.to_string(),
+                        expected_version: 0,
+                    }
+                    .into();
+                }
+                RepositoryError::from(err)
+            })?;
+        }
+    }
+
+    // Batches removed from the product are soft-deleted
+    let ids: Vec<BatchId> = product
+        .batches
+        .iter()
+        .filter_map(|batch| batch.id)
+        .collect();
+    let removed: Vec<BatchId> = sqlx::query_scalar(
+        r#"
+            SELECT id FROM batches
+            WHERE sku = $1 AND id <> ALL($2) AND deleted_at IS NULL
+            AND tenant_id = $3
+        "#,
+    )
+    .bind(product.sku.as_str())
+    .bind(&ids)
+    .bind(tenant.as_str())
+    .fetch_all(&mut *conn)
+    .await?;
+    soft_delete_batches(conn, tenant, &removed).await?;
+
+    for batch in &product.batches {
+        match batch.id {
+            Some(id) => {
+                update_batch(conn, tenant, id, batch).await?;
+            }
+            None => {
+                insert_batch(conn, tenant, batch).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn soft_delete_batches(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    ids: &[BatchId],
+) -> Result<(), RepositoryError> {
+    sqlx::query(
+        r#"
+            UPDATE batches SET deleted_at = now()
+            WHERE id = ANY($1) AND tenant_id = $2
+        "#,
+    )
+    .bind(ids)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM allocations WHERE batch_id = ANY($1) AND tenant_id = $2"#,
+    )
+    .bind(ids)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM reservations WHERE batch_id = ANY($1) AND tenant_id = $2"#,
+    )
+    .bind(ids)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn insert_batch(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    batch: &Batch,
+) -> Result<BatchId, RepositoryError> {
+    ensure_product(conn, tenant, batch.sku.as_str()).await?;
+    let id: BatchId = sqlx::query_scalar(
+        r#"
+            INSERT INTO batches (
+                reference, sku, qty, eta, expires_at, warehouse_id, tenant_id
+            )
+            VALUES ( $1, $2, $3, $4, $5, $6, $7 )
+            RETURNING id
+        "#,
+    )
+    .bind(&batch.reference)
+    .bind(batch.sku.as_str())
+    .bind(i32::try_from(batch.qty)?)
+    .bind(to_utc(batch.eta))
+    .bind(to_utc(batch.expires_at))
+    .bind(&batch.warehouse_id)
+    .bind(tenant.as_str())
+    .fetch_one(&mut *conn)
+    .await?;
+
+    insert_allocations(conn, tenant, id, batch).await?;
+    Ok(id)
+}
+
+// Batches of other tenants are left alone, as if they didn't exist
+async fn update_batch(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    id: BatchId,
+    batch: &Batch,
+) -> Result<bool, RepositoryError> {
+    ensure_product(conn, tenant, batch.sku.as_str()).await?;
+    let rows_affected = sqlx::query(
+        r#"
+            UPDATE batches
+            SET reference = $1, sku = $2, qty = $3, eta = $4, expires_at = $5,
+                warehouse_id = $6
+            WHERE id = $7 AND tenant_id = $8
+        "#,
+    )
+    .bind(&batch.reference)
+    .bind(batch.sku.as_str())
+    .bind(i32::try_from(batch.qty)?)
+    .bind(to_utc(batch.eta))
+    .bind(to_utc(batch.expires_at))
+    .bind(&batch.warehouse_id)
+    .bind(id)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?
+    .rows_affected();
+    if rows_affected == 0 {
+        return Ok(false);
+    }
+
+    // Allocations and reservations are owned by the batch, so replace them
+    // wholesale
+    sqlx::query(
+        r#"DELETE FROM allocations WHERE batch_id = $1 AND tenant_id = $2"#,
+    )
+    .bind(id)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    sqlx::query(
+        r#"DELETE FROM reservations WHERE batch_id = $1 AND tenant_id = $2"#,
+    )
+    .bind(id)
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    insert_allocations(conn, tenant, id, batch).await?;
+
+    Ok(true)
+}
+
+async fn ensure_product(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    sku: &str,
+) -> Result<(), RepositoryError> {
+    sqlx::query(
+        r#"
+            INSERT INTO products (tenant_id, sku) VALUES ( $1, $2 )
+            ON CONFLICT DO NOTHING
+        "#,
+    )
+    .bind(tenant.as_str())
+    .bind(sku)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+async fn insert_allocations(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    batch_id: BatchId,
+    batch: &Batch,
+) -> Result<(), RepositoryError> {
+    for line in &batch.allocated {
+        sqlx::query(
+            r#"
+                INSERT INTO allocations
+                    (batch_id, order_ref, sku, qty, tenant_id)
+                VALUES ( $1, $2, $3, $4, $5 )
+            "#,
+        )
+        .bind(batch_id)
+        .bind(&line.order_ref)
+        .bind(line.sku.as_str())
+        .bind(i32::try_from(line.qty)?)
+        .bind(tenant.as_str())
+        .execute(&mut *conn)
+        .await?;
+    }
+    insert_reservations(conn, tenant, batch_id, batch).await
+}
+
+async fn insert_reservations(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    batch_id: BatchId,
+    batch: &Batch,
+) -> Result<(), RepositoryError> {
+    for reservation in &batch.reserved {
+        sqlx::query(
+            r#"
+                INSERT INTO reservations
+                    (sku, id, batch_id, order_ref, qty, reserved_at, tenant_id)
+                VALUES ( $1, $2, $3, $4, $5, $6, $7 )
+            "#,
+        )
+        .bind(reservation.line.sku.as_str())
+        .bind(reservation.id.0 as i32)
+        .bind(batch_id)
+        .bind(&reservation.line.order_ref)
+        .bind(i32::try_from(reservation.line.qty)?)
+        .bind(reservation.reserved_at)
+        .bind(tenant.as_str())
+        .execute(&mut *conn)
+        .await?;
+    }
+    Ok(())
+}
+
+async fn load_allocations(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    batches: &mut [Batch],
+) -> Result<(), RepositoryError> {
+    let ids: Vec<BatchId> =
+        batches.iter().filter_map(|batch| batch.id).collect();
+    let mut lines: HashMap<BatchId, Vec<OrderLine>> = HashMap::new();
+    let rows = sqlx::query(
+        r#"
+            SELECT batch_id, order_ref, sku, qty
+            FROM allocations
+            WHERE batch_id = ANY($1) AND tenant_id = $2
+            ORDER BY id
+        "#,
+    )
+    .bind(&ids)
+    .bind(tenant.as_str())
+    .fetch_all(&mut *conn)
+    .await?;
+    for row in rows {
+        let line = OrderLine {
+            id: None,
+            order_ref: row.get("order_ref"),
+            sku: Sku::try_from(row.get::<String, _>("sku"))?,
+            qty: row.get::<i32, _>("qty") as u32,
+        };
+        lines
+            .entry(row.get::<BatchId, _>("batch_id"))
+            .or_default()
+            .push(line);
+    }
+
+    for batch in batches.iter_mut() {
+        if let Some(lines) = batch.id.and_then(|id| lines.remove(&id)) {
+            batch.allocated.extend(lines);
+        }
+    }
+
+    let rows = sqlx::query(
+        r#"
+            SELECT id, batch_id, order_ref, sku, qty, reserved_at
+            FROM reservations
+            WHERE batch_id = ANY($1) AND tenant_id = $2
+            ORDER BY id
+        "#,
+    )
+    .bind(&ids)
+    .bind(tenant.as_str())
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut reservations: HashMap<BatchId, Vec<Reservation>> = HashMap::new();
+    for row in rows {
+        let reservation = Reservation {
+            id: ReservationId(row.get::<i32, _>("id") as u32),
+            line: OrderLine {
+                id: None,
+                order_ref: row.get("order_ref"),
+                sku: Sku::try_from(row.get::<String, _>("sku"))?,
+                qty: row.get::<i32, _>("qty") as u32,
+            },
+            reserved_at: row.get("reserved_at"),
+        };
+        reservations
+            .entry(row.get::<BatchId, _>("batch_id"))
+            .or_default()
+            .push(reservation);
+    }
+    for batch in batches.iter_mut() {
+        if let Some(held) = batch.id.and_then(|id| reservations.remove(&id)) {
+            batch.reserved.extend(held);
+        }
+    }
+    Ok(())
+}
+
+// Batch times are written and read as UTC, so what is stored doesn't depend
+// on the timezone of the process. They are only turned into `Local` times on
+// the way out.
+fn to_utc(at: Option<DateTime<Local>>) -> Option<DateTime<Utc>> {
+    at.map(|at| at.with_timezone(&Utc))
+}
+
+fn to_local(at: Option<DateTime<Utc>>) -> Option<DateTime<Local>> {
+    at.map(|at| at.with_timezone(&Local))
+}
+
+// A `batches` row as it is stored, apart from the tenant
+#[derive(Debug, Clone, PartialEq, Eq, sqlx::FromRow)]
+struct BatchRow {
+    id: BatchId,
+    reference: Option<String>,
+    sku: Option<String>,
+    qty: i32,
+    eta: Option<DateTime<Utc>>,
+    deleted_at: Option<DateTime<Utc>>,
+    expires_at: Option<DateTime<Utc>>,
+    warehouse_id: Option<String>,
+}
+
+// Rows are rebuilt field by field rather than through `Batch::new`, which
+// only takes the SKU, quantity and ETA. The quantity is already kept
+// positive by the table, and the allocations and reservations are loaded
+// separately.
+impl TryFrom<BatchRow> for Batch {
+    type Error = DomainError;
+
+    fn try_from(row: BatchRow) -> Result<Self, Self::Error> {
+        Ok(Batch {
+            id: Some(row.id),
+            reference: row.reference,
+            sku: Sku::try_from(row.sku.unwrap_or_default())?,
+            qty: row.qty as u32,
+            eta: to_local(row.eta),
+            deleted_at: to_local(row.deleted_at),
+            expires_at: to_local(row.expires_at),
+            allocated: IndexSet::new(),
+            reserved: Vec::new(),
+            over_allocation_buffer: 0,
+            warehouse_id: row.warehouse_id,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod fixtures {
+    use super::*;
+
+    // Saves a product with one in-stock batch of `qty`
+    pub async fn seed_batch(pg_pool: &PgPool, sku: &str, qty: u32) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batch = Batch::new(sku.to_string(), qty, None).unwrap();
+        let product = Product::new(sku.to_string(), vec![batch]).unwrap();
+        repo.add(&product).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::clock::FixedClock;
+    use crate::domain::model::AllocationError;
+    use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+
+    #[sqlx::test]
+    async fn test_create_and_read(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let mut batch = Batch::new("TEST".to_string(), 10, None).unwrap();
+        batch.reference = Some("BATCH_1".to_string());
+
+        let id = repo.create_batch(&batch).await.unwrap();
+        let stored = repo.read_batch(id, false).await.unwrap();
+
+        assert_eq!(BatchId(1), id);
+        assert_eq!(stored.id, Some(BatchId(1)));
+        assert_eq!(stored.reference, Some("BATCH_1".to_string()));
+        assert_eq!(stored.sku, "TEST");
+        assert_eq!(stored.qty, 10);
+        assert_eq!(stored.eta, None);
+    }
+
+    #[sqlx::test]
+    async fn test_quantities_past_i32_are_refused(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batch =
+            Batch::new("SMALL_TABLE".to_string(), 3_000_000_000, None).unwrap();
+
+        let err = repo.create_batch(&batch).await.unwrap_err();
+
+        assert!(matches!(err, RepositoryError::OutOfRange(_)), "{err:?}");
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM batches")
+            .fetch_one(&pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 0);
+        let zero = sqlx::query(
+            "INSERT INTO batches (tenant_id, qty) VALUES ('default', 0)",
+        )
+        .execute(&pg_pool)
+        .await
+        .unwrap_err();
+        assert!(zero.to_string().contains("batches_qty_positive"), "{zero}");
+    }
+
+    #[sqlx::test]
+    async fn test_products_are_found_by_any_spelling_of_the_sku(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batch = Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap();
+        repo.create_batch(&batch).await.unwrap();
+
+        assert!(repo.get(" small_table").await.unwrap().is_some());
+        assert!(repo.get("").await.unwrap().is_none());
+        let tx = PostgresTransactionRepository::begin_with(
+            &pg_pool,
+            TenantId::default(),
+            IsolationLevel::default(),
+        )
+        .await
+        .unwrap();
+        assert!(tx.get("small_table").await.unwrap().is_some());
+        let listed = repo
+            .list_batches(Some("small_table"), None, None, 10, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_batch_row_round_trips_to_a_batch(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let eta = DateTime::parse_from_rfc3339("2024-06-01T10:00:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let mut batch =
+            Batch::new("SMALL_TABLE".to_string(), 20, to_local(Some(eta)))
+                .unwrap();
+        batch.reference = Some("BATCH_1".to_string());
+        batch.warehouse_id = Some("WAREHOUSE_1".to_string());
+        let id = repo.create_batch(&batch).await.unwrap();
+
+        let row = sqlx::query_as::<_, BatchRow>(
+            r#"
+                SELECT id, reference, sku, qty, eta, deleted_at, expires_at,
+                    warehouse_id
+                FROM batches WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_one(&pg_pool)
+        .await
+        .unwrap();
+
+        assert_eq!(
+            row,
+            BatchRow {
+                id,
+                reference: Some("BATCH_1".to_string()),
+                sku: Some("SMALL_TABLE".to_string()),
+                qty: 20,
+                eta: Some(eta),
+                deleted_at: None,
+                expires_at: None,
+                warehouse_id: Some("WAREHOUSE_1".to_string()),
+            }
+        );
+        batch.id = Some(id);
+        assert_eq!(Batch::try_from(row.clone()).unwrap(), batch);
+        assert!(Batch::try_from(BatchRow { sku: None, ..row }).is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_create_and_read_batch_with_allocations(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let eta = Local::now() + Duration::days(1);
+
+        let mut batch = Batch::new(sku.clone(), 20, Some(eta)).unwrap();
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        let line2 =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 5).unwrap();
+        batch.allocate(&line1).unwrap();
+        batch.allocate(&line2).unwrap();
+
+        let id = repo.create_batch(&batch).await.unwrap();
+        let stored = repo.read_batch(id, false).await.unwrap();
+
+        batch.id = Some(id);
+        assert_eq!(stored.qty, 20);
+        // Postgres keeps microsecond precision
+        assert_eq!(
+            stored.eta.unwrap().timestamp_micros(),
+            eta.timestamp_micros()
+        );
+        assert_eq!(stored.allocated, batch.allocated);
+        assert_eq!(stored.available_qty(), 13);
+    }
+
+    #[sqlx::test]
+    async fn test_read_missing_batch_errors(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+
+        assert!(repo.read_batch(BatchId(42), false).await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_add_and_get_product(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let product = Product::new(
+            sku.clone(),
+            vec![
+                Batch::new(sku.clone(), 10, None).unwrap(),
+                Batch::new(sku.clone(), 20, None).unwrap(),
+            ],
+        )
+        .unwrap();
+
+        repo.add(&product).await.unwrap();
+        let mut stored = repo.get(&sku).await.unwrap().unwrap();
+
+        assert_eq!(stored.sku, sku);
+        assert_eq!(stored.batches.len(), 2);
+        assert_eq!(stored.batches[0].qty, 10);
+        assert_eq!(stored.batches[1].qty, 20);
+        assert_eq!(repo.get("BIG_TABLE").await.unwrap(), None);
+
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 4).unwrap();
+        let batch_id = stored.allocate(&line, None).unwrap();
+        repo.add(&stored).await.unwrap();
+
+        let reloaded = repo.get(&sku).await.unwrap().unwrap();
+        let batch = reloaded
+            .batches
+            .iter()
+            .find(|batch| batch.id == Some(batch_id))
+            .unwrap();
+        assert!(batch.allocated.contains(&line));
+        assert_eq!(batch.available_qty(), 6);
+
+        let by_batch = repo.get_by_batch_id(batch_id).await.unwrap();
+        assert_eq!(by_batch, Some(reloaded));
+        assert_eq!(repo.get_by_batch_id(BatchId(42)).await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn test_available_by_sku_subtracts_allocations_and_reservations(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let table = "SMALL_TABLE".to_string();
+        let lamp = "BLUE_LAMP".to_string();
+        let mut tables = Product::new(
+            table.clone(),
+            vec![
+                Batch::new(table.clone(), 10, None).unwrap(),
+                Batch::new(table.clone(), 5, Some(Local::now())).unwrap(),
+            ],
+        )
+        .unwrap();
+        let lamps = Product::new(
+            lamp.clone(),
+            vec![Batch::new(lamp.clone(), 7, None).unwrap()],
+        )
+        .unwrap();
+        repo.add(&tables).await.unwrap();
+        repo.add(&lamps).await.unwrap();
+
+        tables = repo.get(&table).await.unwrap().unwrap();
+        for (order_ref, qty) in [("ORDER_1", 3), ("ORDER_2", 4)] {
+            let line =
+                OrderLine::new(order_ref.to_string(), table.clone(), qty)
+                    .unwrap();
+            tables.allocate(&line, None).unwrap();
+        }
+        let line =
+            OrderLine::new("ORDER_3".to_string(), table.clone(), 2).unwrap();
+        tables.reserve(&line).unwrap();
+        repo.add(&tables).await.unwrap();
+
+        let available = repo.available_by_sku().await.unwrap();
+        assert_eq!(available[&table], 6);
+        assert_eq!(available[&lamp], 7);
+
+        // Over-allocated, the first batch is left with nothing free
+        sqlx::query("UPDATE batches SET qty = 1 WHERE id = $1")
+            .bind(tables.batches[0].id)
+            .execute(&pg_pool)
+            .await
+            .unwrap();
+        let available = repo.available_by_sku().await.unwrap();
+        assert_eq!(available, HashMap::from([(table, 5), (lamp, 7)]));
+    }
+
+    #[sqlx::test]
+    async fn test_get_by_order_ref_finds_every_product(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        for sku in ["SMALL_TABLE", "BLUE_LAMP", "RED_CHAIR"] {
+            let batch = Batch::new(sku.to_string(), 10, None).unwrap();
+            repo.add(&Product::new(sku.to_string(), vec![batch]).unwrap())
+                .await
+                .unwrap();
+        }
+        for sku in ["SMALL_TABLE", "BLUE_LAMP"] {
+            let mut product = repo.get(sku).await.unwrap().unwrap();
+            let line =
+                OrderLine::new("ORDER_1".to_string(), sku.to_string(), 2)
+                    .unwrap();
+            product.allocate(&line, None).unwrap();
+            repo.add(&product).await.unwrap();
+        }
+
+        let products = repo.get_by_order_ref("ORDER_1").await.unwrap();
+
+        let skus: Vec<&str> = products
+            .iter()
+            .map(|product| product.sku.as_str())
+            .collect();
+        assert_eq!(skus, vec!["BLUE_LAMP", "SMALL_TABLE"]);
+        assert!(repo.get_by_order_ref("ORDER_2").await.unwrap().is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_reservations_are_stored_with_the_product(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let batch = Batch::new(sku.clone(), 10, None).unwrap();
+        repo.add(&Product::new(sku.clone(), vec![batch]).unwrap())
+            .await
+            .unwrap();
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        for order_ref in ["ORDER_1", "ORDER_2"] {
+            let line =
+                OrderLine::new(order_ref.to_string(), sku.clone(), 3).unwrap();
+            product.reserve(&line).unwrap();
+        }
+        product.release(ReservationId(2)).unwrap();
+        repo.add(&product).await.unwrap();
+
+        let mut stored = repo.get(&sku).await.unwrap().unwrap();
+
+        let reserved = &stored.batches[0].reserved;
+        assert_eq!(reserved.len(), 1);
+        assert_eq!(reserved[0].id, ReservationId(1));
+        assert_eq!(reserved[0].line.order_ref, "ORDER_1");
+        assert_eq!(stored.batches[0].available_qty(), 7);
+        let line = OrderLine::new("ORDER_3".to_string(), sku, 1).unwrap();
+        assert_eq!(stored.reserve(&line), Ok(ReservationId(3)));
+    }
+
+    #[sqlx::test]
+    async fn test_saving_a_stale_product_is_a_conflict(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let batch = Batch::new(sku.clone(), 10, None).unwrap();
+        repo.add(&Product::new(sku.clone(), vec![batch]).unwrap())
+            .await
+            .unwrap();
+        let mut first = repo.get(&sku).await.unwrap().unwrap();
+        let mut stale = repo.get(&sku).await.unwrap().unwrap();
+
+        first
+            .allocate(
+                &OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap(),
+                None,
+            )
+            .unwrap();
+        repo.add(&first).await.unwrap();
+        stale
+            .allocate(
+                &OrderLine::new("ORDER_2".to_string(), sku.clone(), 3).unwrap(),
+                None,
+            )
+            .unwrap();
+        let err = repo.add(&stale).await.unwrap_err();
+
+        let RepositoryError::Concurrency(err) = err else {
+            panic!("expected a concurrency error, got {}", err);
+        };
+        assert_eq!(
+            err,
+            ConcurrencyError {
+                sku: sku.clone(),
+                expected_version: 0,
+            }
+        );
+        let stored = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(stored.version_number, 1);
+        assert_eq!(stored.batches[0].available_qty(), 8);
+        let unsaved = Product::new(sku.clone(), vec![]).unwrap();
+        let err = repo.add(&unsaved).await.unwrap_err();
+        assert!(matches!(err, RepositoryError::Concurrency(_)), "{err:?}");
+        let stored = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(stored.version_number, 1);
+        assert_eq!(stored.batches.len(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_rows_failing_validation_are_corrupt(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let batch = Batch::new(sku.clone(), 10, None).unwrap();
+        repo.add(&Product::new(sku.clone(), vec![batch]).unwrap())
+            .await
+            .unwrap();
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        product
+            .allocate(
+                &OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap(),
+                None,
+            )
+            .unwrap();
+        repo.add(&product).await.unwrap();
+        sqlx::query("UPDATE allocations SET sku = ' '")
+            .execute(&pg_pool)
+            .await
+            .unwrap();
+
+        let err = repo.get(&sku).await.unwrap_err();
+
+        assert!(matches!(
+            err,
+            RepositoryError::Corrupt(AllocationError::Invalid(
+                DomainError::EmptySku
+            ))
+        ));
+    }
+
+    #[sqlx::test]
+    async fn test_list_batches_pages_by_eta(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        for days in [3, 1, 2] {
+            let eta = Some(Local::now() + Duration::days(days));
+            let batch = Batch::new(sku.clone(), days as u32, eta).unwrap();
+            repo.create_batch(&batch).await.unwrap();
+        }
+        repo.create_batch(&Batch::new(sku.clone(), 10, None).unwrap())
+            .await
+            .unwrap();
+        repo.create_batch(
+            &Batch::new("BLUE_LAMP".to_string(), 5, None).unwrap(),
+        )
+        .await
+        .unwrap();
+
+        let first = repo
+            .list_batches(Some(&sku), None, None, 2, 0, false)
+            .await
+            .unwrap();
+        let second = repo
+            .list_batches(Some(&sku), None, None, 2, 2, false)
+            .await
+            .unwrap();
+
+        let qtys = |batches: &[Batch]| -> Vec<u32> {
+            batches.iter().map(|batch| batch.qty).collect()
+        };
+        assert_eq!(qtys(&first), vec![10, 1]);
+        assert_eq!(qtys(&second), vec![2, 3]);
+        assert!(second.iter().all(|batch| !first.contains(batch)));
+        assert_eq!(
+            repo.list_batches(None, None, None, 10, 0, false)
+                .await
+                .unwrap()
+                .len(),
+            5
+        );
+    }
+
+    async fn allocation_rows(pg_pool: &PgPool, batch_id: BatchId) -> i64 {
+        sqlx::query_scalar(
+            r#"SELECT COUNT(*) FROM allocations WHERE batch_id = $1"#,
+        )
+        .bind(batch_id)
+        .fetch_one(pg_pool)
+        .await
+        .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_add_product_deletes_removed_batches(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        batch.allocate(&line).unwrap();
+        let product = Product::new(
+            sku.clone(),
+            vec![batch, Batch::new(sku.clone(), 20, None).unwrap()],
+        )
+        .unwrap();
+        repo.add(&product).await.unwrap();
+
+        let mut stored = repo.get(&sku).await.unwrap().unwrap();
+        let batch_id = stored.batches[0].id.unwrap();
+        assert_eq!(stored.remove_batch(batch_id).unwrap(), vec![line]);
+        repo.add(&stored).await.unwrap();
+
+        let reloaded = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(reloaded.batches.len(), 1);
+        assert_eq!(reloaded.batches[0].qty, 20);
+        assert_eq!(allocation_rows(&pg_pool, batch_id).await, 0);
+        assert!(repo.read_batch(batch_id, false).await.is_err());
+        let deleted = repo.read_batch(batch_id, true).await.unwrap();
+        assert!(deleted.deleted_at.is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_add_batches_inserts_in_bulk(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batches: Vec<Batch> = (1..=1000)
+            .map(|qty| {
+                let sku = format!("SKU_{}", qty % 10);
+                Batch::new(sku, qty, None).unwrap()
+            })
+            .collect();
+
+        let ids = repo.add_batches(&batches).await.unwrap();
+
+        let count: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM batches"#)
+            .fetch_one(&pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(count, 1000);
+        assert_eq!(ids.len(), 1000);
+        for (batch, id) in batches.iter().zip(ids).step_by(97) {
+            assert_eq!(
+                repo.read_batch(id, false).await.unwrap().qty,
+                batch.qty
+            );
+        }
+        assert!(repo.get("SKU_3").await.unwrap().is_some());
+    }
+
+    #[sqlx::test]
+    async fn test_add_batches_keeps_allocations(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 20, None).unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        batch.allocate(&line).unwrap();
+
+        let ids = repo
+            .add_batches(&[Batch::new(sku, 5, None).unwrap(), batch])
+            .await
+            .unwrap();
+
+        assert!(repo
+            .read_batch(ids[0], false)
+            .await
+            .unwrap()
+            .allocated
+            .is_empty());
+        assert!(repo
+            .read_batch(ids[1], false)
+            .await
+            .unwrap()
+            .allocated
+            .contains(&line));
+    }
+
+    #[sqlx::test]
+    async fn test_soft_deleted_batch_is_hidden_unless_asked_for(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let kept = repo
+            .create_batch(&Batch::new(sku.clone(), 10, None).unwrap())
+            .await
+            .unwrap();
+        let deleted = repo
+            .create_batch(&Batch::new(sku.clone(), 20, None).unwrap())
+            .await
+            .unwrap();
+
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        product.remove_batch(deleted).unwrap();
+        repo.add(&product).await.unwrap();
+
+        assert!(repo.read_batch(deleted, false).await.is_err());
+        let stored = repo.read_batch(deleted, true).await.unwrap();
+        assert_eq!(stored.qty, 20);
+        assert!(stored.deleted_at.is_some());
+
+        let listed = repo
+            .list_batches(Some(&sku), None, None, 10, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].id, Some(kept));
+        let all = repo
+            .list_batches(Some(&sku), None, None, 10, 0, true)
+            .await
+            .unwrap();
+        assert_eq!(all.len(), 2);
+
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(product.batches.len(), 1);
+        assert_eq!(repo.get_by_batch_id(deleted).await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn test_find_allocation_by_order_ref(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        for qty in [1, 20] {
+            repo.create_batch(&Batch::new(sku.clone(), qty, None).unwrap())
+                .await
+                .unwrap();
+        }
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 5).unwrap();
+        let batch_id = product.allocate(&line, None).unwrap();
+        repo.add(&product).await.unwrap();
+
+        assert_eq!(
+            repo.find_allocation("ORDER_1").await.unwrap(),
+            Some((sku, batch_id))
+        );
+        assert_eq!(repo.find_allocation("ORDER_2").await.unwrap(), None);
+    }
+
+    #[sqlx::test]
+    async fn test_list_batches_within_eta_window(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let today = Local::now();
+        for (qty, days) in
+            [(1, None), (2, Some(1)), (3, Some(5)), (4, Some(10))]
+        {
+            let eta = days.map(|days| today + Duration::days(days));
+            repo.create_batch(&Batch::new(sku.clone(), qty, eta).unwrap())
+                .await
+                .unwrap();
+        }
+        let qtys = |batches: Vec<Batch>| -> Vec<u32> {
+            batches.iter().map(|batch| batch.qty).collect()
+        };
+
+        let window = repo
+            .list_batches(
+                Some(&sku),
+                Some(today),
+                Some(today + Duration::days(5)),
+                10,
+                0,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(qtys(window), vec![2, 3]);
+
+        let until = repo
+            .list_batches(
+                Some(&sku),
+                None,
+                Some(today + Duration::days(2)),
+                10,
+                0,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(qtys(until), vec![1, 2]);
+
+        let from = repo
+            .list_batches(
+                Some(&sku),
+                Some(today + Duration::days(2)),
+                None,
+                10,
+                0,
+                false,
+            )
+            .await
+            .unwrap();
+        assert_eq!(qtys(from), vec![3, 4]);
+    }
+
+    #[sqlx::test]
+    async fn test_expiring_within_lists_batches_expiring_soonest_first(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let now = Local::now();
+        let clock = FixedClock(now);
+        for (qty, days) in [
+            (1, None),
+            (2, Some(3)),
+            (3, Some(-1)),
+            (4, Some(1)),
+            (5, Some(9)),
+        ] {
+            let builder = Batch::builder().sku("SMALL_TABLE").qty(qty);
+            let batch = match days {
+                Some(days) => builder.expires_at(now + Duration::days(days)),
+                None => builder,
+            };
+            repo.create_batch(&batch.build().unwrap()).await.unwrap();
+        }
+
+        let expiring = repo
+            .expiring_within(Duration::days(7), &clock)
+            .await
+            .unwrap();
+
+        let qtys: Vec<u32> = expiring.iter().map(|batch| batch.qty).collect();
+        assert_eq!(qtys, vec![4, 2]);
+        assert_eq!(
+            expiring[0].expires_at.unwrap().timestamp_micros(),
+            (now + Duration::days(1)).timestamp_micros()
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_stream_allocations_yields_every_allocation(
+        pool_options: PgPoolOptions,
+        connect_options: PgConnectOptions,
+    ) {
+        const ORDERS: u32 = 500;
+        let pg_pool = pool_options
+            .max_connections(1)
+            .acquire_timeout(std::time::Duration::from_millis(200))
+            .connect_with(connect_options)
+            .await
+            .unwrap();
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let eta = Local::now() + Duration::days(1);
+        repo.create_batch(&Batch::new(sku.clone(), ORDERS, Some(eta)).unwrap())
+            .await
+            .unwrap();
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        for order in 0..ORDERS {
+            let order_ref = format!("ORDER_{order:04}");
+            let line = OrderLine::new(order_ref, sku.clone(), 1).unwrap();
+            product.allocate(&line, None).unwrap();
+        }
+        repo.add(&product).await.unwrap();
+
+        let mut stream = Box::pin(repo.stream_allocations());
+        let first = stream.next().await.unwrap().unwrap();
+        assert!(first.order_ref.starts_with("ORDER_"));
+        assert_eq!(first.sku, sku);
+        assert_eq!(first.qty, 1);
+        assert_eq!(first.batch_id, BatchId(1));
+        assert_eq!(
+            first.eta.map(|eta| eta.timestamp_micros()),
+            Some(eta.timestamp_micros())
+        );
+
+        // Rows aren't collected up front, the query still holds the only
+        // connection while the first record is in hand
+        assert!(pg_pool.acquire().await.is_err());
+
+        let mut rest = 0;
+        while let Some(record) = stream.next().await {
+            record.unwrap();
+            rest += 1;
+        }
+        assert_eq!(rest + 1, ORDERS);
+        drop(stream);
+        assert!(pg_pool.acquire().await.is_ok());
+    }
+
+    #[sqlx::test]
+    async fn test_over_allocation_buffer_is_stored_with_the_product(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let batch = Batch::new(sku.clone(), 10, None).unwrap();
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        product.set_over_allocation_buffer(3);
+        repo.add(&product).await.unwrap();
+
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(product.over_allocation_buffer, 3);
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 12).unwrap();
+        product.allocate(&line, None).unwrap();
+        repo.add(&product).await.unwrap();
+
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        assert_eq!(product.batches[0].buffer_used(), 2);
+        assert_eq!(product.batches[0].available_qty(), 1);
+    }
+
+    #[sqlx::test]
+    async fn test_warehouse_is_stored_with_the_batch(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let stocked = Batch::builder()
+            .sku("SMALL_TABLE")
+            .qty(10)
+            .warehouse_id("WAREHOUSE_1")
+            .build()
+            .unwrap();
+        let unplaced = Batch::new("SMALL_TABLE".to_string(), 5, None).unwrap();
+
+        let ids = repo.add_batches(&[stocked, unplaced]).await.unwrap();
+        let mut product = repo.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(
+            product.batches[0].warehouse_id.as_deref(),
+            Some("WAREHOUSE_1")
+        );
+        assert_eq!(product.batches[1].warehouse_id, None);
+
+        product.batches[1].warehouse_id = Some("WAREHOUSE_2".to_string());
+        repo.add(&product).await.unwrap();
+        let stored = repo.read_batch(ids[1], false).await.unwrap();
+        assert_eq!(stored.warehouse_id.as_deref(), Some("WAREHOUSE_2"));
+    }
+
+    #[sqlx::test]
+    async fn test_unique_violation_is_a_conflict(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        for order_ref in ["ORDER_1", "ORDER_2"] {
+            batch.reserved.push(Reservation {
+                id: ReservationId(1),
+                line: OrderLine::new(order_ref.to_string(), sku.clone(), 1)
+                    .unwrap(),
+                reserved_at: Local::now(),
+            });
+        }
+
+        let err = repo.create_batch(&batch).await.unwrap_err();
+
+        assert!(matches!(err, RepositoryError::Conflict(_)), "{err:?}");
+        assert!(repo
+            .list_batches(None, None, None, 10, 0, true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_serialization_failure_is_a_retryable_conflict(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let id = repo
+            .create_batch(
+                &Batch::new("SMALL_TABLE".to_string(), 10, None).unwrap(),
+            )
+            .await
+            .unwrap();
+        let mut first = pg_pool.begin().await.unwrap();
+        let mut second = pg_pool.begin().await.unwrap();
+        for tx in [&mut first, &mut second] {
+            sqlx::query("SET TRANSACTION ISOLATION LEVEL SERIALIZABLE")
+                .execute(&mut **tx)
+                .await
+                .unwrap();
+            sqlx::query("SELECT qty FROM batches WHERE id = $1")
+                .bind(id)
+                .execute(&mut **tx)
+                .await
+                .unwrap();
+        }
+        let update = "UPDATE batches SET qty = qty + 1 WHERE id = $1";
+        sqlx::query(update)
+            .bind(id)
+            .execute(&mut *first)
+            .await
+            .unwrap();
+        first.commit().await.unwrap();
+
+        let err: RepositoryError = sqlx::query(update)
+            .bind(id)
+            .execute(&mut *second)
+            .await
+            .unwrap_err()
+            .into();
+
+        assert!(matches!(err, RepositoryError::Conflict(_)), "{err:?}");
+        assert!(crate::services::handlers::is_retryable(&err.into()));
+    }
+
+    #[sqlx::test]
+    async fn test_missing_batch_is_not_found(pg_pool: PgPool) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+
+        let err = repo.read_batch(BatchId(1), true).await.unwrap_err();
+
+        assert!(matches!(err, RepositoryError::NotFound));
+    }
+}
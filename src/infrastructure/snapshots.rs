@@ -0,0 +1,104 @@
+use crate::domain::model::{Product, ProductSnapshot, SNAPSHOT_INTERVAL};
+use crate::domain::tenant::TenantId;
+use sqlx::postgres::PgConnection;
+use sqlx::types::Json;
+
+// Snapshots are only for rebuilding a product from its event stream with
+// `Product::from_snapshot`. The repositories load and save products through
+// the batch tables and never read or write snapshots.
+pub async fn save(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    snapshot: &ProductSnapshot,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        r#"
+            INSERT INTO snapshots (sku, event_count, snapshot, tenant_id)
+            VALUES ( $1, $2, $3, $4 )
+            ON CONFLICT (tenant_id, sku, event_count) DO NOTHING
+        "#,
+    )
+    .bind(&snapshot.sku)
+    .bind(i32::try_from(snapshot.event_count)?)
+    .bind(Json(snapshot))
+    .bind(tenant.as_str())
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+// Snapshots the product once every `SNAPSHOT_INTERVAL` events, returning
+// whether it did
+pub async fn save_if_due(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    product: &Product,
+    event_count: usize,
+) -> anyhow::Result<bool> {
+    if event_count == 0 || !event_count.is_multiple_of(SNAPSHOT_INTERVAL) {
+        return Ok(false);
+    }
+    save(conn, tenant, &product.snapshot(event_count)).await?;
+    Ok(true)
+}
+
+// The snapshot covering the most events, `None` before the first one
+pub async fn latest(
+    conn: &mut PgConnection,
+    tenant: &TenantId,
+    sku: &str,
+) -> anyhow::Result<Option<ProductSnapshot>> {
+    let snapshot: Option<Json<ProductSnapshot>> = sqlx::query_scalar(
+        r#"
+            SELECT snapshot FROM snapshots WHERE sku = $1 AND tenant_id = $2
+            ORDER BY event_count DESC LIMIT 1
+        "#,
+    )
+    .bind(sku)
+    .bind(tenant.as_str())
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(snapshot.map(|Json(snapshot)| snapshot))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::{Batch, BatchId, OrderLine};
+    use sqlx::postgres::PgPool;
+
+    #[sqlx::test]
+    async fn test_rebuilding_from_latest_snapshot_matches_replay(
+        pg_pool: PgPool,
+    ) {
+        let mut conn = pg_pool.acquire().await.unwrap();
+        let tenant = TenantId::default();
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 500, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut live = Product::new(sku.clone(), vec![batch.clone()]).unwrap();
+        let mut events = Vec::new();
+        let mut saved = 0;
+        for n in 0..250 {
+            let line =
+                OrderLine::new(format!("ORDER_{}", n), sku.clone(), 1).unwrap();
+            live.allocate(&line, None).unwrap();
+            events.extend(live.collect_new_events());
+            if save_if_due(&mut conn, &tenant, &live, events.len())
+                .await
+                .unwrap()
+            {
+                saved += 1;
+            }
+        }
+
+        let snapshot = latest(&mut conn, &tenant, &sku).await.unwrap().unwrap();
+        assert_eq!(saved, 2);
+        assert_eq!(snapshot.event_count, 200);
+        let restored = Product::from_snapshot(snapshot, &events).unwrap();
+        let replayed = Product::from_events(sku, vec![batch], &events).unwrap();
+        assert_eq!(restored, replayed);
+        let missing = latest(&mut conn, &tenant, "BLUE_LAMP").await.unwrap();
+        assert_eq!(missing, None);
+    }
+}
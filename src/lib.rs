@@ -1 +1,4 @@
+pub mod api;
 pub mod domain;
+pub mod infrastructure;
+pub mod services;
@@ -0,0 +1,387 @@
+use crate::domain::events::{DomainEvent, EventKind};
+use crate::services::handlers;
+use crate::services::messagebus::{EventHandler, MessageBus};
+use crate::services::pending;
+use crate::services::unit_of_work::{PostgresUnitOfWork, UnitOfWork};
+use crate::services::views;
+use sqlx::postgres::PgPool;
+
+// Builds the message bus with every event handler wired to the database
+pub fn bootstrap(pg_pool: PgPool) -> MessageBus {
+    let mut bus = MessageBus::new();
+    for kind in [EventKind::Allocated, EventKind::Deallocated] {
+        bus.register(kind, update_allocations_view(pg_pool.clone()));
+    }
+    for kind in [
+        EventKind::BatchCreated,
+        EventKind::Allocated,
+        EventKind::Deallocated,
+        EventKind::BatchQuantityIncreased,
+        EventKind::ReservationReleased,
+    ] {
+        bus.register(kind, update_batch_view(pg_pool.clone()));
+    }
+    for kind in [
+        EventKind::OutOfStock,
+        EventKind::Allocated,
+        EventKind::OrderCancelled,
+    ] {
+        bus.register(kind, update_pending_lines(pg_pool.clone()));
+    }
+    bus.register(EventKind::Deallocated, reallocate(pg_pool.clone()));
+    for kind in [
+        EventKind::BatchQuantityIncreased,
+        EventKind::ReservationReleased,
+    ] {
+        bus.register(kind, allocate_pending_lines(pg_pool.clone()));
+    }
+    bus
+}
+
+fn update_allocations_view(pg_pool: PgPool) -> EventHandler {
+    Box::new(move |tenant, event| {
+        let pg_pool = pg_pool.clone();
+        Box::pin(async move {
+            views::update_allocations_view(&event, &tenant, &pg_pool).await?;
+            Ok(Vec::new())
+        })
+    })
+}
+
+fn update_batch_view(pg_pool: PgPool) -> EventHandler {
+    Box::new(move |tenant, event| {
+        let pg_pool = pg_pool.clone();
+        Box::pin(async move {
+            views::update_batch_view(&event, &tenant, &pg_pool).await?;
+            Ok(Vec::new())
+        })
+    })
+}
+
+fn update_pending_lines(pg_pool: PgPool) -> EventHandler {
+    Box::new(move |tenant, event| {
+        let pg_pool = pg_pool.clone();
+        Box::pin(async move {
+            pending::update_pending_lines(&event, &tenant, &pg_pool).await?;
+            Ok(Vec::new())
+        })
+    })
+}
+
+// Each freed line gets its own unit of work, retried like a command. The
+// moves are attributed to the handler in the audit trail.
+fn reallocate(pg_pool: PgPool) -> EventHandler {
+    Box::new(move |tenant, event| {
+        let pg_pool = pg_pool.clone();
+        Box::pin(async move {
+            let (event, tenant, pg_pool) = (&event, &tenant, &pg_pool);
+            handlers::retry_on_conflict(handlers::MAX_ATTEMPTS, || async move {
+                let mut uow =
+                    PostgresUnitOfWork::begin_as(pg_pool, tenant, "reallocate")
+                        .await?;
+                handlers::reallocate_from_deallocated(event, &mut uow).await?;
+                Ok(uow.collect_new_events())
+            })
+            .await
+        })
+    })
+}
+
+// Lines are retried oldest first, each in its own unit of work, whenever
+// stock is freed up
+fn allocate_pending_lines(pg_pool: PgPool) -> EventHandler {
+    Box::new(move |tenant, event| {
+        let pg_pool = pg_pool.clone();
+        Box::pin(async move {
+            let (DomainEvent::BatchQuantityIncreased { sku, .. }
+            | DomainEvent::ReservationReleased { sku, .. }) = &event
+            else {
+                return Ok(Vec::new());
+            };
+            let (tenant, pg_pool) = (&tenant, &pg_pool);
+            let mut raised = Vec::new();
+            for line in pending::pending_lines(sku, tenant, pg_pool).await? {
+                let line = &line;
+                let events = handlers::retry_on_conflict(
+                    handlers::MAX_ATTEMPTS,
+                    || async move {
+                        let mut uow = PostgresUnitOfWork::begin_as(
+                            pg_pool,
+                            tenant,
+                            "allocate_pending_lines",
+                        )
+                        .await?;
+                        handlers::allocate_pending_line(line, &mut uow).await?;
+                        Ok(uow.collect_new_events())
+                    },
+                )
+                .await?;
+                raised.extend(events);
+            }
+            Ok(raised)
+        })
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::commands::Command;
+    use crate::domain::model::OrderLine;
+    use crate::domain::repository::ProductRepository;
+    use crate::domain::tenant::TenantId;
+    use crate::infrastructure::audit::{self, AuditAction};
+    use crate::infrastructure::repository::PostgresBatchRepository;
+    use chrono::{Duration, Local};
+
+    async fn handle(bus: &MessageBus, pg_pool: &PgPool, command: Command) {
+        let mut uow = PostgresUnitOfWork::begin(pg_pool).await.unwrap();
+        bus.handle_command(command, &mut uow).await.unwrap();
+    }
+
+    async fn handle_as(
+        bus: &MessageBus,
+        pg_pool: &PgPool,
+        tenant: &TenantId,
+        command: Command,
+    ) -> anyhow::Result<()> {
+        let mut uow =
+            PostgresUnitOfWork::begin_as(pg_pool, tenant, "test").await?;
+        bus.handle_command(command, &mut uow).await.map(|_| ())
+    }
+
+    #[sqlx::test]
+    async fn test_tenants_only_allocate_from_their_own_stock(pg_pool: PgPool) {
+        let bus = bootstrap(pg_pool.clone());
+        let (acme, globex) = (
+            TenantId::try_from("acme").unwrap(),
+            TenantId::try_from("globex").unwrap(),
+        );
+        let sku = "SMALL_TABLE".to_string();
+        let create = Command::CreateBatch {
+            reference: "BATCH_1".to_string(),
+            sku: sku.clone(),
+            qty: 10,
+            eta: None,
+        };
+        handle_as(&bus, &pg_pool, &globex, create).await.unwrap();
+        let allocate = Command::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 4,
+        };
+
+        let result = handle_as(&bus, &pg_pool, &acme, allocate.clone()).await;
+        assert!(result.unwrap_err().is::<handlers::InvalidSku>());
+        handle_as(&bus, &pg_pool, &globex, allocate).await.unwrap();
+
+        let view = views::allocations("ORDER_1", &globex, &pg_pool).await;
+        assert_eq!(view.unwrap().len(), 1);
+        let view = views::allocations("ORDER_1", &acme, &pg_pool).await;
+        assert!(view.unwrap().is_empty());
+        let batches = views::batches_for_sku(&sku, &globex, &pg_pool).await;
+        assert_eq!(batches.unwrap()[0].available_qty, 6);
+        let repo = PostgresBatchRepository::for_tenant(pg_pool.clone(), acme);
+        assert!(repo.get(&sku).await.unwrap().is_none());
+    }
+
+    #[sqlx::test]
+    async fn test_reduced_batch_quantity_cascades_to_another_batch(
+        pg_pool: PgPool,
+    ) {
+        let bus = bootstrap(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let tomorrow = Local::now() + Duration::days(1);
+        for (reference, qty, eta) in
+            [("IN_STOCK", 10, None), ("SHIPMENT", 10, Some(tomorrow))]
+        {
+            let command = Command::CreateBatch {
+                reference: reference.to_string(),
+                sku: sku.clone(),
+                qty,
+                eta,
+            };
+            handle(&bus, &pg_pool, command).await;
+        }
+        let allocate = Command::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 8,
+        };
+        handle(&bus, &pg_pool, allocate).await;
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        let (in_stock, shipment) = (&product.batches[0], &product.batches[1]);
+
+        let change = Command::ChangeBatchQuantity {
+            batch_id: in_stock.id.unwrap(),
+            qty: 5,
+        };
+        handle(&bus, &pg_pool, change).await;
+
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 8).unwrap();
+        assert!(product.batches[0].allocated.is_empty());
+        assert!(product.batches[1].allocated.contains(&line));
+        let view =
+            views::allocations("ORDER_1", &TenantId::default(), &pg_pool)
+                .await
+                .unwrap();
+        assert_eq!(view.len(), 1);
+        assert_eq!(view[0].batch_id, shipment.id.unwrap());
+
+        let history = audit::allocation_history(
+            "ORDER_1",
+            &TenantId::default(),
+            &pg_pool,
+        )
+        .await
+        .unwrap();
+        let moves: Vec<_> = history
+            .iter()
+            .map(|entry| (entry.action, entry.batch_id, entry.actor.as_str()))
+            .collect();
+        assert_eq!(
+            moves,
+            vec![
+                (AuditAction::Allocated, in_stock.id, "system"),
+                (AuditAction::Deallocated, None, "system"),
+                (AuditAction::Allocated, shipment.id, "reallocate"),
+            ]
+        );
+        assert!(history
+            .windows(2)
+            .all(|pair| pair[0].occurred_at <= pair[1].occurred_at));
+    }
+
+    #[sqlx::test]
+    async fn test_growing_a_batch_allocates_pending_lines(pg_pool: PgPool) {
+        let bus = bootstrap(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let create = Command::CreateBatch {
+            reference: "IN_STOCK".to_string(),
+            sku: sku.clone(),
+            qty: 5,
+            eta: None,
+        };
+        handle(&bus, &pg_pool, create).await;
+        let allocate = Command::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 8,
+        };
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        assert!(bus.handle_command(allocate, &mut uow).await.is_err());
+        drop(uow);
+        let pending =
+            pending::pending_lines(&sku, &TenantId::default(), &pg_pool)
+                .await
+                .unwrap();
+        assert_eq!(pending.len(), 1);
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let product = repo.get(&sku).await.unwrap().unwrap();
+
+        let grow = Command::ChangeBatchQuantity {
+            batch_id: product.batches[0].id.unwrap(),
+            qty: 10,
+        };
+        handle(&bus, &pg_pool, grow).await;
+
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        assert!(product.batches[0].allocated.contains(&pending[0]));
+        assert!(pending::pending_lines(&sku, &TenantId::default(), &pg_pool)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_cancelled_pending_lines_are_not_allocated(pg_pool: PgPool) {
+        let bus = bootstrap(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let create = Command::CreateBatch {
+            reference: "IN_STOCK".to_string(),
+            sku: sku.clone(),
+            qty: 5,
+            eta: None,
+        };
+        handle(&bus, &pg_pool, create).await;
+        let allocate = Command::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 8,
+        };
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        assert!(bus.handle_command(allocate, &mut uow).await.is_err());
+        drop(uow);
+        let cancel = Command::CancelAllocation {
+            order_ref: "ORDER_1".to_string(),
+        };
+        handle(&bus, &pg_pool, cancel).await;
+        assert!(pending::pending_lines(&sku, &TenantId::default(), &pg_pool)
+            .await
+            .unwrap()
+            .is_empty());
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let product = repo.get(&sku).await.unwrap().unwrap();
+
+        let grow = Command::ChangeBatchQuantity {
+            batch_id: product.batches[0].id.unwrap(),
+            qty: 10,
+        };
+        handle(&bus, &pg_pool, grow).await;
+
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        assert!(product.batches[0].allocated.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_batch_view_follows_the_write_model(pg_pool: PgPool) {
+        let bus = bootstrap(pg_pool.clone());
+        let sku = "SMALL_TABLE".to_string();
+        let tomorrow = Local::now() + Duration::days(1);
+        for (reference, qty, eta) in
+            [("IN_STOCK", 10, None), ("SHIPMENT", 20, Some(tomorrow))]
+        {
+            let command = Command::CreateBatch {
+                reference: reference.to_string(),
+                sku: sku.clone(),
+                qty,
+                eta,
+            };
+            handle(&bus, &pg_pool, command).await;
+        }
+        for (order_ref, qty) in [("ORDER_1", 6), ("ORDER_2", 7), ("ORDER_3", 2)]
+        {
+            let allocate = Command::Allocate {
+                order_ref: order_ref.to_string(),
+                sku: sku.clone(),
+                qty,
+            };
+            handle(&bus, &pg_pool, allocate).await;
+        }
+        let cancel = Command::CancelAllocation {
+            order_ref: "ORDER_1".to_string(),
+        };
+        handle(&bus, &pg_pool, cancel).await;
+
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let product = repo.get(&sku).await.unwrap().unwrap();
+        let view = views::batches_for_sku(&sku, &TenantId::default(), &pg_pool)
+            .await
+            .unwrap();
+        let stored: Vec<_> = product
+            .batches
+            .iter()
+            .map(|batch| (batch.id.unwrap(), batch.available_qty()))
+            .collect();
+        let viewed: Vec<_> = view
+            .iter()
+            .map(|batch| (batch.batch_id, batch.available_qty))
+            .collect();
+        assert_eq!(viewed, stored);
+        assert_eq!(viewed.iter().map(|(_, qty)| qty).sum::<u32>(), 21);
+        assert_eq!(view[1].reference.as_deref(), Some("SHIPMENT"));
+    }
+}
@@ -0,0 +1,1085 @@
+use crate::domain::clock::Clock;
+use crate::domain::commands::Command;
+use crate::domain::events::DomainEvent;
+use crate::domain::model::{
+    AllocationError, Batch, BatchId, OrderLine, ReservationId,
+};
+use crate::domain::repository::{ConcurrencyError, ProductRepository};
+use crate::infrastructure::db::IsolationLevel;
+use crate::services::unit_of_work::UnitOfWork;
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::future::Future;
+use std::time::{Duration, Instant};
+
+pub const MAX_ATTEMPTS: u32 = 3;
+const RETRY_BACKOFF: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidSku(pub String);
+
+impl fmt::Display for InvalidSku {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid sku {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSku {}
+
+pub async fn add_batch(
+    reference: String,
+    sku: String,
+    qty: u32,
+    eta: Option<DateTime<Local>>,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    let mut batch = Batch::new(sku, qty, eta)?;
+    batch.reference = Some(reference);
+
+    let mut product = uow.products().get_or_create(&batch.sku).await?;
+    product.add_batch(batch)?;
+
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+
+    Ok(())
+}
+
+// Records `allocations_total` and `out_of_stock_total` per SKU along with
+// the `allocate_duration_seconds` latency histogram
+#[tracing::instrument(
+    skip_all,
+    fields(order_ref = %order_ref, sku = %sku, qty = qty)
+)]
+pub async fn allocate(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<BatchId> {
+    let started = Instant::now();
+    let result = allocate_line(order_ref, sku, qty, uow).await;
+    metrics::histogram!("allocate_duration_seconds")
+        .record(started.elapsed().as_secs_f64());
+    result
+}
+
+async fn allocate_line(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<BatchId> {
+    let line = OrderLine::new(order_ref, sku, qty)?;
+    let mut product = uow
+        .products()
+        .get(line.sku.as_str())
+        .await?
+        .ok_or_else(|| InvalidSku(line.sku.to_string()))?;
+
+    let batch_id = match product.allocate(&line, None) {
+        Ok(batch_id) => batch_id,
+        Err(err) => {
+            let events = product.collect_new_events();
+            for event in &events {
+                if let DomainEvent::OutOfStock { sku, .. } = event {
+                    metrics::counter!("out_of_stock_total", "sku" => sku.clone())
+                        .increment(1);
+                }
+            }
+            uow.record_events(events);
+            return Err(err.into());
+        }
+    };
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+    metrics::counter!("allocations_total", "sku" => line.sku.to_string())
+        .increment(1);
+
+    Ok(batch_id)
+}
+
+// What became of one line passed to `try_allocate_all`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocationOutcome {
+    Allocated(BatchId),
+    OutOfStock,
+    InvalidSku,
+    Invalid,
+}
+
+// Allocates each `(order_ref, sku, qty)` line in turn and commits the ones
+// that fit together, returning an outcome per line in the same order. A line
+// out of stock, for an unknown SKU or that isn't a valid order line doesn't
+// stop the others. Any other error rolls it all back.
+pub async fn try_allocate_all(
+    lines: Vec<(String, String, u32)>,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<Vec<AllocationOutcome>> {
+    let lines: Vec<_> = lines
+        .into_iter()
+        .map(|(order_ref, sku, qty)| OrderLine::new(order_ref, sku, qty).ok())
+        .collect();
+
+    let mut outcomes = Vec::with_capacity(lines.len());
+    for line in &lines {
+        let Some(line) = line else {
+            outcomes.push(AllocationOutcome::Invalid);
+            continue;
+        };
+        let Some(mut product) = uow.products().get(line.sku.as_str()).await?
+        else {
+            outcomes.push(AllocationOutcome::InvalidSku);
+            continue;
+        };
+        let outcome = match product.allocate(line, None) {
+            Ok(batch_id) => {
+                uow.products().add(&product).await?;
+                AllocationOutcome::Allocated(batch_id)
+            }
+            Err(AllocationError::NoBatchAvailable) => {
+                AllocationOutcome::OutOfStock
+            }
+            Err(err) => return Err(err.into()),
+        };
+        uow.record_events(product.collect_new_events());
+        outcomes.push(outcome);
+    }
+    uow.commit().await?;
+
+    for (line, outcome) in lines.iter().zip(&outcomes) {
+        let Some(line) = line else { continue };
+        let sku = line.sku.to_string();
+        match outcome {
+            AllocationOutcome::Allocated(_) => {
+                metrics::counter!("allocations_total", "sku" => sku)
+                    .increment(1)
+            }
+            AllocationOutcome::OutOfStock => {
+                metrics::counter!("out_of_stock_total", "sku" => sku)
+                    .increment(1)
+            }
+            AllocationOutcome::InvalidSku | AllocationOutcome::Invalid => {}
+        }
+    }
+    Ok(outcomes)
+}
+
+// Runs the allocation through the unit of work like `allocate` does, then
+// rolls it back. Nothing is stored and no events are recorded.
+pub async fn allocate_dry_run(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<BatchId> {
+    let line = OrderLine::new(order_ref, sku, qty)?;
+    let mut product = uow
+        .products()
+        .get(line.sku.as_str())
+        .await?
+        .ok_or_else(|| InvalidSku(line.sku.to_string()))?;
+
+    let result = product.allocate(&line, None);
+    if result.is_ok() {
+        uow.products().add(&product).await?;
+    }
+    uow.rollback().await?;
+
+    Ok(result?)
+}
+
+// Read-only counterpart of `allocate`, the batch the line would go to
+pub async fn preview_allocation(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    products: &dyn ProductRepository,
+) -> anyhow::Result<Option<BatchId>> {
+    let line = OrderLine::new(order_ref, sku, qty)?;
+    let product = products
+        .get(line.sku.as_str())
+        .await?
+        .ok_or_else(|| InvalidSku(line.sku.to_string()))?;
+
+    Ok(product.preview_allocation(&line))
+}
+
+// The isolation level the unit of work handling `command` should run at.
+// Every handler loads the product through the transactional repository,
+// which takes the product's advisory lock first, so `ReadCommitted` is
+// enough for them to see each other's changes in turn. `Serializable` would
+// make a blocked allocation fail and be retried instead.
+pub fn isolation_level(command: &Command) -> IsolationLevel {
+    match command {
+        Command::Allocate { .. }
+        | Command::CreateBatch { .. }
+        | Command::ChangeBatchQuantity { .. }
+        | Command::CancelAllocation { .. } => IsolationLevel::ReadCommitted,
+    }
+}
+
+// Whether the transaction was aborted by Postgres because it conflicted with
+// a concurrent one (serialization failure or deadlock), or saved a stale
+// product, so running it again may succeed. The database error may be
+// wrapped, in a `RepositoryError` for one.
+pub fn is_retryable(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| err.is::<ConcurrencyError>())
+        || err
+            .chain()
+            .filter_map(|err| err.downcast_ref::<sqlx::Error>())
+            .filter_map(|err| err.as_database_error())
+            .filter_map(|err| err.code())
+            .any(|code| code == "40001" || code == "40P01")
+}
+
+// Runs `attempt` until it succeeds, fails for a reason other than a
+// conflicting transaction, or `max_attempts` is reached. Each attempt should
+// begin its own unit of work, backing off a little longer every time.
+pub async fn retry_on_conflict<T, F, Fut>(
+    max_attempts: u32,
+    mut attempt: F,
+) -> anyhow::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = anyhow::Result<T>>,
+{
+    let mut attempts = 1;
+    loop {
+        match attempt().await {
+            Err(err) if attempts < max_attempts && is_retryable(&err) => {
+                tracing::warn!(attempts, %err, "retrying conflicting transaction");
+                tokio::time::sleep(RETRY_BACKOFF * attempts).await;
+                attempts += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+// Lines that no longer fit the batch are deallocated, each with a
+// `Deallocated` event so `reallocate_from_deallocated` can place it
+// elsewhere. Growing the batch raises `BatchQuantityIncreased` so lines
+// waiting for stock get another go via `allocate_pending_line`.
+pub async fn change_batch_quantity(
+    batch_id: BatchId,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    let mut product = uow
+        .products()
+        .get_by_batch_id(batch_id)
+        .await?
+        .ok_or(AllocationError::UnknownBatch { batch_id })?;
+
+    product.change_batch_quantity(batch_id, qty)?;
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+
+    Ok(())
+}
+
+// Frees the order's lines in every product holding them, in one unit of
+// work. Cancelling an order that has nothing allocated, e.g. one already
+// cancelled, succeeds without changing any batch. Its lines still waiting
+// for stock are dropped through `OrderCancelled`.
+pub async fn cancel_allocation(
+    order_ref: String,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    for mut product in uow.products().get_by_order_ref(&order_ref).await? {
+        product.cancel_order(&order_ref);
+        uow.products().add(&product).await?;
+        uow.record_events(product.collect_new_events());
+    }
+    uow.record_events(vec![DomainEvent::OrderCancelled { order_ref }]);
+    uow.commit().await?;
+
+    Ok(())
+}
+
+// Holds stock for the line until `confirm_reservation` allocates it or
+// `release_reservation` gives it back
+pub async fn reserve(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<ReservationId> {
+    let line = OrderLine::new(order_ref, sku, qty)?;
+    let mut product = uow
+        .products()
+        .get(line.sku.as_str())
+        .await?
+        .ok_or_else(|| InvalidSku(line.sku.to_string()))?;
+
+    let result = product.reserve(&line);
+    if result.is_ok() {
+        uow.products().add(&product).await?;
+    }
+    uow.record_events(product.collect_new_events());
+    let reservation_id = result?;
+    uow.commit().await?;
+
+    Ok(reservation_id)
+}
+
+pub async fn confirm_reservation(
+    sku: String,
+    reservation_id: ReservationId,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<BatchId> {
+    let mut product = uow
+        .products()
+        .get(&sku)
+        .await?
+        .ok_or_else(|| InvalidSku(sku.clone()))?;
+
+    let batch_id = product.confirm(reservation_id)?;
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+
+    Ok(batch_id)
+}
+
+pub async fn release_reservation(
+    sku: String,
+    reservation_id: ReservationId,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<OrderLine> {
+    let mut product = uow
+        .products()
+        .get(&sku)
+        .await?
+        .ok_or_else(|| InvalidSku(sku.clone()))?;
+
+    let line = product.release(reservation_id)?;
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+
+    Ok(line)
+}
+
+// Frees the lines of the product's expired batches. Their `Deallocated`
+// events get them reallocated to batches that are still good.
+pub async fn release_expired(
+    sku: String,
+    clock: &dyn Clock,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<Vec<OrderLine>> {
+    let mut product = uow
+        .products()
+        .get(&sku)
+        .await?
+        .ok_or_else(|| InvalidSku(sku.clone()))?;
+
+    let freed = product.release_expired(clock);
+    if !freed.is_empty() {
+        uow.products().add(&product).await?;
+        uow.record_events(product.collect_new_events());
+    }
+    uow.commit().await?;
+
+    Ok(freed)
+}
+
+// Event handler trying to allocate a freed line to another batch
+pub async fn reallocate_from_deallocated(
+    event: &DomainEvent,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    let DomainEvent::Deallocated {
+        order_ref,
+        sku,
+        qty,
+        cancelled: false,
+    } = event
+    else {
+        return Ok(());
+    };
+
+    allocate_if_in_stock(order_ref.clone(), sku.clone(), *qty, uow).await
+}
+
+// Retries a line that ran out of stock earlier, once a batch has grown
+pub async fn allocate_pending_line(
+    line: &OrderLine,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    allocate_if_in_stock(
+        line.order_ref.clone(),
+        line.sku.to_string(),
+        line.qty,
+        uow,
+    )
+    .await
+}
+
+// Running out of stock isn't a failure here, it is reported by the
+// `OutOfStock` event the product raises
+async fn allocate_if_in_stock(
+    order_ref: String,
+    sku: String,
+    qty: u32,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<()> {
+    match allocate(order_ref, sku, qty, uow).await {
+        Err(err)
+            if err.downcast_ref::<AllocationError>()
+                == Some(&AllocationError::NoBatchAvailable) =>
+        {
+            Ok(())
+        }
+        result => result.map(|_| ()),
+    }
+}
+
+// Deletes a batch, returning the lines it held. Each of them is reported
+// with a `Deallocated` event so it can be reprocessed.
+pub async fn delete_batch(
+    batch_id: BatchId,
+    uow: &mut dyn UnitOfWork,
+) -> anyhow::Result<Vec<OrderLine>> {
+    let mut product = uow
+        .products()
+        .get_by_batch_id(batch_id)
+        .await?
+        .ok_or(AllocationError::UnknownBatch { batch_id })?;
+
+    let freed = product.remove_batch(batch_id)?;
+    uow.products().add(&product).await?;
+    uow.record_events(product.collect_new_events());
+    uow.commit().await?;
+
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::clock::FixedClock;
+    use crate::domain::model::Product;
+    use crate::services::unit_of_work::fake::FakeUnitOfWork;
+    use chrono::Duration;
+
+    #[tokio::test]
+    async fn test_add_batch_for_new_product() {
+        let mut uow = FakeUnitOfWork::default();
+
+        add_batch(
+            "BATCH_1".to_string(),
+            "CRUNCHY_ARMCHAIR".to_string(),
+            100,
+            None,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+
+        let product = uow.products.get("CRUNCHY_ARMCHAIR").await.unwrap();
+        let batches = product.unwrap().batches;
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batches[0].reference, Some("BATCH_1".to_string()));
+        assert_eq!(batches[0].qty, 100);
+        assert!(uow.committed);
+    }
+
+    #[tokio::test]
+    async fn test_add_batch_for_existing_product() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        add_batch(
+            "BATCH_2".to_string(),
+            "SMALL_TABLE".to_string(),
+            20,
+            None,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_returns_batch_id_and_persists_allocation() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let batch_id = allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(batch_id, BatchId(1));
+        assert_eq!(product.batches[0].available_qty(), 7);
+        assert!(uow.committed);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 3,
+                batch_id: BatchId(1),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allocate_dry_run_does_not_commit() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let batch_id = allocate_dry_run(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(batch_id, BatchId(1));
+        assert!(!uow.committed);
+        assert!(uow.collect_new_events().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_allocate_errors_for_invalid_sku() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let err = allocate(
+            "ORDER_1".to_string(),
+            "NONEXISTENT_SKU".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<InvalidSku>(),
+            Some(&InvalidSku("NONEXISTENT_SKU".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_allocate_errors_when_out_of_stock() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let err = allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            30,
+            &mut uow,
+        )
+        .await
+        .unwrap_err();
+
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(
+            err.downcast_ref::<AllocationError>(),
+            Some(&AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(product.batches[0].available_qty(), 10);
+        assert!(!uow.committed);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 30,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_try_allocate_all_reports_an_outcome_per_line() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        let lines = [
+            ("ORDER_1", "SMALL_TABLE", 3),
+            ("ORDER_2", "SMALL_TABLE", 30),
+            ("ORDER_3", "BLUE_LAMP", 1),
+            ("ORDER_4", "SMALL_TABLE", 7),
+        ]
+        .map(|(order_ref, sku, qty)| {
+            (order_ref.to_string(), sku.to_string(), qty)
+        });
+
+        let outcomes =
+            try_allocate_all(lines.to_vec(), &mut uow).await.unwrap();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                AllocationOutcome::Allocated(BatchId(1)),
+                AllocationOutcome::OutOfStock,
+                AllocationOutcome::InvalidSku,
+                AllocationOutcome::Allocated(BatchId(1)),
+            ]
+        );
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 0);
+        assert!(uow.committed);
+        let order_refs: Vec<_> = uow
+            .collect_new_events()
+            .into_iter()
+            .map(|event| match event {
+                DomainEvent::Allocated { order_ref, .. }
+                | DomainEvent::OutOfStock { order_ref, .. } => order_ref,
+                event => panic!("unexpected {:?}", event),
+            })
+            .collect();
+        assert_eq!(order_refs, ["ORDER_1", "ORDER_2", "ORDER_4"]);
+    }
+
+    #[tokio::test]
+    async fn test_try_allocate_all_reports_invalid_lines_without_failing() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        let lines = [
+            ("ORDER_1", "SMALL_TABLE", 0),
+            ("ORDER_2", "SMALL_TABLE", 4),
+            ("ORDER_3", "", 1),
+        ]
+        .map(|(order_ref, sku, qty)| {
+            (order_ref.to_string(), sku.to_string(), qty)
+        });
+
+        let outcomes =
+            try_allocate_all(lines.to_vec(), &mut uow).await.unwrap();
+
+        assert_eq!(
+            outcomes,
+            vec![
+                AllocationOutcome::Invalid,
+                AllocationOutcome::Allocated(BatchId(1)),
+                AllocationOutcome::Invalid,
+            ]
+        );
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 6);
+        assert!(uow.committed);
+    }
+
+    fn product_with_stock_and_shipment(sku: &str) -> Product {
+        let tomorrow = Local::now() + Duration::days(1);
+        let mut stock = Batch::new(sku.to_string(), 20, None).unwrap();
+        stock.id = Some(BatchId(1));
+        let mut shipment =
+            Batch::new(sku.to_string(), 5, Some(tomorrow)).unwrap();
+        shipment.id = Some(BatchId(2));
+
+        Product::new(sku.to_string(), vec![stock, shipment]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_change_batch_quantity_deallocates_overflow_lines() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut product = product_with_stock_and_shipment(&sku);
+        let big =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 6).unwrap();
+        let small =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 4).unwrap();
+        let medium =
+            OrderLine::new("ORDER_3".to_string(), sku.clone(), 5).unwrap();
+        for line in [&big, &small, &medium] {
+            assert_eq!(product.allocate(line, None), Ok(BatchId(1)));
+        }
+        product.collect_new_events();
+        let mut uow = FakeUnitOfWork::with_products(vec![product]);
+
+        change_batch_quantity(BatchId(1), 6, &mut uow)
+            .await
+            .unwrap();
+
+        let product = uow.products.get(&sku).await.unwrap().unwrap();
+        assert!(product.batches[0].allocated.contains(&big));
+        assert!(product.batches[1].allocated.is_empty());
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_3".to_string(),
+                    sku: sku.clone(),
+                    qty: 5,
+                    cancelled: false,
+                },
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_2".to_string(),
+                    sku,
+                    qty: 4,
+                    cancelled: false,
+                },
+            ]
+        );
+        assert!(uow.committed);
+    }
+
+    #[tokio::test]
+    async fn test_reallocate_from_deallocated_places_line_elsewhere() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut uow = FakeUnitOfWork::with_products(vec![
+            product_with_stock_and_shipment(&sku),
+        ]);
+        let deallocated = DomainEvent::Deallocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 4,
+            cancelled: false,
+        };
+
+        reallocate_from_deallocated(&deallocated, &mut uow)
+            .await
+            .unwrap();
+
+        let product = uow.products.get(&sku).await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 16);
+        assert!(uow.committed);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 4,
+                batch_id: BatchId(1),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reserved_stock_is_allocated_once_confirmed() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let reservation_id = reserve(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            4,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 6);
+        assert!(uow.collect_new_events().is_empty());
+
+        let sku = "SMALL_TABLE".to_string();
+        let batch_id = confirm_reservation(sku, reservation_id, &mut uow)
+            .await
+            .unwrap();
+
+        assert_eq!(batch_id, BatchId(1));
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 6);
+        assert_eq!(product.batches[0].reserved_qty(), 0);
+        assert_eq!(uow.collect_new_events().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_releasing_a_reservation_is_published() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        let sku = "SMALL_TABLE".to_string();
+        let reservation_id =
+            reserve("ORDER_1".to_string(), sku.clone(), 4, &mut uow)
+                .await
+                .unwrap();
+
+        let line = release_reservation(sku.clone(), reservation_id, &mut uow)
+            .await
+            .unwrap();
+
+        assert_eq!(line.qty, 4);
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 10);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::ReservationReleased {
+                reservation_id: reservation_id.0,
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 4,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_lines_of_an_expired_batch_are_reallocated() {
+        let sku = "SMALL_TABLE".to_string();
+        let now = Local::now();
+        let mut product = product_with_stock_and_shipment(&sku);
+        product.batches[0].expires_at = Some(now);
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 4).unwrap();
+        let early = FixedClock(now - Duration::days(1));
+        assert_eq!(product.allocate_at(&line, None, &early), Ok(BatchId(1)));
+        product.collect_new_events();
+        let mut uow = FakeUnitOfWork::with_products(vec![product]);
+
+        let freed = release_expired(sku.clone(), &FixedClock(now), &mut uow)
+            .await
+            .unwrap();
+
+        assert_eq!(freed, vec![line]);
+        for event in uow.collect_new_events() {
+            reallocate_from_deallocated(&event, &mut uow).await.unwrap();
+        }
+        let product = uow.products.get(&sku).await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 20);
+        assert_eq!(product.batches[1].available_qty(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_reallocate_from_deallocated_reports_out_of_stock() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut uow = FakeUnitOfWork::with_products(vec![
+            product_with_stock_and_shipment(&sku),
+        ]);
+        let deallocated = DomainEvent::Deallocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.clone(),
+            qty: 30,
+            cancelled: false,
+        };
+
+        reallocate_from_deallocated(&deallocated, &mut uow)
+            .await
+            .unwrap();
+
+        assert!(!uow.committed);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku,
+                qty: 30,
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_growing_a_batch_is_reported() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        change_batch_quantity(BatchId(1), 15, &mut uow)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::BatchQuantityIncreased {
+                sku: "SMALL_TABLE".to_string(),
+                batch_id: BatchId(1),
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_change_quantity_of_unknown_batch_errors() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let err = change_batch_quantity(BatchId(42), 5, &mut uow)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<AllocationError>(),
+            Some(&AllocationError::UnknownBatch {
+                batch_id: BatchId(42)
+            })
+        );
+        assert!(!uow.committed);
+    }
+
+    #[tokio::test]
+    async fn test_delete_batch_returns_freed_lines_and_events() {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let line1 =
+            OrderLine::new("ORDER_1".to_string(), sku.clone(), 2).unwrap();
+        let line2 =
+            OrderLine::new("ORDER_2".to_string(), sku.clone(), 3).unwrap();
+        product.allocate(&line1, None).unwrap();
+        product.allocate(&line2, None).unwrap();
+        product.collect_new_events();
+        let mut uow = FakeUnitOfWork::with_products(vec![product]);
+
+        let freed = delete_batch(BatchId(1), &mut uow).await.unwrap();
+
+        assert_eq!(freed, vec![line1, line2]);
+        let events = uow.collect_new_events();
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, DomainEvent::Deallocated { .. })));
+        let product = uow.products.get(&sku).await.unwrap().unwrap();
+        assert!(product.batches.is_empty());
+        assert!(uow.committed);
+    }
+
+    #[tokio::test]
+    async fn test_allocate_normalizes_the_sku() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let batch_id = allocate(
+            "ORDER_1".to_string(),
+            " small_table".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        let reservation_id = reserve(
+            "ORDER_2".to_string(),
+            "small_table".to_string(),
+            2,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        let confirmed = confirm_reservation(
+            "small_table".to_string(),
+            reservation_id,
+            &mut uow,
+        )
+        .await;
+
+        assert_eq!(batch_id, BatchId(1));
+        assert_eq!(confirmed.unwrap(), BatchId(1));
+    }
+
+    #[tokio::test]
+    async fn test_preview_allocation_does_not_allocate() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let preview = preview_allocation(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &uow.products,
+        )
+        .await
+        .unwrap();
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 10);
+
+        let batch_id = allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        assert_eq!(preview, Some(batch_id));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_allocation_frees_lines_in_every_product() {
+        let mut batch = Batch::new("BLUE_LAMP".to_string(), 10, None).unwrap();
+        batch.id = Some(BatchId(2));
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        uow.products
+            .add(&Product::new("BLUE_LAMP".to_string(), vec![batch]).unwrap())
+            .await
+            .unwrap();
+        for (order_ref, sku) in [
+            ("ORDER_1", "SMALL_TABLE"),
+            ("ORDER_1", "BLUE_LAMP"),
+            ("ORDER_2", "SMALL_TABLE"),
+        ] {
+            allocate(order_ref.to_string(), sku.to_string(), 3, &mut uow)
+                .await
+                .unwrap();
+        }
+        uow.collect_new_events();
+
+        cancel_allocation("ORDER_1".to_string(), &mut uow)
+            .await
+            .unwrap();
+
+        let available = |product: Option<Product>| {
+            product.unwrap().batches[0].available_qty()
+        };
+        assert_eq!(
+            available(uow.products.get("SMALL_TABLE").await.unwrap()),
+            7
+        );
+        assert_eq!(available(uow.products.get("BLUE_LAMP").await.unwrap()), 10);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_1".to_string(),
+                    sku: "BLUE_LAMP".to_string(),
+                    qty: 3,
+                    cancelled: true,
+                },
+                DomainEvent::Deallocated {
+                    order_ref: "ORDER_1".to_string(),
+                    sku: "SMALL_TABLE".to_string(),
+                    qty: 3,
+                    cancelled: true,
+                },
+                DomainEvent::OrderCancelled {
+                    order_ref: "ORDER_1".to_string(),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelling_an_order_twice_is_a_no_op() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        cancel_allocation("ORDER_1".to_string(), &mut uow)
+            .await
+            .unwrap();
+        uow.collect_new_events();
+        let before = uow.products.get("SMALL_TABLE").await.unwrap();
+
+        cancel_allocation("ORDER_1".to_string(), &mut uow)
+            .await
+            .unwrap();
+
+        assert_eq!(uow.products.get("SMALL_TABLE").await.unwrap(), before);
+        assert_eq!(
+            uow.collect_new_events(),
+            vec![DomainEvent::OrderCancelled {
+                order_ref: "ORDER_1".to_string()
+            }]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_lines_are_not_reallocated() {
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+        let event = DomainEvent::Deallocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 3,
+            cancelled: true,
+        };
+
+        reallocate_from_deallocated(&event, &mut uow).await.unwrap();
+
+        let product = uow.products.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert_eq!(product.batches[0].available_qty(), 10);
+    }
+}
@@ -0,0 +1,166 @@
+use crate::domain::model::{Batch, BatchId};
+use crate::infrastructure::repository::PostgresBatchRepository;
+use chrono::{DateTime, Local};
+use serde::Deserialize;
+use std::path::Path;
+
+// Quantities are stored in Postgres INTEGER columns
+const MAX_QTY: u32 = i32::MAX as u32;
+
+#[derive(Debug, Deserialize)]
+struct CsvRow {
+    #[serde(rename = "ref")]
+    reference: String,
+    sku: String,
+    qty: u32,
+    // Left empty for batches already in stock
+    eta: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RowError {
+    // Line in the file, the header being line 1
+    pub line: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    pub imported: Vec<BatchId>,
+    pub errors: Vec<RowError>,
+}
+
+// Registers the batches of a `ref,sku,qty,eta` CSV file. Rows that can't be
+// parsed or fail validation are reported and skipped, the rest are stored
+// together.
+pub async fn import_batches_from_csv(
+    path: impl AsRef<Path>,
+    repo: &PostgresBatchRepository,
+) -> anyhow::Result<ImportReport> {
+    let contents = tokio::fs::read(path).await?;
+    let (batches, errors) = parse_batches(&contents)?;
+    Ok(ImportReport {
+        imported: repo.add_batches(&batches).await?,
+        errors,
+    })
+}
+
+// The parsing half of `import_batches_from_csv`, fed untrusted bytes by the
+// `parse_csv` fuzz target. A file that isn't valid CSV or UTF-8 is an error,
+// a bad row only gets reported.
+pub fn parse_batches(
+    contents: &[u8],
+) -> anyhow::Result<(Vec<Batch>, Vec<RowError>)> {
+    let mut reader = csv::Reader::from_reader(contents);
+    let headers = reader.headers()?.clone();
+
+    let mut errors = Vec::new();
+    let mut batches = Vec::new();
+    for record in reader.records() {
+        let record = match record {
+            Ok(record) => record,
+            Err(err) if matches!(err.kind(), csv::ErrorKind::Utf8 { .. }) => {
+                return Err(err.into());
+            }
+            // Such as a row with more or fewer fields than the header
+            Err(err) => {
+                let line = err.position().map_or(0, |position| position.line());
+                let message = err.to_string();
+                errors.push(RowError { line, message });
+                continue;
+            }
+        };
+        let line = record.position().map_or(0, |position| position.line());
+        // Postgres text can't hold NUL, so the whole import would fail later
+        if record.iter().any(|field| field.contains('\0')) {
+            let message = "field contains a NUL character".to_string();
+            errors.push(RowError { line, message });
+            continue;
+        }
+        let parsed = record
+            .deserialize::<CsvRow>(Some(&headers))
+            .map_err(|err| err.to_string())
+            .and_then(|row| {
+                if row.qty > MAX_QTY {
+                    return Err(format!(
+                        "qty {} is more than the {} a batch can hold",
+                        row.qty, MAX_QTY
+                    ));
+                }
+                let mut batch = Batch::new(row.sku, row.qty, row.eta)
+                    .map_err(|err| err.to_string())?;
+                batch.reference = Some(row.reference);
+                Ok(batch)
+            });
+        match parsed {
+            Ok(batch) => batches.push(batch),
+            Err(message) => errors.push(RowError { line, message }),
+        }
+    }
+    Ok((batches, errors))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use sqlx::postgres::PgPool;
+
+    #[test]
+    fn test_malformed_input_is_an_error_not_a_panic() {
+        let (batches, errors) = parse_batches(
+            b"ref,sku,qty,eta\nBATCH_1,SMALL\0TABLE,5,\n\
+              BATCH_2,SMALL_TABLE,99999999999999999999,\n\
+              BATCH_3,SMALL_TABLE,5,+999999-01-01T00:00:00Z\n\
+              BATCH_4,SMALL_TABLE,3000000000,\n",
+        )
+        .unwrap();
+        assert!(batches.is_empty());
+        assert_eq!(errors.len(), 4);
+        assert_eq!(
+            errors[3].message,
+            "qty 3000000000 is more than the 2147483647 a batch can hold"
+        );
+        assert_eq!(errors[0].message, "field contains a NUL character");
+
+        let (batches, errors) = parse_batches(
+            b"ref,sku,qty,eta\nBATCH_1,SMALL_TABLE,5\nBATCH_2,SMALL_TABLE,5,\n",
+        )
+        .unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(errors[0].line, 2);
+
+        assert!(
+            parse_batches(b"ref,sku,qty,eta\nBATCH_1,\xff\xfe,5,\n").is_err()
+        );
+        assert!(parse_batches(b"").unwrap().0.is_empty());
+    }
+
+    #[sqlx::test]
+    async fn test_good_rows_are_imported_and_bad_ones_reported(
+        pg_pool: PgPool,
+    ) {
+        let repo = PostgresBatchRepository::new(pg_pool);
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/fixtures/batches.csv");
+
+        let report = import_batches_from_csv(path, &repo).await.unwrap();
+
+        assert_eq!(report.imported.len(), 3);
+        assert_eq!(report.errors.len(), 2);
+        assert_eq!(report.errors[0].line, 4);
+        assert_eq!(report.errors[1].line, 6);
+        assert!(report.errors[1].message.contains("fields"));
+        let tables = repo
+            .list_batches(Some("SMALL_TABLE"), None, None, 10, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(tables.len(), 2);
+        assert_eq!(tables[0].reference, Some("BATCH_1".to_string()));
+        assert!(tables[1].eta.is_some());
+        let chairs = repo
+            .list_batches(Some("BLUE_CHAIR"), None, None, 10, 0, false)
+            .await
+            .unwrap();
+        assert_eq!(chairs.len(), 1);
+        assert_eq!(chairs[0].qty, 12);
+    }
+}
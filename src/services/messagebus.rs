@@ -0,0 +1,315 @@
+use crate::domain::commands::{Command, CommandOutcome};
+use crate::domain::events::{DomainEvent, EventKind, Publisher};
+use crate::domain::tenant::TenantId;
+use crate::services::handlers;
+use crate::services::unit_of_work::UnitOfWork;
+use async_trait::async_trait;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::pin::Pin;
+
+// Event handlers are given the tenant the event was raised for and resolve to
+// the events raised by the work they did, which are handled in turn
+pub type HandlerFuture =
+    Pin<Box<dyn Future<Output = anyhow::Result<Vec<DomainEvent>>> + Send>>;
+pub type EventHandler =
+    Box<dyn Fn(TenantId, DomainEvent) -> HandlerFuture + Send + Sync>;
+
+// Commands go to the one handler able to carry them out and their errors are
+// returned to the caller. Events are fanned out to every handler registered
+// for their kind on a best-effort basis, a failing handler is logged and
+// doesn't stop the others.
+#[derive(Default)]
+pub struct MessageBus {
+    handlers: HashMap<EventKind, Vec<EventHandler>>,
+}
+
+impl MessageBus {
+    pub fn new() -> MessageBus {
+        MessageBus::default()
+    }
+
+    pub fn register(&mut self, kind: EventKind, handler: EventHandler) {
+        self.handlers.entry(kind).or_default().push(handler);
+    }
+
+    // Events raised while handling the command are published once it has
+    // finished, whether it succeeded or not
+    pub async fn handle_command(
+        &self,
+        command: Command,
+        uow: &mut dyn UnitOfWork,
+    ) -> anyhow::Result<CommandOutcome> {
+        let result = match command {
+            Command::Allocate {
+                order_ref,
+                sku,
+                qty,
+            } => handlers::allocate(order_ref, sku, qty, uow)
+                .await
+                .map(|batch_id| CommandOutcome::Allocated { batch_id }),
+            Command::CreateBatch {
+                reference,
+                sku,
+                qty,
+                eta,
+            } => handlers::add_batch(reference, sku, qty, eta, uow)
+                .await
+                .map(|()| CommandOutcome::Done),
+            Command::ChangeBatchQuantity { batch_id, qty } => {
+                handlers::change_batch_quantity(batch_id, qty, uow)
+                    .await
+                    .map(|()| CommandOutcome::Done)
+            }
+            Command::CancelAllocation { order_ref } => {
+                handlers::cancel_allocation(order_ref, uow)
+                    .await
+                    .map(|()| CommandOutcome::Done)
+            }
+        };
+
+        let tenant = uow.tenant().clone();
+        self.handle_events(&tenant, uow.collect_new_events()).await;
+        result
+    }
+
+    pub async fn handle_event(&self, tenant: &TenantId, event: DomainEvent) {
+        self.handle_events(tenant, vec![event]).await;
+    }
+
+    // Feed events collected from one of the tenant's aggregates through the
+    // bus, along with any raised by their handlers
+    pub async fn handle_events(
+        &self,
+        tenant: &TenantId,
+        events: Vec<DomainEvent>,
+    ) {
+        let mut queue = VecDeque::from(events);
+        while let Some(event) = queue.pop_front() {
+            queue.extend(self.dispatch(tenant, event).await);
+        }
+    }
+
+    async fn dispatch(
+        &self,
+        tenant: &TenantId,
+        event: DomainEvent,
+    ) -> Vec<DomainEvent> {
+        let Some(handlers) = self.handlers.get(&event.kind()) else {
+            return Vec::new();
+        };
+        let mut raised = Vec::new();
+        for handler in handlers {
+            match handler(tenant.clone(), event.clone()).await {
+                Ok(events) => raised.extend(events),
+                Err(err) => {
+                    tracing::error!(
+                        ?event,
+                        %tenant,
+                        %err,
+                        "event handler failed"
+                    )
+                }
+            }
+        }
+        raised
+    }
+}
+
+// Handler failures are logged by the bus, so publishing never fails.
+#[async_trait]
+impl Publisher for MessageBus {
+    async fn publish(
+        &self,
+        tenant: &TenantId,
+        event: &DomainEvent,
+    ) -> anyhow::Result<()> {
+        self.handle_event(tenant, event.clone()).await;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::{
+        AllocationError, Batch, BatchId, OrderLine, Product,
+    };
+    use crate::services::handlers::InvalidSku;
+    use crate::services::unit_of_work::fake::FakeUnitOfWork;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    fn counting_handler(count: &Arc<AtomicUsize>) -> EventHandler {
+        let counter = count.clone();
+        Box::new(move |_, _| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Ok(vec![]) })
+        })
+    }
+
+    fn failing_handler() -> EventHandler {
+        Box::new(|_, _| Box::pin(async { Err(anyhow::anyhow!("boom")) }))
+    }
+
+    fn allocate(sku: &str, qty: u32) -> Command {
+        Command::Allocate {
+            order_ref: "ORDER_1".to_string(),
+            sku: sku.to_string(),
+            qty,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_handler_fires_for_each_out_of_stock_event() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(EventKind::OutOfStock, counting_handler(&count));
+
+        let out_of_stock = DomainEvent::OutOfStock {
+            order_ref: "ORDER_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 1,
+        };
+        bus.handle_event(&TenantId::default(), out_of_stock.clone())
+            .await;
+        bus.handle_event(&TenantId::default(), out_of_stock).await;
+        bus.handle_event(
+            &TenantId::default(),
+            DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 1,
+                batch_id: BatchId(1),
+            },
+        )
+        .await;
+
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_collected_events_are_fed_through_the_bus() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(EventKind::OutOfStock, counting_handler(&count));
+
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 1, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let order = OrderLine::new("ORDER_1".to_string(), sku, 10).unwrap();
+
+        assert!(product.allocate(&order, None).is_err());
+        bus.handle_events(&TenantId::default(), product.collect_new_events())
+            .await;
+
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failing_event_handler_does_not_stop_the_others() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(EventKind::OutOfStock, failing_handler());
+        bus.register(EventKind::OutOfStock, counting_handler(&count));
+
+        bus.handle_event(
+            &TenantId::default(),
+            DomainEvent::OutOfStock {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 1,
+            },
+        )
+        .await;
+
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_command_returns_its_handler_outcome() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(EventKind::Allocated, counting_handler(&count));
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let outcome = bus.handle_command(allocate("SMALL_TABLE", 3), &mut uow);
+
+        assert_eq!(
+            outcome.await.unwrap(),
+            CommandOutcome::Allocated {
+                batch_id: BatchId(1)
+            }
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert!(uow.committed);
+    }
+
+    #[tokio::test]
+    async fn test_command_errors_are_propagated() {
+        let bus = MessageBus::new();
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let err = bus
+            .handle_command(allocate("UNKNOWN", 3), &mut uow)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<InvalidSku>(),
+            Some(&InvalidSku("UNKNOWN".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_events_of_failed_command_are_still_published() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(EventKind::OutOfStock, counting_handler(&count));
+        let mut uow = FakeUnitOfWork::with_batch("SMALL_TABLE", 10);
+
+        let err = bus
+            .handle_command(allocate("SMALL_TABLE", 30), &mut uow)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err.downcast_ref::<AllocationError>(),
+            Some(&AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_events_raised_by_handlers_are_handled_in_turn() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        bus.register(
+            EventKind::Deallocated,
+            Box::new(|_, _| {
+                Box::pin(async {
+                    Ok(vec![DomainEvent::OutOfStock {
+                        order_ref: "ORDER_1".to_string(),
+                        sku: "SMALL_TABLE".to_string(),
+                        qty: 1,
+                    }])
+                })
+            }),
+        );
+        bus.register(EventKind::OutOfStock, counting_handler(&count));
+
+        bus.handle_event(
+            &TenantId::default(),
+            DomainEvent::Deallocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 1,
+                cancelled: false,
+            },
+        )
+        .await;
+
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,9 @@
+pub mod bootstrap;
+pub mod handlers;
+pub mod import;
+pub mod messagebus;
+pub mod pending;
+pub mod relay;
+pub mod reservations;
+pub mod unit_of_work;
+pub mod views;
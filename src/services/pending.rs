@@ -0,0 +1,167 @@
+use crate::domain::events::DomainEvent;
+use crate::domain::model::{OrderLine, Sku};
+use crate::domain::tenant::TenantId;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+
+// Lines of the SKU that ran out of stock and haven't been allocated since,
+// oldest first
+pub async fn pending_lines(
+    sku: &str,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<Vec<OrderLine>> {
+    let rows = sqlx::query(
+        "SELECT order_ref, sku, qty FROM pending_lines
+         WHERE sku = $1 AND tenant_id = $2 ORDER BY created_at, order_ref",
+    )
+    .bind(sku)
+    .bind(tenant.as_str())
+    .fetch_all(pg_pool)
+    .await?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(OrderLine {
+                id: None,
+                order_ref: row.get("order_ref"),
+                sku: Sku::try_from(row.get::<String, _>("sku"))?,
+                qty: row.get::<i32, _>("qty") as u32,
+            })
+        })
+        .collect()
+}
+
+// Event handler tracking which lines are waiting for stock
+pub async fn update_pending_lines(
+    event: &DomainEvent,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<()> {
+    match event {
+        DomainEvent::OutOfStock {
+            order_ref,
+            sku,
+            qty,
+        } => {
+            sqlx::query(
+                "INSERT INTO pending_lines (order_ref, sku, qty, tenant_id)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (tenant_id, order_ref, sku)
+                 DO UPDATE SET qty = EXCLUDED.qty",
+            )
+            .bind(order_ref)
+            .bind(sku)
+            .bind(i32::try_from(*qty)?)
+            .bind(tenant.as_str())
+            .execute(pg_pool)
+            .await?;
+        }
+        DomainEvent::Allocated { order_ref, sku, .. } => {
+            sqlx::query(
+                "DELETE FROM pending_lines
+                 WHERE order_ref = $1 AND sku = $2 AND tenant_id = $3",
+            )
+            .bind(order_ref)
+            .bind(sku)
+            .bind(tenant.as_str())
+            .execute(pg_pool)
+            .await?;
+        }
+        // Its lines waiting for stock mustn't be allocated any more
+        DomainEvent::OrderCancelled { order_ref } => {
+            sqlx::query(
+                "DELETE FROM pending_lines
+                 WHERE order_ref = $1 AND tenant_id = $2",
+            )
+            .bind(order_ref)
+            .bind(tenant.as_str())
+            .execute(pg_pool)
+            .await?;
+        }
+        DomainEvent::Deallocated { .. }
+        | DomainEvent::BatchCreated { .. }
+        | DomainEvent::BatchQuantityIncreased { .. }
+        | DomainEvent::OverAllocated { .. }
+        | DomainEvent::ReservationReleased { .. } => {}
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::BatchId;
+
+    fn out_of_stock(order_ref: &str, qty: u32) -> DomainEvent {
+        DomainEvent::OutOfStock {
+            order_ref: order_ref.to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty,
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_out_of_stock_lines_stay_pending_until_allocated(
+        pg_pool: PgPool,
+    ) {
+        let allocated = DomainEvent::Allocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 5,
+            batch_id: BatchId(1),
+        };
+        let tenant = TenantId::default();
+        let other = TenantId::try_from("acme").unwrap();
+        for event in [out_of_stock("ORDER_1", 5), out_of_stock("ORDER_2", 3)] {
+            update_pending_lines(&event, &tenant, &pg_pool)
+                .await
+                .unwrap();
+        }
+        let pending = pending_lines("SMALL_TABLE", &tenant, &pg_pool).await;
+        assert_eq!(pending.unwrap().len(), 2);
+        let pending = pending_lines("SMALL_TABLE", &other, &pg_pool).await;
+        assert!(pending.unwrap().is_empty());
+
+        update_pending_lines(&allocated, &tenant, &pg_pool)
+            .await
+            .unwrap();
+
+        let pending = pending_lines("SMALL_TABLE", &tenant, &pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(
+            pending,
+            vec![OrderLine::new(
+                "ORDER_2".to_string(),
+                "SMALL_TABLE".to_string(),
+                3
+            )
+            .unwrap()]
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_cancelling_an_order_drops_its_pending_lines(pg_pool: PgPool) {
+        let tenant = TenantId::default();
+        for event in [out_of_stock("ORDER_1", 5), out_of_stock("ORDER_2", 3)] {
+            update_pending_lines(&event, &tenant, &pg_pool)
+                .await
+                .unwrap();
+        }
+        let cancelled = DomainEvent::OrderCancelled {
+            order_ref: "ORDER_1".to_string(),
+        };
+
+        update_pending_lines(&cancelled, &tenant, &pg_pool)
+            .await
+            .unwrap();
+
+        let pending = pending_lines("SMALL_TABLE", &tenant, &pg_pool)
+            .await
+            .unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].order_ref, "ORDER_2");
+    }
+}
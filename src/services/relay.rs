@@ -0,0 +1,183 @@
+use crate::domain::events::Publisher;
+use crate::infrastructure::outbox;
+use sqlx::postgres::PgPool;
+use std::time::Duration;
+
+pub const RELAY_INTERVAL: Duration = Duration::from_secs(1);
+const RELAY_BATCH_SIZE: i64 = 100;
+
+// Publishes one batch of unsent outbox events in order, marking them sent.
+// Returns how many were published. Publishing stops at the first failure,
+// the events from there on are retried on the next call.
+pub async fn publish_pending(
+    pg_pool: &PgPool,
+    publisher: &dyn Publisher,
+) -> anyhow::Result<usize> {
+    let mut tx = pg_pool.begin().await?;
+    let mut sent = Vec::new();
+    let mut failure = None;
+    for (id, tenant, event) in outbox::unsent(&mut tx, RELAY_BATCH_SIZE).await?
+    {
+        match publisher.publish(&tenant, &event).await {
+            Ok(()) => sent.push(id),
+            Err(err) => {
+                failure = Some(err);
+                break;
+            }
+        }
+    }
+    outbox::mark_sent(&mut tx, &sent).await?;
+    tx.commit().await?;
+
+    match failure {
+        Some(err) => Err(err),
+        None => Ok(sent.len()),
+    }
+}
+
+// Polls the outbox forever. A message bus used as the publisher should only
+// carry handlers for external systems: in-process handlers already saw the
+// events when they were raised.
+pub async fn run(
+    pg_pool: PgPool,
+    publisher: Box<dyn Publisher>,
+    interval: Duration,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        loop {
+            match publish_pending(&pg_pool, publisher.as_ref()).await {
+                Ok(count) if count as i64 == RELAY_BATCH_SIZE => continue,
+                Ok(_) => break,
+                Err(err) => {
+                    tracing::error!(%err, "failed to relay outbox events");
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::events::{DomainEvent, EventKind};
+    use crate::domain::model::BatchId;
+    use crate::domain::tenant::TenantId;
+    use crate::infrastructure::repository::fixtures::seed_batch;
+    use crate::services::handlers;
+    use crate::services::messagebus::{EventHandler, MessageBus};
+    use crate::services::unit_of_work::PostgresUnitOfWork;
+    use std::sync::{Arc, Mutex};
+
+    async fn allocate(pg_pool: &PgPool) -> anyhow::Result<BatchId> {
+        let mut uow = PostgresUnitOfWork::begin(pg_pool).await?;
+        handlers::allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            3,
+            &mut uow,
+        )
+        .await
+    }
+
+    async fn outbox_events(pg_pool: &PgPool) -> Vec<DomainEvent> {
+        let mut conn = pg_pool.acquire().await.unwrap();
+        outbox::unsent(&mut conn, 100)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(_, _, event)| event)
+            .collect()
+    }
+
+    async fn allocation_count(pg_pool: &PgPool) -> i64 {
+        sqlx::query_scalar("SELECT count(*) FROM allocations")
+            .fetch_one(pg_pool)
+            .await
+            .unwrap()
+    }
+
+    #[sqlx::test]
+    async fn test_allocation_events_land_in_the_outbox(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let batch_id = allocate(&pg_pool).await.unwrap();
+
+        assert_eq!(
+            outbox_events(&pg_pool).await,
+            vec![DomainEvent::Allocated {
+                order_ref: "ORDER_1".to_string(),
+                sku: "SMALL_TABLE".to_string(),
+                qty: 3,
+                batch_id,
+            }]
+        );
+        assert_eq!(allocation_count(&pg_pool).await, 1);
+    }
+
+    #[sqlx::test]
+    async fn test_failing_outbox_write_rolls_back_the_allocation(
+        pg_pool: PgPool,
+    ) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        sqlx::query(
+            "ALTER TABLE outbox ADD CONSTRAINT no_events CHECK (false)",
+        )
+        .execute(&pg_pool)
+        .await
+        .unwrap();
+
+        assert!(allocate(&pg_pool).await.is_err());
+
+        assert_eq!(allocation_count(&pg_pool).await, 0);
+    }
+
+    #[sqlx::test]
+    async fn test_relay_publishes_unsent_events_once(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        allocate(&pg_pool).await.unwrap();
+        let published = Arc::new(Mutex::new(Vec::new()));
+        let mut bus = MessageBus::new();
+        let handler: EventHandler = Box::new({
+            let published = published.clone();
+            move |tenant, event| {
+                published.lock().unwrap().push((tenant, event));
+                Box::pin(async { Ok(vec![]) })
+            }
+        });
+        bus.register(EventKind::Allocated, handler);
+
+        assert_eq!(publish_pending(&pg_pool, &bus).await.unwrap(), 1);
+        assert_eq!(publish_pending(&pg_pool, &bus).await.unwrap(), 0);
+
+        assert!(outbox_events(&pg_pool).await.is_empty());
+        let published = published.lock().unwrap();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].0, TenantId::default());
+    }
+
+    struct FailingPublisher;
+
+    #[async_trait::async_trait]
+    impl Publisher for FailingPublisher {
+        async fn publish(
+            &self,
+            _: &TenantId,
+            _: &DomainEvent,
+        ) -> anyhow::Result<()> {
+            Err(anyhow::anyhow!("broker unavailable"))
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_events_that_fail_to_publish_stay_unsent(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        allocate(&pg_pool).await.unwrap();
+
+        assert!(publish_pending(&pg_pool, &FailingPublisher).await.is_err());
+
+        assert_eq!(outbox_events(&pg_pool).await.len(), 1);
+    }
+}
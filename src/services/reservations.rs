@@ -0,0 +1,223 @@
+use crate::domain::clock::Clock;
+use crate::domain::model::Reservation;
+use crate::domain::tenant::TenantId;
+use crate::services::messagebus::MessageBus;
+use crate::services::unit_of_work::UnitOfWorkFactory;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+pub const RESERVATION_TTL: Duration = Duration::from_secs(15 * 60);
+
+// Releases every reservation of the tenant older than `ttl` in one unit of
+// work, then runs the `ReservationReleased` events through the bus so the
+// freed stock can go to lines waiting for it. Returns the released
+// reservations.
+pub async fn sweep(
+    uow: &dyn UnitOfWorkFactory,
+    tenant: &TenantId,
+    bus: &MessageBus,
+    ttl: Duration,
+    clock: &dyn Clock,
+) -> anyhow::Result<Vec<Reservation>> {
+    let cutoff = clock.now() - chrono::Duration::from_std(ttl)?;
+    let mut uow = uow.begin_for(tenant).await?;
+    let mut released = Vec::new();
+    for mut product in
+        uow.products().get_with_reservations_before(cutoff).await?
+    {
+        released.extend(product.release_unconfirmed(cutoff));
+        uow.products().add(&product).await?;
+        uow.record_events(product.collect_new_events());
+    }
+    uow.commit().await?;
+
+    bus.handle_events(tenant, uow.collect_new_events()).await;
+    Ok(released)
+}
+
+// Sweeps every tenant in turn, forever, every `interval`. A tenant failing
+// doesn't hold up the others.
+pub async fn run(
+    uow: Arc<dyn UnitOfWorkFactory>,
+    bus: Arc<MessageBus>,
+    ttl: Duration,
+    interval: Duration,
+    clock: Arc<dyn Clock>,
+) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        let tenants = match uow.tenants().await {
+            Ok(tenants) => tenants,
+            Err(err) => {
+                tracing::error!(%err, "failed to list tenants to sweep");
+                continue;
+            }
+        };
+        for tenant in tenants {
+            match sweep(uow.as_ref(), &tenant, &bus, ttl, clock.as_ref()).await
+            {
+                Ok(released) if !released.is_empty() => {
+                    tracing::info!(
+                        %tenant,
+                        count = released.len(),
+                        "released expired reservations"
+                    );
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    tracing::error!(
+                        %tenant,
+                        %err,
+                        "failed to release reservations"
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::clock::FixedClock;
+    use crate::domain::events::EventKind;
+    use crate::domain::model::{
+        Batch, BatchId, OrderLine, Product, ReservationId,
+    };
+    use crate::domain::repository::ProductRepository;
+    use crate::infrastructure::repository::PostgresBatchRepository;
+    use crate::services::messagebus::EventHandler;
+    use crate::services::unit_of_work::InMemoryStore;
+    use chrono::{DateTime, Local};
+    use sqlx::postgres::PgPool;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // One reservation of 4 made at `reserved_at`, one confirmed allocation
+    // of 2
+    fn store_with_reservation(reserved_at: DateTime<Local>) -> InMemoryStore {
+        let sku = "SMALL_TABLE".to_string();
+        let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let mut product = Product::new(sku.clone(), vec![batch]).unwrap();
+        let clock = FixedClock(reserved_at);
+        let line = |order_ref: &str, qty| {
+            OrderLine::new(order_ref.to_string(), sku.clone(), qty).unwrap()
+        };
+        product.reserve_at(&line("ORDER_1", 4), &clock).unwrap();
+        let confirmed = product.reserve_at(&line("ORDER_2", 2), &clock);
+        product.confirm(confirmed.unwrap()).unwrap();
+        product.collect_new_events();
+        InMemoryStore::with_products(vec![product])
+    }
+
+    async fn available_qty(store: &InMemoryStore) -> u32 {
+        let product = store.products().get("SMALL_TABLE").await.unwrap();
+        product.unwrap().batches[0].available_qty()
+    }
+
+    #[tokio::test]
+    async fn test_sweep_releases_reservations_older_than_the_ttl() {
+        let now = Local::now();
+        let store = store_with_reservation(now);
+        let released_events = Arc::new(AtomicUsize::new(0));
+        let mut bus = MessageBus::new();
+        let handler: EventHandler = Box::new({
+            let released_events = released_events.clone();
+            move |_, _| {
+                released_events.fetch_add(1, Ordering::SeqCst);
+                Box::pin(async { Ok(vec![]) })
+            }
+        });
+        bus.register(EventKind::ReservationReleased, handler);
+        let ttl = Duration::from_secs(60);
+
+        let early = FixedClock(now + chrono::Duration::seconds(59));
+        let released = sweep(&store, &TenantId::default(), &bus, ttl, &early)
+            .await
+            .unwrap();
+        assert!(released.is_empty());
+        assert_eq!(available_qty(&store).await, 4);
+
+        let late = FixedClock(now + chrono::Duration::seconds(61));
+        let released = sweep(&store, &TenantId::default(), &bus, ttl, &late)
+            .await
+            .unwrap();
+
+        assert_eq!(released.len(), 1);
+        assert_eq!(released[0].id, ReservationId(1));
+        assert_eq!(available_qty(&store).await, 8);
+        assert_eq!(released_events.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_unconfirmed_reservation_is_released_automatically() {
+        let now = Local::now();
+        let store = Arc::new(store_with_reservation(now));
+        let ttl = Duration::from_secs(60);
+        let later = FixedClock(now + chrono::Duration::minutes(5));
+
+        let sweeper = tokio::spawn(run(
+            store.clone(),
+            Arc::new(MessageBus::new()),
+            ttl,
+            Duration::from_millis(10),
+            Arc::new(later),
+        ));
+        let released = tokio::time::timeout(Duration::from_secs(5), async {
+            while available_qty(&store).await != 8 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        sweeper.abort();
+
+        assert!(released.is_ok());
+        let product = store.products().get("SMALL_TABLE").await.unwrap();
+        let batch = &product.unwrap().batches[0];
+        assert!(batch.reserved.is_empty());
+        assert_eq!(batch.total_allocated_qty(), 2);
+    }
+
+    #[sqlx::test]
+    async fn test_sweep_releases_stored_reservations(pg_pool: PgPool) {
+        let now = Local::now();
+        let sku = "SMALL_TABLE".to_string();
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let batch = Batch::new(sku.clone(), 10, None).unwrap();
+        repo.add(&Product::new(sku.clone(), vec![batch]).unwrap())
+            .await
+            .unwrap();
+        let mut product = repo.get(&sku).await.unwrap().unwrap();
+        let line = OrderLine::new("ORDER_1".to_string(), sku, 2).unwrap();
+        product.reserve_at(&line, &FixedClock(now)).unwrap();
+        repo.add(&product).await.unwrap();
+        let later = FixedClock(now + chrono::Duration::minutes(5));
+
+        let released = sweep(
+            &pg_pool,
+            &TenantId::default(),
+            &MessageBus::new(),
+            RESERVATION_TTL,
+            &later,
+        )
+        .await
+        .unwrap();
+        assert!(released.is_empty());
+        let released = sweep(
+            &pg_pool,
+            &TenantId::default(),
+            &MessageBus::new(),
+            Duration::from_secs(60),
+            &later,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(released.len(), 1);
+        let product = repo.get("SMALL_TABLE").await.unwrap().unwrap();
+        assert!(product.batches[0].reserved.is_empty());
+        assert_eq!(product.batches[0].available_qty(), 10);
+    }
+}
@@ -0,0 +1,798 @@
+use crate::domain::events::DomainEvent;
+use crate::domain::model::{BatchId, Product};
+use crate::domain::repository::fake::FakeProductRepository;
+use crate::domain::repository::{ProductRepository, RepositoryError};
+use crate::domain::tenant::TenantId;
+use crate::infrastructure::db::IsolationLevel;
+use crate::infrastructure::repository::PostgresTransactionRepository;
+use async_trait::async_trait;
+use chrono::{DateTime, Local};
+use sqlx::postgres::PgPool;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+// Work that isn't explicitly committed is rolled back when the unit of work
+// is dropped. Handlers record the events raised by the aggregates they touch
+// so the caller can publish them once the command has finished. Events
+// recorded before a commit are part of it: if the commit fails they are
+// dropped rather than published. Everything it reads and writes belongs to
+// its tenant.
+#[async_trait]
+pub trait UnitOfWork: Send + Sync {
+    fn tenant(&self) -> &TenantId;
+    fn products(&self) -> &dyn ProductRepository;
+    fn record_events(&mut self, events: Vec<DomainEvent>);
+    fn collect_new_events(&mut self) -> Vec<DomainEvent>;
+    async fn commit(&mut self) -> anyhow::Result<()>;
+    async fn rollback(&mut self) -> anyhow::Result<()>;
+}
+
+// Begins units of work against whichever store the app was started with.
+// `begin` works on the default tenant.
+#[async_trait]
+pub trait UnitOfWorkFactory: Send + Sync {
+    // Stores without transactions of their own ignore the level
+    async fn begin_with(
+        &self,
+        tenant: &TenantId,
+        isolation: IsolationLevel,
+    ) -> anyhow::Result<Box<dyn UnitOfWork>>;
+
+    // Every tenant with stored products, for jobs working across all of them
+    async fn tenants(&self) -> anyhow::Result<Vec<TenantId>>;
+
+    async fn begin(&self) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        self.begin_for(&TenantId::default()).await
+    }
+
+    async fn begin_for(
+        &self,
+        tenant: &TenantId,
+    ) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        self.begin_with(tenant, IsolationLevel::default()).await
+    }
+}
+
+// Recorded as the actor in the audit trail unless a more specific one is
+// given
+pub const SYSTEM_ACTOR: &str = "system";
+
+pub struct PostgresUnitOfWork {
+    products: PostgresTransactionRepository,
+    events: Vec<DomainEvent>,
+    actor: String,
+}
+
+impl PostgresUnitOfWork {
+    pub async fn begin(pg_pool: &PgPool) -> anyhow::Result<Self> {
+        Self::begin_as(pg_pool, &TenantId::default(), SYSTEM_ACTOR).await
+    }
+
+    // `actor` is who or what the allocations changed by this unit of work
+    // are attributed to in the audit trail
+    pub async fn begin_as(
+        pg_pool: &PgPool,
+        tenant: &TenantId,
+        actor: &str,
+    ) -> anyhow::Result<Self> {
+        Self::begin_with(pg_pool, tenant, actor, IsolationLevel::default())
+            .await
+    }
+
+    pub async fn begin_with(
+        pg_pool: &PgPool,
+        tenant: &TenantId,
+        actor: &str,
+        isolation: IsolationLevel,
+    ) -> anyhow::Result<Self> {
+        let products = PostgresTransactionRepository::begin_with(
+            pg_pool,
+            tenant.clone(),
+            isolation,
+        )
+        .await?;
+        Ok(Self {
+            products,
+            events: Vec::new(),
+            actor: actor.to_string(),
+        })
+    }
+
+    async fn add_to_logs(&self) -> Result<(), RepositoryError> {
+        self.products.add_to_outbox(&self.events).await?;
+        self.products
+            .add_to_audit_trail(&self.events, &self.actor)
+            .await
+    }
+}
+
+#[async_trait]
+impl UnitOfWork for PostgresUnitOfWork {
+    fn tenant(&self) -> &TenantId {
+        self.products.tenant()
+    }
+
+    fn products(&self) -> &dyn ProductRepository {
+        &self.products
+    }
+
+    fn record_events(&mut self, events: Vec<DomainEvent>) {
+        self.events.extend(events);
+    }
+
+    fn collect_new_events(&mut self) -> Vec<DomainEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Recorded events are written to the outbox and the audit trail in the
+    // same transaction
+    async fn commit(&mut self) -> anyhow::Result<()> {
+        let result = match self.add_to_logs().await {
+            Ok(()) => self.products.commit().await,
+            Err(err) => Err(err),
+        };
+        if result.is_err() {
+            self.events.clear();
+        }
+        Ok(result?)
+    }
+
+    async fn rollback(&mut self) -> anyhow::Result<()> {
+        Ok(self.products.rollback().await?)
+    }
+}
+
+#[async_trait]
+impl UnitOfWorkFactory for PgPool {
+    async fn begin_with(
+        &self,
+        tenant: &TenantId,
+        isolation: IsolationLevel,
+    ) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        let uow = PostgresUnitOfWork::begin_with(
+            self,
+            tenant,
+            SYSTEM_ACTOR,
+            isolation,
+        )
+        .await?;
+        Ok(Box::new(uow))
+    }
+
+    async fn tenants(&self) -> anyhow::Result<Vec<TenantId>> {
+        let tenants: Vec<String> = sqlx::query_scalar(
+            "SELECT DISTINCT tenant_id FROM products ORDER BY tenant_id",
+        )
+        .fetch_all(self)
+        .await?;
+        Ok(tenants
+            .into_iter()
+            .map(TenantId::try_from)
+            .collect::<Result<_, _>>()?)
+    }
+}
+
+// Products shared by every unit of work when running with
+// `REPOSITORY=memory`, kept apart per tenant. Nothing survives a restart.
+#[derive(Clone, Default)]
+pub struct InMemoryStore {
+    products: Arc<FakeProductRepository>,
+    tenants: Arc<Mutex<HashMap<TenantId, Arc<FakeProductRepository>>>>,
+}
+
+impl InMemoryStore {
+    // Seeds the default tenant
+    pub fn with_products(products: Vec<Product>) -> Self {
+        Self {
+            products: Arc::new(FakeProductRepository::with_products(products)),
+            tenants: Arc::default(),
+        }
+    }
+
+    // The default tenant's products
+    pub fn products(&self) -> &dyn ProductRepository {
+        self.products.as_ref()
+    }
+
+    fn store(&self, tenant: &TenantId) -> Arc<FakeProductRepository> {
+        if *tenant == TenantId::default() {
+            return self.products.clone();
+        }
+        self.tenants
+            .lock()
+            .unwrap()
+            .entry(tenant.clone())
+            .or_default()
+            .clone()
+    }
+}
+
+#[async_trait]
+impl UnitOfWorkFactory for InMemoryStore {
+    async fn begin_with(
+        &self,
+        tenant: &TenantId,
+        _isolation: IsolationLevel,
+    ) -> anyhow::Result<Box<dyn UnitOfWork>> {
+        Ok(Box::new(InMemoryUnitOfWork {
+            tenant: tenant.clone(),
+            products: StagedProductRepository {
+                store: self.store(tenant),
+                staged: FakeProductRepository::default(),
+            },
+            events: Vec::new(),
+        }))
+    }
+
+    async fn tenants(&self) -> anyhow::Result<Vec<TenantId>> {
+        let mut tenants: Vec<TenantId> =
+            self.tenants.lock().unwrap().keys().cloned().collect();
+        tenants.push(TenantId::default());
+        tenants.sort();
+        Ok(tenants)
+    }
+}
+
+// Reads see the products staged by this unit of work first
+struct StagedProductRepository {
+    store: Arc<FakeProductRepository>,
+    staged: FakeProductRepository,
+}
+
+#[async_trait]
+impl ProductRepository for StagedProductRepository {
+    async fn get(&self, sku: &str) -> Result<Option<Product>, RepositoryError> {
+        match self.staged.get(sku).await? {
+            Some(product) => Ok(Some(product)),
+            None => self.store.get(sku).await,
+        }
+    }
+
+    async fn get_by_batch_id(
+        &self,
+        batch_id: BatchId,
+    ) -> Result<Option<Product>, RepositoryError> {
+        match self.staged.get_by_batch_id(batch_id).await? {
+            Some(product) => Ok(Some(product)),
+            None => self.store.get_by_batch_id(batch_id).await,
+        }
+    }
+
+    async fn get_by_order_ref(
+        &self,
+        order_ref: &str,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut products = self.staged.get_by_order_ref(order_ref).await?;
+        for product in self.store.get_by_order_ref(order_ref).await? {
+            if self.staged.get(product.sku.as_str()).await?.is_none() {
+                products.push(product);
+            }
+        }
+        products.sort_by(|a, b| a.sku.cmp(&b.sku));
+        Ok(products)
+    }
+
+    async fn get_with_reservations_before(
+        &self,
+        reserved_before: DateTime<Local>,
+    ) -> Result<Vec<Product>, RepositoryError> {
+        let mut products = self
+            .staged
+            .get_with_reservations_before(reserved_before)
+            .await?;
+        for product in self
+            .store
+            .get_with_reservations_before(reserved_before)
+            .await?
+        {
+            if self.staged.get(product.sku.as_str()).await?.is_none() {
+                products.push(product);
+            }
+        }
+        products.sort_by(|a, b| a.sku.cmp(&b.sku));
+        Ok(products)
+    }
+
+    // Batch ids come from the shared store so they stay unique across units
+    // of work. The version is checked against the store on commit.
+    async fn add(&self, product: &Product) -> Result<(), RepositoryError> {
+        let mut product = product.clone();
+        self.store.assign_batch_ids(&mut product);
+        self.staged.stage(&product);
+        Ok(())
+    }
+}
+
+// Staged products replace the stored ones on commit. There is no locking,
+// a commit fails with `ConcurrencyError` if another one saved any of its
+// products first, and can be retried like against Postgres.
+pub struct InMemoryUnitOfWork {
+    tenant: TenantId,
+    products: StagedProductRepository,
+    events: Vec<DomainEvent>,
+}
+
+#[async_trait]
+impl UnitOfWork for InMemoryUnitOfWork {
+    fn tenant(&self) -> &TenantId {
+        &self.tenant
+    }
+
+    fn products(&self) -> &dyn ProductRepository {
+        &self.products
+    }
+
+    fn record_events(&mut self, events: Vec<DomainEvent>) {
+        self.events.extend(events);
+    }
+
+    fn collect_new_events(&mut self) -> Vec<DomainEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    async fn commit(&mut self) -> anyhow::Result<()> {
+        let staged = std::mem::take(&mut self.products.staged);
+        let result = self.products.store.add_all(&staged.all());
+        if result.is_err() {
+            self.events.clear();
+        }
+        Ok(result?)
+    }
+
+    async fn rollback(&mut self) -> anyhow::Result<()> {
+        self.products.staged = FakeProductRepository::default();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub mod fake {
+    use super::*;
+    use crate::domain::model::Batch;
+
+    #[derive(Default)]
+    pub struct FakeUnitOfWork {
+        pub tenant: TenantId,
+        pub products: FakeProductRepository,
+        pub committed: bool,
+        pub events: Vec<DomainEvent>,
+    }
+
+    impl FakeUnitOfWork {
+        pub fn with_products(products: Vec<Product>) -> Self {
+            Self {
+                tenant: TenantId::default(),
+                products: FakeProductRepository::with_products(products),
+                committed: false,
+                events: Vec::new(),
+            }
+        }
+
+        // One in-stock batch of `qty` with id 1
+        pub fn with_batch(sku: &str, qty: u32) -> Self {
+            let mut batch = Batch::new(sku.to_string(), qty, None).unwrap();
+            batch.id = Some(BatchId(1));
+            let product = Product::new(sku.to_string(), vec![batch]).unwrap();
+            Self::with_products(vec![product])
+        }
+    }
+
+    #[async_trait]
+    impl UnitOfWork for FakeUnitOfWork {
+        fn tenant(&self) -> &TenantId {
+            &self.tenant
+        }
+
+        fn products(&self) -> &dyn ProductRepository {
+            &self.products
+        }
+
+        fn record_events(&mut self, events: Vec<DomainEvent>) {
+            self.events.extend(events);
+        }
+
+        fn collect_new_events(&mut self) -> Vec<DomainEvent> {
+            std::mem::take(&mut self.events)
+        }
+
+        async fn commit(&mut self) -> anyhow::Result<()> {
+            self.committed = true;
+            Ok(())
+        }
+
+        async fn rollback(&mut self) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::domain::model::AllocationError;
+    use crate::domain::model::{Batch, OrderLine};
+    use crate::infrastructure::repository::fixtures::seed_batch;
+    use crate::infrastructure::repository::PostgresBatchRepository;
+    use crate::services::handlers;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    async fn allocated_qty(pg_pool: &PgPool, sku: &str) -> u32 {
+        let repo = PostgresBatchRepository::new(pg_pool.clone());
+        let product = repo.get(sku).await.unwrap().unwrap();
+        product
+            .batches
+            .iter()
+            .map(|batch| batch.qty - batch.available_qty())
+            .sum()
+    }
+
+    async fn allocate_in(uow: &dyn UnitOfWork, sku: &str) {
+        let mut product = uow.products().get(sku).await.unwrap().unwrap();
+        let line =
+            OrderLine::new("ORDER_1".to_string(), sku.to_string(), 5).unwrap();
+        product.allocate(&line, None).unwrap();
+        uow.products().add(&product).await.unwrap();
+    }
+
+    #[sqlx::test]
+    async fn test_uow_can_commit_an_allocation(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        allocate_in(&uow, "SMALL_TABLE").await;
+        uow.commit().await.unwrap();
+
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 5);
+    }
+
+    #[sqlx::test]
+    async fn test_uow_rolls_back_uncommitted_work_by_default(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        allocate_in(&uow, "SMALL_TABLE").await;
+        drop(uow);
+
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 0);
+    }
+
+    #[sqlx::test]
+    async fn test_uow_rolls_back_on_explicit_rollback(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        allocate_in(&uow, "SMALL_TABLE").await;
+        uow.rollback().await.unwrap();
+
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 0);
+        assert!(uow.commit().await.is_err());
+    }
+
+    #[sqlx::test]
+    async fn test_dry_run_leaves_the_database_unchanged(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        let dry_run = handlers::allocate_dry_run(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            5,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 0);
+
+        let mut uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        let batch_id = handlers::allocate(
+            "ORDER_1".to_string(),
+            "SMALL_TABLE".to_string(),
+            5,
+            &mut uow,
+        )
+        .await
+        .unwrap();
+        assert_eq!(dry_run, batch_id);
+    }
+
+    #[sqlx::test]
+    async fn test_concurrent_allocations_do_not_oversell(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let tasks: Vec<_> = ["ORDER_1", "ORDER_2"]
+            .into_iter()
+            .map(|order_ref| {
+                let pg_pool = pg_pool.clone();
+                tokio::spawn(async move {
+                    let mut uow = PostgresUnitOfWork::begin(&pg_pool).await?;
+                    handlers::allocate(
+                        order_ref.to_string(),
+                        "SMALL_TABLE".to_string(),
+                        8,
+                        &mut uow,
+                    )
+                    .await
+                })
+            })
+            .collect();
+
+        let mut results = Vec::new();
+        for task in tasks {
+            results.push(task.await.unwrap());
+        }
+
+        let failures: Vec<_> = results
+            .iter()
+            .filter_map(|result| result.as_ref().err())
+            .collect();
+        assert_eq!(results.iter().filter(|result| result.is_ok()).count(), 1);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(
+            failures[0].downcast_ref::<AllocationError>(),
+            Some(&AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 8);
+    }
+
+    #[sqlx::test]
+    async fn test_different_skus_do_not_block_each_other(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        seed_batch(&pg_pool, "BLUE_LAMP", 10).await;
+
+        let first = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        allocate_in(&first, "SMALL_TABLE").await;
+        let mut second = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        let other_sku = async {
+            allocate_in(&second, "BLUE_LAMP").await;
+            second.commit().await.unwrap();
+        };
+
+        tokio::time::timeout(std::time::Duration::from_secs(1), other_sku)
+            .await
+            .expect("allocation of another SKU was blocked");
+        assert_eq!(allocated_qty(&pg_pool, "BLUE_LAMP").await, 5);
+    }
+
+    #[sqlx::test]
+    async fn test_same_sku_allocations_serialize(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let mut first = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+        allocate_in(&first, "SMALL_TABLE").await;
+        let second = tokio::spawn({
+            let pg_pool = pg_pool.clone();
+            async move {
+                let uow = PostgresUnitOfWork::begin(&pg_pool).await.unwrap();
+                uow.products().get("SMALL_TABLE").await.unwrap().unwrap()
+            }
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        assert!(!second.is_finished());
+
+        first.commit().await.unwrap();
+        let product = second.await.unwrap();
+        assert_eq!(product.batches[0].available_qty(), 5);
+    }
+
+    // Makes the first update of a product abort the way a conflicting
+    // concurrent transaction would
+    async fn fail_first_product_update(pg_pool: &PgPool) {
+        for statement in [
+            "CREATE SEQUENCE product_updates",
+            r#"
+                CREATE FUNCTION conflict_once() RETURNS trigger AS $$
+                BEGIN
+                    IF nextval('product_updates') = 1 THEN
+                        RAISE EXCEPTION 'conflicting transaction'
+                            USING ERRCODE = 'serialization_failure';
+                    END IF;
+                    RETURN NEW;
+                END
+                $$ LANGUAGE plpgsql
+            "#,
+            r#"
+                CREATE TRIGGER conflict_once BEFORE UPDATE ON products
+                FOR EACH ROW EXECUTE FUNCTION conflict_once()
+            "#,
+        ] {
+            sqlx::query(statement).execute(pg_pool).await.unwrap();
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_allocate_is_retried_after_a_conflict(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        fail_first_product_update(&pg_pool).await;
+
+        let mut attempts = 0;
+        let batch_id = handlers::retry_on_conflict(3, || {
+            attempts += 1;
+            let pg_pool = pg_pool.clone();
+            async move {
+                let mut uow = PostgresUnitOfWork::begin(&pg_pool).await?;
+                handlers::allocate(
+                    "ORDER_1".to_string(),
+                    "SMALL_TABLE".to_string(),
+                    5,
+                    &mut uow,
+                )
+                .await
+            }
+        })
+        .await;
+
+        assert!(batch_id.is_ok());
+        assert_eq!(attempts, 2);
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 5);
+    }
+
+    #[sqlx::test]
+    async fn test_serializable_allocation_that_raced_is_retried(
+        pg_pool: PgPool,
+    ) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+        let serializable = IsolationLevel::Serializable;
+        let mut first = PostgresUnitOfWork::begin_with(
+            &pg_pool,
+            &TenantId::default(),
+            "first",
+            serializable,
+        )
+        .await
+        .unwrap();
+        allocate_in(&first, "SMALL_TABLE").await;
+
+        let attempts = Arc::new(AtomicU32::new(0));
+        let second = tokio::spawn({
+            let pg_pool = pg_pool.clone();
+            let attempts = attempts.clone();
+            handlers::retry_on_conflict(3, move || {
+                let pg_pool = pg_pool.clone();
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+                async move {
+                    let mut uow = PostgresUnitOfWork::begin_with(
+                        &pg_pool,
+                        &TenantId::default(),
+                        "second",
+                        serializable,
+                    )
+                    .await?;
+                    let result = handlers::allocate(
+                        "ORDER_2".to_string(),
+                        "SMALL_TABLE".to_string(),
+                        5,
+                        &mut uow,
+                    )
+                    .await;
+                    if attempt == 0 {
+                        // Raced the first allocation, whose commit it waited
+                        // for without seeing
+                        let err = result.as_ref().unwrap_err();
+                        assert!(handlers::is_retryable(err), "{err:?}");
+                    }
+                    result
+                }
+            })
+        });
+        tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+        first.commit().await.unwrap();
+
+        assert!(second.await.unwrap().is_ok());
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert_eq!(allocated_qty(&pg_pool, "SMALL_TABLE").await, 10);
+    }
+
+    #[sqlx::test]
+    async fn test_other_errors_are_not_retried(pg_pool: PgPool) {
+        seed_batch(&pg_pool, "SMALL_TABLE", 10).await;
+
+        let mut attempts = 0;
+        let result = handlers::retry_on_conflict(3, || {
+            attempts += 1;
+            let pg_pool = pg_pool.clone();
+            async move {
+                let mut uow = PostgresUnitOfWork::begin(&pg_pool).await?;
+                handlers::allocate(
+                    "ORDER_1".to_string(),
+                    "SMALL_TABLE".to_string(),
+                    50,
+                    &mut uow,
+                )
+                .await
+            }
+        })
+        .await;
+
+        assert_eq!(
+            result.unwrap_err().downcast_ref::<AllocationError>(),
+            Some(&AllocationError::NoBatchAvailable)
+        );
+        assert_eq!(attempts, 1);
+    }
+
+    fn store_with_batch(sku: &str, qty: u32) -> InMemoryStore {
+        let mut batch = Batch::new(sku.to_string(), qty, None).unwrap();
+        batch.id = Some(BatchId(1));
+        let product = Product::new(sku.to_string(), vec![batch]).unwrap();
+        InMemoryStore::with_products(vec![product])
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_changes_are_only_visible_after_commit() {
+        let store = store_with_batch("SMALL_TABLE", 10);
+        let available = |product: Option<Product>| {
+            product.unwrap().batches[0].available_qty()
+        };
+
+        let mut rolled_back = store.begin().await.unwrap();
+        allocate_in(rolled_back.as_ref(), "SMALL_TABLE").await;
+        rolled_back.rollback().await.unwrap();
+        assert_eq!(
+            available(store.products().get("SMALL_TABLE").await.unwrap()),
+            10
+        );
+
+        let mut committed = store.begin().await.unwrap();
+        allocate_in(committed.as_ref(), "SMALL_TABLE").await;
+        assert_eq!(
+            available(store.products().get("SMALL_TABLE").await.unwrap()),
+            10
+        );
+        committed.commit().await.unwrap();
+        assert_eq!(
+            available(store.products().get("SMALL_TABLE").await.unwrap()),
+            5
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_in_memory_commits_conflict_then_retry() {
+        let store = store_with_batch("SMALL_TABLE", 10);
+        let mut first = store.begin().await.unwrap();
+        let mut second = store.begin().await.unwrap();
+        allocate_in(first.as_ref(), "SMALL_TABLE").await;
+        allocate_in(second.as_ref(), "SMALL_TABLE").await;
+
+        first.commit().await.unwrap();
+        let err = second.commit().await.unwrap_err();
+        assert!(handlers::is_retryable(&err), "{err:?}");
+
+        let mut retried = store.begin().await.unwrap();
+        let err = handlers::allocate(
+            "ORDER_2".to_string(),
+            "SMALL_TABLE".to_string(),
+            6,
+            retried.as_mut(),
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<AllocationError>(),
+            Some(&AllocationError::NoBatchAvailable)
+        );
+        let product = store.products().get("SMALL_TABLE").await.unwrap();
+        assert_eq!(product.unwrap().batches[0].available_qty(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_events_of_a_failed_in_memory_commit_are_dropped() {
+        let store = store_with_batch("SMALL_TABLE", 10);
+        let mut first = store.begin().await.unwrap();
+        let mut second = store.begin().await.unwrap();
+        let stale = second.products().get("SMALL_TABLE").await.unwrap();
+        allocate_in(first.as_ref(), "SMALL_TABLE").await;
+        first.commit().await.unwrap();
+
+        let mut product = stale.unwrap();
+        let line =
+            OrderLine::new("ORDER_2".to_string(), "SMALL_TABLE".to_string(), 2)
+                .unwrap();
+        product.allocate(&line, None).unwrap();
+        second.products().add(&product).await.unwrap();
+        second.record_events(product.collect_new_events());
+        let err = second.commit().await.unwrap_err();
+
+        assert!(handlers::is_retryable(&err), "{err:?}");
+        assert!(second.collect_new_events().is_empty());
+    }
+}
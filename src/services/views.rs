@@ -0,0 +1,258 @@
+use crate::domain::events::DomainEvent;
+use crate::domain::model::{BatchId, Sku};
+use crate::domain::tenant::TenantId;
+use chrono::{DateTime, Local, Utc};
+use serde::Serialize;
+use sqlx::postgres::PgPool;
+use sqlx::Row;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct AllocationView {
+    pub sku: String,
+    pub batch_id: BatchId,
+}
+
+// Read side of the allocations, served straight from `allocations_view`
+// without loading any aggregates
+pub async fn allocations(
+    order_ref: &str,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<Vec<AllocationView>> {
+    let rows = sqlx::query(
+        "SELECT sku, batch_id FROM allocations_view
+         WHERE order_ref = $1 AND tenant_id = $2 ORDER BY sku",
+    )
+    .bind(order_ref)
+    .bind(tenant.as_str())
+    .fetch_all(pg_pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| AllocationView {
+            sku: row.get("sku"),
+            batch_id: row.get("batch_id"),
+        })
+        .collect())
+}
+
+// Event handler keeping `allocations_view` in step with the write model
+pub async fn update_allocations_view(
+    event: &DomainEvent,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<()> {
+    match event {
+        DomainEvent::Allocated {
+            order_ref,
+            sku,
+            batch_id,
+            ..
+        } => {
+            sqlx::query(
+                "INSERT INTO allocations_view
+                     (order_ref, sku, batch_id, tenant_id)
+                 VALUES ($1, $2, $3, $4)
+                 ON CONFLICT (tenant_id, order_ref, sku)
+                 DO UPDATE SET batch_id = EXCLUDED.batch_id",
+            )
+            .bind(order_ref)
+            .bind(sku)
+            .bind(*batch_id)
+            .bind(tenant.as_str())
+            .execute(pg_pool)
+            .await?;
+        }
+        DomainEvent::Deallocated { order_ref, sku, .. } => {
+            sqlx::query(
+                "DELETE FROM allocations_view
+                 WHERE order_ref = $1 AND sku = $2 AND tenant_id = $3",
+            )
+            .bind(order_ref)
+            .bind(sku)
+            .bind(tenant.as_str())
+            .execute(pg_pool)
+            .await?;
+        }
+        DomainEvent::OutOfStock { .. }
+        | DomainEvent::BatchCreated { .. }
+        | DomainEvent::BatchQuantityIncreased { .. }
+        | DomainEvent::OverAllocated { .. }
+        | DomainEvent::ReservationReleased { .. }
+        | DomainEvent::OrderCancelled { .. } => {}
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BatchView {
+    pub batch_id: BatchId,
+    pub reference: Option<String>,
+    pub eta: Option<DateTime<Local>>,
+    pub qty: u32,
+    pub available_qty: u32,
+}
+
+// The SKU's batches as `batch_view` last saw them, in stock first and then
+// by eta. The SKU is normalized first, like the repositories do.
+pub async fn batches_for_sku(
+    sku: &str,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<Vec<BatchView>> {
+    let Ok(sku) = Sku::try_from(sku) else {
+        return Ok(Vec::new());
+    };
+    let rows = sqlx::query(
+        "SELECT batch_id, reference, eta, qty, available_qty FROM batch_view
+         WHERE sku = $1 AND tenant_id = $2 ORDER BY eta NULLS FIRST, batch_id",
+    )
+    .bind(sku.as_str())
+    .bind(tenant.as_str())
+    .fetch_all(pg_pool)
+    .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| BatchView {
+            batch_id: row.get("batch_id"),
+            reference: row.get("reference"),
+            eta: row
+                .get::<Option<DateTime<Utc>>, _>("eta")
+                .map(|eta| eta.with_timezone(&Local)),
+            qty: row.get::<i32, _>("qty") as u32,
+            available_qty: row.get::<i32, _>("available_qty") as u32,
+        })
+        .collect())
+}
+
+// Event handler keeping `batch_view` in step with the write model. Events
+// don't say how much of which batch is left, so the SKU's rows are rebuilt
+// from the committed batches whenever its stock moves. Reservations and
+// batches shrunk without freeing a line raise no event, they show up with
+// the next one for the SKU.
+pub async fn update_batch_view(
+    event: &DomainEvent,
+    tenant: &TenantId,
+    pg_pool: &PgPool,
+) -> anyhow::Result<()> {
+    let sku = match event {
+        DomainEvent::BatchCreated { sku, .. }
+        | DomainEvent::Allocated { sku, .. }
+        | DomainEvent::Deallocated { sku, .. }
+        | DomainEvent::BatchQuantityIncreased { sku, .. }
+        | DomainEvent::ReservationReleased { sku, .. } => sku,
+        DomainEvent::OutOfStock { .. }
+        | DomainEvent::OverAllocated { .. }
+        | DomainEvent::OrderCancelled { .. } => return Ok(()),
+    };
+
+    let mut tx = pg_pool.begin().await?;
+    // Serializes rebuilds of the same SKU, so an older one can't land last
+    sqlx::query(
+        "SELECT pg_advisory_xact_lock(
+             hashtext('batch_view:' || $1), hashtext($2)
+         )",
+    )
+    .bind(tenant.as_str())
+    .bind(sku)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query("DELETE FROM batch_view WHERE sku = $1 AND tenant_id = $2")
+        .bind(sku)
+        .bind(tenant.as_str())
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query(
+        "INSERT INTO batch_view
+             (batch_id, sku, reference, eta, qty, available_qty, tenant_id)
+         SELECT b.id, b.sku, b.reference, b.eta, b.qty,
+             GREATEST(
+                 b.qty + p.over_allocation_buffer
+                     - COALESCE(a.qty, 0) - COALESCE(r.qty, 0),
+                 0
+             ),
+             b.tenant_id
+         FROM batches b
+         JOIN products p ON p.tenant_id = b.tenant_id AND p.sku = b.sku
+         LEFT JOIN (
+             SELECT batch_id, SUM(qty) AS qty FROM allocations
+             GROUP BY batch_id
+         ) a ON a.batch_id = b.id
+         LEFT JOIN (
+             SELECT batch_id, SUM(qty) AS qty FROM reservations
+             GROUP BY batch_id
+         ) r ON r.batch_id = b.id
+         WHERE b.sku = $1 AND b.tenant_id = $2 AND b.deleted_at IS NULL",
+    )
+    .bind(sku)
+    .bind(tenant.as_str())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn allocated(order_ref: &str, sku: &str, batch_id: u32) -> DomainEvent {
+        DomainEvent::Allocated {
+            order_ref: order_ref.to_string(),
+            sku: sku.to_string(),
+            qty: 1,
+            batch_id: BatchId(batch_id),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_allocated_events_populate_the_view(pg_pool: PgPool) {
+        let tenant = TenantId::default();
+        for event in [
+            allocated("ORDER_1", "SMALL_TABLE", 1),
+            allocated("ORDER_1", "BLUE_LAMP", 2),
+            allocated("ORDER_2", "SMALL_TABLE", 1),
+        ] {
+            update_allocations_view(&event, &tenant, &pg_pool)
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(
+            allocations("ORDER_1", &tenant, &pg_pool).await.unwrap(),
+            vec![
+                AllocationView {
+                    sku: "BLUE_LAMP".to_string(),
+                    batch_id: BatchId(2)
+                },
+                AllocationView {
+                    sku: "SMALL_TABLE".to_string(),
+                    batch_id: BatchId(1)
+                },
+            ]
+        );
+    }
+
+    #[sqlx::test]
+    async fn test_deallocated_event_removes_the_view_row(pg_pool: PgPool) {
+        let deallocated = DomainEvent::Deallocated {
+            order_ref: "ORDER_1".to_string(),
+            sku: "SMALL_TABLE".to_string(),
+            qty: 1,
+            cancelled: false,
+        };
+        let tenant = TenantId::default();
+        for event in [allocated("ORDER_1", "SMALL_TABLE", 1), deallocated] {
+            update_allocations_view(&event, &tenant, &pg_pool)
+                .await
+                .unwrap();
+        }
+
+        let view = allocations("ORDER_1", &tenant, &pg_pool).await.unwrap();
+        assert!(view.is_empty());
+    }
+}
@@ -0,0 +1,118 @@
+use assert_cmd::Command;
+use cosmic::domain::model::{Batch, Product};
+use cosmic::domain::repository::ProductRepository;
+use cosmic::infrastructure::repository::PostgresBatchRepository;
+use predicates::prelude::*;
+use serde_json::Value;
+use sqlx::postgres::PgPool;
+
+// `DATABASE_URL` pointed at the test's own database instead of the server
+fn database_url(pg_pool: &PgPool) -> String {
+    let url = std::env::var("DATABASE_URL").unwrap();
+    let authority = url.find("://").map_or(0, |index| index + 3);
+    let server = match url[authority..].find('/') {
+        Some(index) => &url[..authority + index],
+        None => &url,
+    };
+    let options = pg_pool.connect_options();
+    format!("{}/{}", server, options.get_database().unwrap())
+}
+
+fn cosmic(pg_pool: &PgPool) -> Command {
+    let mut command = Command::cargo_bin("cosmic").unwrap();
+    command
+        .env("DATABASE_URL", database_url(pg_pool))
+        .env("RUST_LOG", "warn");
+    command
+}
+
+async fn seed(pg_pool: &PgPool) {
+    let sku = "SMALL_TABLE".to_string();
+    let mut batch = Batch::new(sku.clone(), 10, None).unwrap();
+    batch.reference = Some("BATCH_1".to_string());
+    PostgresBatchRepository::new(pg_pool.clone())
+        .add(&Product::new(sku, vec![batch]).unwrap())
+        .await
+        .unwrap();
+}
+
+#[sqlx::test]
+async fn test_allocate_prints_the_batch_as_json(pg_pool: PgPool) {
+    seed(&pg_pool).await;
+
+    let output = cosmic(&pg_pool)
+        .args(["allocate", "--order-ref", "ORDER_1"])
+        .args(["--sku", "SMALL_TABLE", "--qty", "4", "--json"])
+        .output()
+        .unwrap();
+
+    assert!(output.status.success(), "{:?}", output);
+    let allocation: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(allocation["order_ref"], "ORDER_1");
+    assert_eq!(allocation["batch_id"], 1);
+
+    cosmic(&pg_pool)
+        .args(["show", "--sku", "SMALL_TABLE"])
+        .assert()
+        .success()
+        .stdout(
+            predicate::str::is_match(r"1\s+BATCH_1\s+10\s+6\s+in stock")
+                .unwrap(),
+        );
+}
+
+#[sqlx::test]
+async fn test_add_batch_then_show_it(pg_pool: PgPool) {
+    cosmic(&pg_pool)
+        .args(["add-batch", "--ref", "BATCH_9", "--sku", "BLUE_LAMP"])
+        .args(["--qty", "5", "--eta", "2024-06-01T10:00:00+00:00"])
+        .assert()
+        .success()
+        .stdout("added batch BATCH_9 of 5 BLUE_LAMP\n");
+
+    let output = cosmic(&pg_pool)
+        .args(["show", "--sku", "BLUE_LAMP", "--json"])
+        .output()
+        .unwrap();
+    let batches: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(batches[0]["reference"], "BATCH_9");
+    assert_eq!(batches[0]["available"], 5);
+}
+
+// Each run reads `TZ` afresh, so the ETA is written in one timezone and
+// read back in another
+#[sqlx::test]
+async fn test_eta_is_the_same_instant_in_another_timezone(pg_pool: PgPool) {
+    cosmic(&pg_pool)
+        .env("TZ", "America/New_York")
+        .args(["add-batch", "--ref", "BATCH_9", "--sku", "BLUE_LAMP"])
+        .args(["--qty", "5", "--eta", "2024-06-01T10:00:00+00:00"])
+        .assert()
+        .success();
+
+    let output = cosmic(&pg_pool)
+        .env("TZ", "Asia/Kolkata")
+        .args(["show", "--sku", "BLUE_LAMP", "--json"])
+        .output()
+        .unwrap();
+    let batches: Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(batches[0]["eta"], "2024-06-01T15:30:00+05:30");
+}
+
+#[sqlx::test]
+async fn test_errors_exit_non_zero_with_a_message(pg_pool: PgPool) {
+    seed(&pg_pool).await;
+
+    cosmic(&pg_pool)
+        .args(["allocate", "--order-ref", "ORDER_1"])
+        .args(["--sku", "SMALL_TABLE", "--qty", "50"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Error:"))
+        .stdout("");
+    cosmic(&pg_pool)
+        .args(["show", "--sku", "RED_LAMP"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("RED_LAMP"));
+}